@@ -0,0 +1,167 @@
+use std::{
+    io::{self, Read, Seek, SeekFrom},
+    time::Duration,
+};
+
+use rodio::Source;
+
+/// A rodio source for uncompressed AIFF/AIFC files, which rodio cannot decode on its own.
+pub struct AiffDecoder<R: Read + Seek> {
+    reader: R,
+    looped: bool,
+    channels: u16,
+    sample_rate: u32,
+    sample_width: usize,
+    little_endian: bool,
+    data_start: u64,
+    data_len: u64,
+    pos: u64,
+}
+
+/// Decodes the 80 bit IEEE 754 extended precision float used for the sample rate.
+fn extended_to_f64(bytes: &[u8; 10]) -> f64 {
+    let exponent = (u16::from_be_bytes([bytes[0], bytes[1]]) & 0x7fff) as i32;
+    let mantissa = u64::from_be_bytes(bytes[2..10].try_into().unwrap());
+    if exponent == 0 && mantissa == 0 {
+        return 0.0;
+    }
+    mantissa as f64 * 2f64.powi(exponent - 16383 - 63)
+}
+
+impl<R: Read + Seek> AiffDecoder<R> {
+    pub fn new(reader: R) -> Result<AiffDecoder<R>, anyhow::Error> {
+        AiffDecoder::open(reader, false)
+    }
+
+    pub fn new_looped(reader: R) -> Result<AiffDecoder<R>, anyhow::Error> {
+        AiffDecoder::open(reader, true)
+    }
+
+    fn open(mut reader: R, looped: bool) -> Result<AiffDecoder<R>, anyhow::Error> {
+        let mut header = [0; 12];
+        reader.read_exact(&mut header)?;
+        let aifc = match &header[8..12] {
+            _ if &header[0..4] != b"FORM" => return Err(anyhow::anyhow!("Not an AIFF file")),
+            b"AIFF" => false,
+            b"AIFC" => true,
+            _ => return Err(anyhow::anyhow!("Not an AIFF file")),
+        };
+
+        let mut comm = None;
+        let mut ssnd = None;
+        while comm.is_none() || ssnd.is_none() {
+            let mut chunk = [0; 8];
+            match reader.read_exact(&mut chunk) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            let size = u32::from_be_bytes(chunk[4..8].try_into()?) as u64;
+            let start = reader.stream_position()?;
+
+            match &chunk[0..4] {
+                b"COMM" => {
+                    let mut data = vec![0; size as usize];
+                    reader.read_exact(&mut data)?;
+                    if data.len() < 18 {
+                        return Err(anyhow::anyhow!("Truncated AIFF COMM chunk"));
+                    }
+                    let channels = u16::from_be_bytes(data[0..2].try_into()?);
+                    let sample_size = u16::from_be_bytes(data[6..8].try_into()?);
+                    let sample_rate = extended_to_f64(data[8..18].try_into()?) as u32;
+                    let compression = if aifc { data.get(18..22) } else { None };
+                    let little_endian = match compression {
+                        None | Some(b"NONE") | Some(b"twos") => false,
+                        Some(b"sowt") => true,
+                        Some(_) => return Err(anyhow::anyhow!("Unsupported AIFC compression")),
+                    };
+                    comm = Some((channels, sample_size, sample_rate, little_endian));
+                }
+                b"SSND" => {
+                    let mut data = [0; 8];
+                    reader.read_exact(&mut data)?;
+                    let offset = u32::from_be_bytes(data[0..4].try_into()?) as u64;
+                    ssnd = Some((start + 8 + offset, size.saturating_sub(8 + offset)));
+                }
+                _ => {}
+            }
+
+            // Chunks are padded to an even length.
+            reader.seek(SeekFrom::Start(start + size + size % 2))?;
+        }
+
+        let (
+            Some((channels, sample_size, sample_rate, little_endian)),
+            Some((data_start, data_len)),
+        ) = (comm, ssnd)
+        else {
+            return Err(anyhow::anyhow!("Missing AIFF COMM or SSND chunk"));
+        };
+        if !(1..=32).contains(&sample_size) || channels == 0 || sample_rate == 0 {
+            return Err(anyhow::anyhow!("Unsupported AIFF sample format"));
+        }
+
+        reader.seek(SeekFrom::Start(data_start))?;
+
+        Ok(AiffDecoder {
+            reader,
+            looped,
+            channels,
+            sample_rate,
+            sample_width: sample_size.div_ceil(8) as usize,
+            little_endian,
+            data_start,
+            data_len,
+            pos: 0,
+        })
+    }
+}
+
+impl<R: Read + Seek> Iterator for AiffDecoder<R> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        if self.data_len < self.sample_width as u64 {
+            return None;
+        }
+        if self.pos + self.sample_width as u64 > self.data_len {
+            if !self.looped {
+                return None;
+            }
+            self.reader.seek(SeekFrom::Start(self.data_start)).ok()?;
+            self.pos = 0;
+        }
+
+        let mut bytes = [0; 4];
+        let bytes = &mut bytes[..self.sample_width];
+        self.reader.read_exact(bytes).ok()?;
+        self.pos += self.sample_width as u64;
+        if self.little_endian {
+            bytes.reverse();
+        }
+
+        // Keep the most significant 16 bits regardless of the stored width.
+        Some(match bytes.len() {
+            1 => i16::from(bytes[0] as i8) << 8,
+            _ => i16::from_be_bytes([bytes[0], bytes[1]]),
+        })
+    }
+}
+
+impl<R: Read + Seek> Source for AiffDecoder<R> {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
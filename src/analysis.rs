@@ -0,0 +1,427 @@
+use std::{
+    f64::consts::TAU,
+    fs::File,
+    io::BufReader,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use rodio::{decoder::DecoderError, Decoder, Source};
+
+use crate::{aiff, file::Key};
+
+/// Only the start of each song is analyzed, which is plenty to find a steady tempo.
+const MAX_SECONDS: usize = 120;
+/// The number of onset strength frames per second.  Higher rates resolve fast tempos better.
+const FRAME_RATE: u32 = 200;
+/// Songs shorter than this don't have enough beats to go on.
+const MIN_SECONDS: usize = 4;
+/// Tempos near this are preferred when several are about as likely, to avoid locking on to a
+/// multiple of the actual tempo.
+const PREFERRED_BPM: f64 = 120.0;
+/// Downbeats are looked for assuming this many beats to a bar.
+const BEATS_PER_BAR: usize = 4;
+/// How far off the tempo from the autocorrelation might be, as a fraction of the period.
+const MAX_PERIOD_ERROR: f64 = 0.005;
+/// The number of periods either side of the autocorrelation's to try when lining up the grid.
+const PERIOD_STEPS: i32 = 10;
+
+/// Songs are downsampled to about this rate to find their key, which only needs the pitches of
+/// notes.
+const KEY_SAMPLE_RATE: usize = 11025;
+/// The number of samples in each window the pitches are measured over.
+const KEY_WINDOW: usize = 4096;
+/// The range of MIDI notes whose pitches are measured, from C2 to B6.
+const KEY_NOTES: std::ops::Range<u8> = 36..96;
+/// How closely the pitches need to fit a key's profile for it to count, as a correlation.
+const MIN_KEY_CORRELATION: f64 = 0.5;
+/// The number of columns per second in a song's spectrogram.
+pub const SPECTROGRAM_RATE: usize = 20;
+/// The frequencies the spectrogram measures, in Hz.  Most are low, where kick drums and bass
+/// lines are.
+pub const SPECTROGRAM_BANDS: [f64; 8] = [50.0, 80.0, 125.0, 200.0, 400.0, 800.0, 1600.0, 3200.0];
+/// The number of points per second in a song's waveform.
+pub const WAVEFORM_RATE: usize = 10;
+/// How often each pitch class turns up in major and minor keys, starting from the tonic, from
+/// Krumhansl and Kessler's listening experiments.
+const MAJOR_PROFILE: [f64; 12] = [
+    6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f64; 12] = [
+    6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+
+/// The tempo of a song, and where its beat grid starts.
+#[derive(Clone, Copy)]
+pub struct Tempo {
+    pub bpm: f64,
+    /// How far into the song the first downbeat falls.
+    pub offset: Duration,
+    /// How strongly the song repeats at this tempo, from 0 to 1.
+    pub confidence: f64,
+}
+
+/// Everything estimated from a song's audio.  Either part is None when the song doesn't have a
+/// clear one.
+#[derive(Clone, Copy, Default)]
+pub struct Estimate {
+    pub tempo: Option<Tempo>,
+    pub key: Option<Key>,
+}
+
+type Samples = Box<dyn Source<Item = i16> + Send>;
+
+/// The start of a song, decoded.
+pub struct Audio {
+    /// Interleaved samples.
+    pub samples: Vec<i16>,
+    pub channels: usize,
+    pub rate: usize,
+}
+
+/// Opens a song with the same decoders used for playback.
+fn open(path: &str) -> Result<Samples, anyhow::Error> {
+    match Decoder::new(BufReader::new(File::open(path)?)) {
+        Ok(source) => Ok(Box::new(source)),
+        Err(DecoderError::UnrecognizedFormat) => {
+            aiff::AiffDecoder::new(BufReader::new(File::open(path)?))
+                .map(|source| Box::new(source) as Samples)
+                .map_err(|_| anyhow::anyhow!("{}: Cannot decode this format", path))
+        }
+        Err(e) => Err(anyhow::anyhow!("{}: {}", path, e)),
+    }
+}
+
+/// Decodes the start of a song, or all of it when seconds is None.
+pub fn decode(path: &str, seconds: Option<usize>) -> Result<Audio, anyhow::Error> {
+    let source = open(path)?;
+    let channels = source.channels().max(1) as usize;
+    let rate = source.sample_rate() as usize;
+    let limit = seconds.map_or(usize::MAX, |seconds| rate * channels * seconds);
+    Ok(Audio {
+        samples: source.take(limit).collect(),
+        channels,
+        rate,
+    })
+}
+
+/// How a song's audio is stored, as read from its decoder.
+pub struct StreamInfo {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub duration: Option<Duration>,
+}
+
+/// Opens a song just far enough to see its stream properties.
+pub fn stream_info(path: &str) -> Result<StreamInfo, anyhow::Error> {
+    let source = open(path)?;
+    Ok(StreamInfo {
+        sample_rate: source.sample_rate(),
+        channels: source.channels(),
+        duration: source.total_duration(),
+    })
+}
+
+/// How long a song is.  Formats that don't record it are decoded all the way through to find
+/// out.
+pub fn duration(path: &str) -> Result<Duration, anyhow::Error> {
+    let source = open(path)?;
+    if let Some(duration) = source.total_duration() {
+        return Ok(duration);
+    }
+    let per_second = u64::from(source.channels().max(1)) * u64::from(source.sample_rate().max(1));
+    let samples = source.count() as u64;
+    Ok(Duration::from_secs_f64(samples as f64 / per_second as f64))
+}
+
+/// How loud a whole song is over time, as the peak of each stretch of WAVEFORM_RATE per second,
+/// scaled so the loudest is 1.  Gives up as soon as it's cancelled.
+pub fn waveform(path: &str, cancelled: &AtomicBool) -> Result<Vec<f32>, anyhow::Error> {
+    let source = open(path)?;
+    let channels = source.channels().max(1) as usize;
+    let rate = source.sample_rate().max(1) as usize;
+    let chunk = (rate / WAVEFORM_RATE).max(1) * channels;
+
+    let mut peaks = Vec::new();
+    let mut peak = 0;
+    for (i, sample) in source.enumerate() {
+        peak = peak.max(sample.unsigned_abs());
+        if (i + 1) % chunk == 0 {
+            if cancelled.load(Ordering::Relaxed) {
+                return Err(anyhow::anyhow!("{}: Cancelled", path));
+            }
+            peaks.push(peak);
+            peak = 0;
+        }
+    }
+    if peak > 0 {
+        peaks.push(peak);
+    }
+
+    let loudest = f32::from(peaks.iter().copied().max().unwrap_or(0).max(1));
+    Ok(peaks
+        .into_iter()
+        .map(|peak| f32::from(peak) / loudest)
+        .collect())
+}
+
+/// How strongly a whole song sounds in each of SPECTROGRAM_BANDS, SPECTROGRAM_RATE times a
+/// second.  Each band is scaled so its loudest is 1, so quiet kick drums still stand out.
+pub fn spectrogram(path: &str) -> Result<Vec<[f32; SPECTROGRAM_BANDS.len()]>, anyhow::Error> {
+    let (mono, rate) = downsample(&decode(path, None)?);
+    let hop = (rate as usize / SPECTROGRAM_RATE).max(1);
+    // Frames overlap by half, so the lowest bands are still told apart.
+    let window = hann_window(hop * 2);
+    let coefficients = SPECTROGRAM_BANDS.map(|band| 2.0 * (TAU * band / rate).cos());
+
+    let mut columns = (0..mono.len().saturating_sub(hop) / hop)
+        .map(|i| {
+            let frame = &mono[i * hop..(i * hop + window.len()).min(mono.len())];
+            coefficients.map(|coefficient| goertzel(frame, &window, coefficient) as f32)
+        })
+        .collect::<Vec<_>>();
+
+    for band in 0..SPECTROGRAM_BANDS.len() {
+        let loudest = columns
+            .iter()
+            .map(|column| column[band])
+            .fold(0.0, f32::max);
+        if loudest > 0.0 {
+            for column in &mut columns {
+                // A square root brings out quieter hits without a full decibel scale.
+                column[band] = (column[band] / loudest).sqrt();
+            }
+        }
+    }
+    Ok(columns)
+}
+
+/// How strongly each frame sounds like the start of a note, from the rise in loudness, along with
+/// the exact number of frames per second.
+fn onset_strength(audio: &Audio) -> (Vec<f64>, f64) {
+    let frame_len = (audio.rate / FRAME_RATE as usize).max(1);
+    let hop = frame_len * audio.channels;
+
+    let mut energies = Vec::new();
+    let mut sum = 0.0;
+    let mut count = 0;
+    for sample in &audio.samples {
+        let sample = f64::from(*sample) / f64::from(i16::MAX);
+        sum += sample * sample;
+        count += 1;
+        if count == hop {
+            // Compress, so quiet passages still count.
+            energies.push((1.0 + 1000.0 * sum / count as f64).ln());
+            sum = 0.0;
+            count = 0;
+        }
+    }
+
+    let mut onsets = energies
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).max(0.0))
+        .collect::<Vec<_>>();
+    let mean = onsets.iter().sum::<f64>() / onsets.len().max(1) as f64;
+    onsets.iter_mut().for_each(|onset| *onset -= mean);
+    (onsets, audio.rate as f64 / frame_len as f64)
+}
+
+fn autocorrelation(onsets: &[f64], lag: usize) -> f64 {
+    let products = onsets.iter().zip(&onsets[lag..]).map(|(a, b)| a * b);
+    products.sum::<f64>() / (onsets.len() - lag) as f64
+}
+
+/// The average onset strength on a grid starting at the given frame.
+fn grid_strength(onsets: &[f64], start: f64, period: f64) -> f64 {
+    let frames = (0..)
+        .map(|i| (start + i as f64 * period).round() as usize)
+        .take_while(|frame| *frame < onsets.len());
+    let (sum, count) = frames.fold((0.0, 0), |(sum, count), frame| {
+        (sum + onsets[frame], count + 1)
+    });
+    sum / count.max(1) as f64
+}
+
+/// Picks the grid start that lines up best with the onsets.
+fn strongest(onsets: &[f64], starts: impl Iterator<Item = f64>, period: f64) -> f64 {
+    starts
+        .max_by(|a, b| {
+            grid_strength(onsets, *a, period).total_cmp(&grid_strength(onsets, *b, period))
+        })
+        .unwrap_or(0.0)
+}
+
+/// Lines a beat grid up with the onsets, returning the beat period and the frame of the first
+/// downbeat.  The period is fine tuned along the way, since even a tiny error adds up to the grid
+/// drifting off the beat over the length of a song.  The downbeat is whichever beat of the bar is
+/// strongest.
+fn beat_grid(onsets: &[f64], lag: f64) -> (f64, f64) {
+    let (period, beat) = (-PERIOD_STEPS..=PERIOD_STEPS)
+        .map(|step| {
+            let period = lag * (1.0 + step as f64 / PERIOD_STEPS as f64 * MAX_PERIOD_ERROR);
+            let starts = (0..period.ceil() as usize).map(|frame| frame as f64);
+            let beat = strongest(onsets, starts, period);
+            (period, beat)
+        })
+        .max_by(|(a, start_a), (b, start_b)| {
+            grid_strength(onsets, *start_a, *a).total_cmp(&grid_strength(onsets, *start_b, *b))
+        })
+        .unwrap_or((lag, 0.0));
+    let downbeat = strongest(
+        onsets,
+        (0..BEATS_PER_BAR).map(|i| beat + i as f64 * period),
+        period * BEATS_PER_BAR as f64,
+    );
+    (period, downbeat)
+}
+
+/// Estimates the tempo of a song from how its onsets repeat, searching only between min_bpm and
+/// max_bpm.
+fn tempo(audio: &Audio, min_bpm: f64, max_bpm: f64) -> Option<Tempo> {
+    let (onsets, frame_rate) = onset_strength(audio);
+    if (onsets.len() as f64) < MIN_SECONDS as f64 * frame_rate {
+        return None;
+    }
+
+    let frames_per_minute = 60.0 * frame_rate;
+    let min_lag = ((frames_per_minute / max_bpm).floor() as usize).max(2);
+    let max_lag = ((frames_per_minute / min_bpm).ceil() as usize).min(onsets.len() / 2);
+    if min_lag + 2 > max_lag {
+        return None;
+    }
+
+    let correlations = (min_lag - 1..=max_lag + 1)
+        .map(|lag| autocorrelation(&onsets, lag))
+        .collect::<Vec<_>>();
+    let prior = |lag: f64| {
+        let octaves = (frames_per_minute / lag / PREFERRED_BPM).log2();
+        (-0.5 * octaves * octaves).exp()
+    };
+    let best = (1..correlations.len() - 1)
+        .filter(|i| correlations[*i] > 0.0)
+        .max_by(|a, b| {
+            let score = |i: usize| correlations[i] * prior((min_lag - 1 + i) as f64);
+            score(*a).total_cmp(&score(*b))
+        })?;
+
+    // Fit a parabola through the peak to find the lag between frames.
+    let (before, peak, after) = (
+        correlations[best - 1],
+        correlations[best],
+        correlations[best + 1],
+    );
+    let curvature = before - 2.0 * peak + after;
+    let offset = if curvature < 0.0 {
+        0.5 * (before - after) / curvature
+    } else {
+        0.0
+    };
+    let lag = (min_lag - 1 + best) as f64 + offset;
+
+    let (period, downbeat) = beat_grid(&onsets, lag);
+    // Comparing against no lag at all normalizes out how loud the onsets are.
+    let confidence = (peak / autocorrelation(&onsets, 0)).clamp(0.0, 1.0);
+    Some(Tempo {
+        bpm: frames_per_minute / period,
+        // Each onset is the rise into the frame after it.
+        offset: Duration::from_secs_f64((downbeat + 1.0) / frame_rate),
+        confidence,
+    })
+}
+
+/// Mixes a song down to mono at about KEY_SAMPLE_RATE, along with the exact rate.
+fn downsample(audio: &Audio) -> (Vec<f64>, f64) {
+    let factor = (audio.rate / KEY_SAMPLE_RATE).max(1);
+    // Averaging neighbouring samples is a rough low pass filter, which is enough to keep high
+    // notes from folding down onto the ones being measured.
+    let mono = audio
+        .samples
+        .chunks(factor * audio.channels)
+        .map(|chunk| {
+            chunk.iter().map(|sample| f64::from(*sample)).sum::<f64>() / chunk.len() as f64
+        })
+        .collect();
+    (mono, (audio.rate / factor) as f64)
+}
+
+fn hann_window(len: usize) -> Vec<f64> {
+    (0..len)
+        .map(|i| 0.5 - 0.5 * (TAU * i as f64 / len as f64).cos())
+        .collect()
+}
+
+/// How strongly a frame sounds at the frequency whose Goertzel coefficient is given.
+fn goertzel(frame: &[f64], window: &[f64], coefficient: f64) -> f64 {
+    // The Goertzel algorithm measures a single frequency without a whole FFT.
+    let (mut s1, mut s2) = (0.0, 0.0);
+    for (sample, weight) in frame.iter().zip(window) {
+        let s = sample * weight + coefficient * s1 - s2;
+        s2 = s1;
+        s1 = s;
+    }
+    let power = s1 * s1 + s2 * s2 - coefficient * s1 * s2;
+    power.max(0.0).sqrt()
+}
+
+/// How strongly each pitch class sounds over the song, summed over every octave.
+fn chroma(audio: &Audio) -> [f64; 12] {
+    let (mono, rate) = downsample(audio);
+    let window = hann_window(KEY_WINDOW);
+    let coefficients = KEY_NOTES
+        .map(|note| {
+            let pitch = 440.0 * 2f64.powf((f64::from(note) - 69.0) / 12.0);
+            (note, 2.0 * (TAU * pitch / rate).cos())
+        })
+        .collect::<Vec<_>>();
+
+    let mut chroma = [0.0; 12];
+    for frame in mono.chunks_exact(KEY_WINDOW) {
+        for (note, coefficient) in &coefficients {
+            chroma[(note % 12) as usize] += goertzel(frame, &window, *coefficient);
+        }
+    }
+    chroma
+}
+
+fn correlation(a: &[f64; 12], b: impl Fn(usize) -> f64) -> f64 {
+    let mean_a = a.iter().sum::<f64>() / 12.0;
+    let mean_b = (0..12).map(&b).sum::<f64>() / 12.0;
+    let (mut product, mut square_a, mut square_b) = (0.0, 0.0, 0.0);
+    for (i, a) in a.iter().enumerate() {
+        let (a, b) = (a - mean_a, b(i) - mean_b);
+        product += a * b;
+        square_a += a * a;
+        square_b += b * b;
+    }
+    product / (square_a * square_b).sqrt()
+}
+
+/// Estimates the key of a song by comparing how much each pitch class sounds against the profile
+/// of every major and minor key.
+fn key(audio: &Audio) -> Option<Key> {
+    let chroma = chroma(audio);
+    (0..12u8)
+        .flat_map(|tonic| [false, true].map(|minor| Key { tonic, minor }))
+        .map(|key| {
+            let profile = if key.minor {
+                &MINOR_PROFILE
+            } else {
+                &MAJOR_PROFILE
+            };
+            let fit = correlation(&chroma, |i| profile[(i + 12 - key.tonic as usize) % 12]);
+            (key, fit)
+        })
+        .filter(|(_, fit)| *fit >= MIN_KEY_CORRELATION)
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .map(|(key, _)| key)
+}
+
+/// Estimates the tempo and key of a song, searching only between min_bpm and max_bpm for the
+/// tempo.
+pub fn estimate(path: &str, min_bpm: f64, max_bpm: f64) -> Result<Estimate, anyhow::Error> {
+    let audio = decode(path, Some(MAX_SECONDS))?;
+    Ok(Estimate {
+        tempo: tempo(&audio, min_bpm, max_bpm),
+        key: key(&audio),
+    })
+}
@@ -0,0 +1,59 @@
+use std::{f32::consts::TAU, time::Duration};
+
+use rodio::Source;
+
+const SAMPLE_RATE: u32 = 44100;
+const CLICK_LEN: u64 = SAMPLE_RATE as u64 / 50;
+const PITCH: f32 = 1000.0;
+
+/// An endless metronome click, used to calibrate tap timing against the audio output and to
+/// check a tapped tempo against the music.
+pub struct Click {
+    period: u64,
+    pos: u64,
+}
+
+impl Click {
+    /// Starts the given fraction of the way through a beat.
+    pub fn new(bpm: f64, phase: f64) -> Click {
+        let period = (SAMPLE_RATE as f64 * 60.0 / bpm) as u64;
+        Click {
+            period,
+            pos: (period as f64 * phase) as u64,
+        }
+    }
+}
+
+impl Iterator for Click {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let pos = self.pos % self.period;
+        self.pos += 1;
+        if pos >= CLICK_LEN {
+            return Some(0.0);
+        }
+
+        // Fade the click out so it doesn't pop.
+        let fade = 1.0 - pos as f32 / CLICK_LEN as f32;
+        Some((pos as f32 * PITCH * TAU / SAMPLE_RATE as f32).sin() * fade * 0.5)
+    }
+}
+
+impl Source for Click {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
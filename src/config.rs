@@ -0,0 +1,231 @@
+use std::{env, fs, io, path::PathBuf, str::FromStr};
+
+use clap::ValueEnum;
+
+use crate::{
+    cover, file, BpmRange, Estimator, Panel, Rounding, SortBy, StartAt, Theme, Window, MAX_VOLUME,
+};
+
+/// Defaults loaded from `~/.config/crabtap/config.toml`.  Command line flags take precedence over
+/// anything set here.
+#[derive(Default)]
+pub struct Config {
+    pub confirm: Option<bool>,
+    pub max_time: Option<u32>,
+    pub device: Option<String>,
+    pub id3_version: Option<file::Id3Version>,
+    pub mirror_ape: Option<bool>,
+    pub backup: Option<bool>,
+    pub bpm_tag: Option<String>,
+    pub bpm_tag_only: Option<bool>,
+    pub write_comment: Option<bool>,
+    pub backup_dir: Option<PathBuf>,
+    pub estimate: Option<bool>,
+    pub acoustid_key: Option<String>,
+    pub bpm_api: Option<String>,
+    pub skip_tagged: Option<bool>,
+    pub shuffle: Option<bool>,
+    pub sort: Option<SortBy>,
+    pub spectrogram: Option<bool>,
+    pub replay_gain: Option<bool>,
+    pub key_notation: Option<file::KeyNotation>,
+    pub theme: Option<Theme>,
+    pub graphics: Option<cover::Graphics>,
+    pub table_height: Option<u16>,
+    pub compact: Option<bool>,
+    pub hide: Option<Vec<Panel>>,
+    pub precision: Option<usize>,
+    pub latency: Option<f64>,
+    pub rounding: Option<Rounding>,
+    pub bpm_range: Option<BpmRange>,
+    pub num_avg: Option<Window>,
+    pub estimator: Option<Estimator>,
+    pub outlier_threshold: Option<f64>,
+    pub idle_reset: Option<f64>,
+    pub beats_per_tap: Option<u32>,
+    pub min_taps: Option<usize>,
+    pub auto_confirm: Option<usize>,
+    pub auto_tolerance: Option<f64>,
+    pub volume: Option<u32>,
+    pub start_at: Option<StartAt>,
+    pub preview: Option<u32>,
+}
+
+fn config_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("crabtap").join("config.toml"))
+}
+
+/// Parses a bare, double quoted or single quoted toml value.  Escapes aren't supported.
+fn unquote(value: &str) -> Result<&str, anyhow::Error> {
+    for quote in ['"', '\''] {
+        if let Some(value) = value.strip_prefix(quote) {
+            return value
+                .strip_suffix(quote)
+                .ok_or_else(|| anyhow::anyhow!("Unterminated string"));
+        }
+    }
+    Ok(value)
+}
+
+/// Cuts a trailing `# comment` off a line, leaving any `#` inside a string alone.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), _) if c == open => quote = None,
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Parses a toml array of strings, or a single string of comma separated values.
+fn parse_list(value: &str) -> Result<Vec<&str>, anyhow::Error> {
+    let items = match value.strip_prefix('[') {
+        Some(items) => items
+            .strip_suffix(']')
+            .ok_or_else(|| anyhow::anyhow!("Unterminated array"))?,
+        None => unquote(value)?,
+    };
+    items
+        .split(',')
+        .map(str::trim)
+        // Toml allows a trailing comma.
+        .filter(|item| !item.is_empty())
+        .map(unquote)
+        .collect()
+}
+
+fn parse_enum<T: ValueEnum>(value: &str) -> Result<T, anyhow::Error> {
+    T::from_str(unquote(value)?, true).map_err(|e| anyhow::anyhow!(e))
+}
+
+fn parse_number<T: FromStr>(value: &str) -> Result<T, anyhow::Error> {
+    value
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Expected a number"))
+}
+
+fn parse_bool(value: &str) -> Result<bool, anyhow::Error> {
+    match value {
+        "true" => Ok(true),
+        "false" => Ok(false),
+        _ => Err(anyhow::anyhow!("Expected true or false")),
+    }
+}
+
+impl Config {
+    pub fn load() -> Result<Config, anyhow::Error> {
+        let Some(path) = config_path() else {
+            return Ok(Config::default());
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(e.into()),
+        };
+
+        Config::parse(&contents).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+    }
+
+    /// Parses the flat `key = value` subset of toml the config file uses: strings, numbers,
+    /// booleans and arrays of strings on one line each, without tables or escapes.
+    fn parse(contents: &str) -> Result<Config, anyhow::Error> {
+        let mut config = Config::default();
+        for (i, line) in contents.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                return Err(anyhow::anyhow!("line {}: Tables aren't supported", i + 1));
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("line {}: Expected key = value", i + 1))?;
+            let value = value.trim();
+            let result = match key.trim() {
+                "confirm" => parse_bool(value).map(|v| config.confirm = Some(v)),
+                "max_time" => parse_number(value).map(|v| config.max_time = Some(v)),
+                "device" => unquote(value).map(|v| config.device = Some(v.to_owned())),
+                "id3_version" => parse_enum(value).map(|v| config.id3_version = Some(v)),
+                "mirror_ape" => parse_bool(value).map(|v| config.mirror_ape = Some(v)),
+                "backup" => parse_bool(value).map(|v| config.backup = Some(v)),
+                "bpm_tag" => unquote(value).map(|v| config.bpm_tag = Some(v.to_owned())),
+                "bpm_tag_only" => parse_bool(value).map(|v| config.bpm_tag_only = Some(v)),
+                "write_comment" => parse_bool(value).map(|v| config.write_comment = Some(v)),
+                "backup_dir" => unquote(value).map(|v| config.backup_dir = Some(v.into())),
+                "estimate" => parse_bool(value).map(|v| config.estimate = Some(v)),
+                "acoustid_key" => unquote(value).map(|v| config.acoustid_key = Some(v.to_owned())),
+                "bpm_api" => unquote(value).map(|v| config.bpm_api = Some(v.to_owned())),
+                "skip_tagged" => parse_bool(value).map(|v| config.skip_tagged = Some(v)),
+                "shuffle" => parse_bool(value).map(|v| config.shuffle = Some(v)),
+                "sort" => parse_enum(value).map(|v| config.sort = Some(v)),
+                "spectrogram" => parse_bool(value).map(|v| config.spectrogram = Some(v)),
+                "replay_gain" => parse_bool(value).map(|v| config.replay_gain = Some(v)),
+                "key_notation" => parse_enum(value).map(|v| config.key_notation = Some(v)),
+                "theme" => parse_enum(value).map(|v| config.theme = Some(v)),
+                "graphics" => parse_enum(value).map(|v| config.graphics = Some(v)),
+                "table_height" => match parse_number(value) {
+                    Ok(v) if !(1..=100).contains(&v) => {
+                        Err(anyhow::anyhow!("Expected a percentage from 1 to 100"))
+                    }
+                    result => result.map(|v| config.table_height = Some(v)),
+                },
+                "compact" => parse_bool(value).map(|v| config.compact = Some(v)),
+                "hide" => parse_list(value)
+                    .and_then(|v| v.into_iter().map(parse_enum).collect())
+                    .map(|v| config.hide = Some(v)),
+                "latency" => parse_number(value).map(|v| config.latency = Some(v)),
+                "precision" => parse_number(value).map(|v| config.precision = Some(v)),
+                "rounding" => parse_enum(value).map(|v| config.rounding = Some(v)),
+                "bpm_range" => unquote(value)
+                    .and_then(str::parse)
+                    .map(|v| config.bpm_range = Some(v)),
+                "num_avg" => unquote(value)
+                    .and_then(str::parse)
+                    .map(|v| config.num_avg = Some(v)),
+                "estimator" => parse_enum(value).map(|v| config.estimator = Some(v)),
+                "outlier_threshold" => {
+                    parse_number(value).map(|v| config.outlier_threshold = Some(v))
+                }
+                "idle_reset" => parse_number(value).map(|v| config.idle_reset = Some(v)),
+                "min_taps" => parse_number(value).map(|v| config.min_taps = Some(v)),
+                "auto_confirm" => match parse_number(value) {
+                    Ok(0) => Err(anyhow::anyhow!("Expected a positive number")),
+                    result => result.map(|v| config.auto_confirm = Some(v)),
+                },
+                "auto_tolerance" => parse_number(value).map(|v| config.auto_tolerance = Some(v)),
+                "beats_per_tap" => match parse_number(value) {
+                    Ok(0) => Err(anyhow::anyhow!("Expected a positive number")),
+                    result => result.map(|v| config.beats_per_tap = Some(v)),
+                },
+                "volume" => match parse_number(value) {
+                    Ok(v) if v > MAX_VOLUME => Err(anyhow::anyhow!(
+                        "Expected a percentage up to {}",
+                        MAX_VOLUME
+                    )),
+                    result => result.map(|v| config.volume = Some(v)),
+                },
+                "start_at" => unquote(value)
+                    .and_then(str::parse)
+                    .map(|v| config.start_at = Some(v)),
+                "preview" => match parse_number(value) {
+                    Ok(0) => Err(anyhow::anyhow!("Expected a positive number")),
+                    result => result.map(|v| config.preview = Some(v)),
+                },
+                key => Err(anyhow::anyhow!("Unknown key {}", key)),
+            };
+            result.map_err(|e| anyhow::anyhow!("line {}: {}", i + 1, e))?;
+        }
+
+        Ok(config)
+    }
+}
@@ -0,0 +1,255 @@
+use std::env;
+
+use base64::prelude::*;
+use image::{imageops::FilterType, RgbImage};
+use ratatui::{
+    layout::Rect,
+    style::{Color, Style},
+    text::{Line, Span},
+};
+
+/// Cover art is scaled down to this straight after decoding, which is plenty for a corner of the
+/// tui and keeps redrawing it cheap.
+const MAX_SIZE: u32 = 512;
+/// Terminals don't say how big their cells are in pixels, so assume a typical one.
+const CELL_WIDTH: u32 = 10;
+const CELL_HEIGHT: u32 = 20;
+/// Kitty takes images in pieces of at most this much base64.
+const KITTY_CHUNK: usize = 4096;
+/// Sixel colours are rounded to this many levels of red, green and blue each, so the palette
+/// fits in the 256 registers most terminals have.
+const LEVELS: u16 = 6;
+
+/// How cover art is drawn.
+#[derive(Clone, Copy, Default, PartialEq, Debug, clap::ValueEnum)]
+pub enum Graphics {
+    /// Pick one from what the terminal says it is
+    #[default]
+    Auto,
+    /// The kitty graphics protocol, which Ghostty speaks too
+    Kitty,
+    /// iTerm2's inline images, which WezTerm shows too
+    Iterm,
+    Sixel,
+    /// Coloured half blocks, which work in any terminal with true colour
+    Blocks,
+}
+
+impl Graphics {
+    /// Works out what Auto means here.  Tmux doesn't pass graphics through, so it gets blocks.
+    pub fn detect(self) -> Graphics {
+        if self != Graphics::Auto {
+            return self;
+        }
+        let var = |name| env::var(name).unwrap_or_default();
+        let term = var("TERM");
+        if env::var_os("TMUX").is_some() {
+            Graphics::Blocks
+        } else if env::var_os("KITTY_WINDOW_ID").is_some()
+            || term.contains("kitty")
+            || term.contains("ghostty")
+        {
+            Graphics::Kitty
+        } else if matches!(var("TERM_PROGRAM").as_str(), "iTerm.app" | "WezTerm") {
+            Graphics::Iterm
+        } else if term.starts_with("foot") || term.starts_with("mlterm") || term.contains("sixel") {
+            Graphics::Sixel
+        } else {
+            Graphics::Blocks
+        }
+    }
+
+    /// Whether images are drawn over the tui with escape sequences, rather than as text.
+    pub fn escaped(self) -> bool {
+        matches!(self, Graphics::Kitty | Graphics::Iterm | Graphics::Sixel)
+    }
+
+    /// Takes down whatever was drawn last.  Images drawn into the cells themselves go when the
+    /// cells are redrawn, so only kitty needs telling.
+    pub fn clear(self) -> &'static str {
+        match self {
+            Graphics::Kitty => "\x1b_Ga=d,q=2\x1b\\",
+            _ => "",
+        }
+    }
+}
+
+/// A song's cover art, decoded.
+pub struct Cover {
+    /// The image as it was embedded, for terminals that decode it themselves.
+    data: Vec<u8>,
+    image: RgbImage,
+}
+
+impl Cover {
+    pub fn decode(data: Vec<u8>) -> Result<Cover, anyhow::Error> {
+        let image = image::load_from_memory(&data)?
+            .thumbnail(MAX_SIZE, MAX_SIZE)
+            .to_rgb8();
+        Ok(Cover { data, image })
+    }
+
+    /// The biggest part of an area the image fits in without being stretched, centred across
+    /// it.  Cells are taken to be twice as tall as they are wide.
+    pub fn fit(&self, area: Rect) -> Rect {
+        let (width, height) = self.image.dimensions();
+        let (width, height) = (width.max(1), height.max(1));
+        // In half cells, which are about square.
+        let (columns, rows) = (u32::from(area.width), u32::from(area.height) * 2);
+        let (columns, rows) = if width * rows > height * columns {
+            (columns, (height * columns / width).max(1))
+        } else {
+            ((width * rows / height).max(1), rows)
+        };
+        let columns = columns as u16;
+        Rect {
+            x: area.x + (area.width - columns) / 2,
+            y: area.y,
+            width: columns,
+            height: rows.div_ceil(2) as u16,
+        }
+    }
+
+    /// The image as half blocks, with two pixels to a cell, to fill the area from fit.
+    pub fn blocks(&self, area: Rect) -> Vec<Line<'static>> {
+        let image = self.scaled(u32::from(area.width), u32::from(area.height) * 2);
+        let colour = |x, y| {
+            let [r, g, b] = image.get_pixel(x, y).0;
+            Color::Rgb(r, g, b)
+        };
+        (0..u32::from(area.height))
+            .map(|row| {
+                Line::from(
+                    (0..u32::from(area.width))
+                        .map(|x| {
+                            let style = Style::default()
+                                .fg(colour(x, row * 2))
+                                .bg(colour(x, row * 2 + 1));
+                            Span::styled("▀", style)
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect()
+    }
+
+    /// The escape sequence that draws the image over the area from fit, starting from the
+    /// cursor at its top left.
+    pub fn escape(&self, graphics: Graphics, area: Rect) -> String {
+        let (columns, rows) = (u32::from(area.width), u32::from(area.height));
+        match graphics {
+            Graphics::Kitty => {
+                let image = self.scaled(columns * CELL_WIDTH, rows * CELL_HEIGHT);
+                let encoded = BASE64_STANDARD.encode(image.as_raw());
+                let chunks = encoded.as_bytes().chunks(KITTY_CHUNK).collect::<Vec<_>>();
+                let mut escape = String::new();
+                for (i, chunk) in chunks.iter().enumerate() {
+                    let more = u8::from(i + 1 < chunks.len());
+                    let chunk = std::str::from_utf8(chunk).unwrap();
+                    if i == 0 {
+                        escape += &format!(
+                            "\x1b_Ga=T,f=24,s={},v={},c={},r={},C=1,q=2,m={};{}\x1b\\",
+                            image.width(),
+                            image.height(),
+                            columns,
+                            rows,
+                            more,
+                            chunk
+                        );
+                    } else {
+                        escape += &format!("\x1b_Gm={};{}\x1b\\", more, chunk);
+                    }
+                }
+                escape
+            }
+            Graphics::Iterm => format!(
+                "\x1b]1337;File=inline=1;size={};width={};height={};preserveAspectRatio=1:{}\x07",
+                self.data.len(),
+                columns,
+                rows,
+                BASE64_STANDARD.encode(&self.data)
+            ),
+            Graphics::Sixel => sixel(&self.scaled(columns * CELL_WIDTH, rows * CELL_HEIGHT)),
+            Graphics::Auto | Graphics::Blocks => String::new(),
+        }
+    }
+
+    fn scaled(&self, width: u32, height: u32) -> RgbImage {
+        image::imageops::resize(
+            &self.image,
+            width.max(1),
+            height.max(1),
+            FilterType::Triangle,
+        )
+    }
+}
+
+/// Encodes an image as sixels, a band of six rows at a time.
+fn sixel(image: &RgbImage) -> String {
+    let (width, height) = image.dimensions();
+    let level = |c: u8| (u16::from(c) * (LEVELS - 1) + 127) / 255;
+    let colours = image
+        .pixels()
+        .map(|pixel| {
+            let [r, g, b] = pixel.0.map(level);
+            (r * LEVELS + g) * LEVELS + b
+        })
+        .collect::<Vec<_>>();
+    let palette = LEVELS.pow(3);
+
+    let mut escape = format!("\x1bPq\"1;1;{};{}", width, height);
+    let percent = |level: u16| level * 100 / (LEVELS - 1);
+    for colour in 0..palette {
+        escape += &format!(
+            "#{};2;{};{};{}",
+            colour,
+            percent(colour / LEVELS / LEVELS),
+            percent(colour / LEVELS % LEVELS),
+            percent(colour % LEVELS)
+        );
+    }
+    for band in (0..height).step_by(6) {
+        let rows = band..(band + 6).min(height);
+        let at = |x: u32, y: u32| colours[(y * width + x) as usize];
+        let mut used = vec![false; palette.into()];
+        for y in rows.clone() {
+            for x in 0..width {
+                used[usize::from(at(x, y))] = true;
+            }
+        }
+
+        for colour in (0..palette).filter(|colour| used[usize::from(*colour)]) {
+            escape += &format!("#{}", colour);
+            let mut run: Option<(char, usize)> = None;
+            for x in 0..width {
+                let bits = rows
+                    .clone()
+                    .enumerate()
+                    .filter(|(_, y)| at(x, *y) == colour)
+                    .fold(0, |bits, (i, _)| bits | 1 << i);
+                let sixel = char::from(63 + bits);
+                match &mut run {
+                    Some((last, count)) if *last == sixel => *count += 1,
+                    _ => {
+                        push_run(&mut escape, run.take());
+                        run = Some((sixel, 1));
+                    }
+                }
+            }
+            push_run(&mut escape, run);
+            // Back to the start of the band for the next colour.
+            escape.push('$');
+        }
+        escape.push('-');
+    }
+    escape + "\x1b\\"
+}
+
+/// Writes out a run of the same sixel, as a count and the sixel once if that's shorter.
+fn push_run(escape: &mut String, run: Option<(char, usize)>) {
+    match run {
+        Some((sixel, count)) if count > 3 => *escape += &format!("!{}{}", count, sixel),
+        Some((sixel, count)) => escape.extend(std::iter::repeat_n(sixel, count)),
+        None => {}
+    }
+}
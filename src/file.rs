@@ -1,98 +1,1301 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+    time::Duration,
+};
+
+use base64::prelude::*;
 use id3::TagLike;
 
+mod apev2;
+mod asf;
+mod dsf;
+mod mp4;
+mod vorbis;
+
 pub trait Music {
     fn path(&self) -> &str;
-    fn bpm(&self) -> Option<u32>;
-    fn set_bpm(&mut self, bpm: u32) -> Result<(), anyhow::Error>;
+    fn format(&self) -> Format;
+    fn bpm(&self) -> Option<f64>;
+    /// Saves the BPM, along with an estimated key if there is one.  The key only goes in if the
+    /// track doesn't already have one, so a key set by hand isn't replaced with a guess.
+    fn set_bpm(&mut self, bpm: f64, key: Option<Key>) -> Result<(), anyhow::Error>;
+    /// Saves a track with several tempos.  The longest section's BPM is written as the BPM, and
+    /// the full list is written to a separate field.  The key is saved as with set_bpm.
+    fn set_sections(&mut self, sections: &[Section], key: Option<Key>)
+        -> Result<(), anyhow::Error>;
+    /// Saves the BPM along with where the beat grid starts, for DJ software.  The key is saved as
+    /// with set_bpm.
+    fn set_beat_grid(
+        &mut self,
+        bpm: f64,
+        offset: Duration,
+        key: Option<Key>,
+    ) -> Result<(), anyhow::Error>;
+    /// Saves an estimated key on its own, unless the track already has one.
+    fn set_key(&mut self, key: Key) -> Result<(), anyhow::Error>;
+    /// Reads the fields saving a BPM can change, to put them back with restore.
+    fn backup(&self) -> Result<Backup, anyhow::Error>;
+    /// Undoes a save by putting the fields back how they were, removing any that weren't there.
+    fn restore(&mut self, backup: Backup) -> Result<(), anyhow::Error>;
+    /// Saves edits to the artist, title and so on in one go.  Blank values remove the field.
+    fn set_metadata(&mut self, edits: &[(MetadataField, String)]) -> Result<(), anyhow::Error>;
+    /// The embedded cover art, as a jpeg or png file.
+    fn picture(&self) -> Result<Option<Vec<u8>>, anyhow::Error>;
+    /// How much to turn the track up or down by to play at the ReplayGain reference loudness, in
+    /// dB.
+    fn gain(&self) -> Option<f64>;
+    /// The musical key, as it's written in the tag.
+    fn key(&self) -> Option<&str>;
+    fn metadata(&self) -> &Metadata;
 }
 
-pub struct Mp3 {
-    path: String,
-    bpm: Option<u32>,
-}
-
-impl Mp3 {
-    pub fn new(path: String) -> Result<Mp3, anyhow::Error> {
-        let tag = match id3::Tag::read_from_path(&path) {
-            Ok(tag) => Some(tag),
-            Err(id3::Error {
-                kind: id3::ErrorKind::NoTag,
-                ..
-            }) => None,
-            Err(e) => return Err(e.into()),
-        };
+/// Everything a save can change, as it was beforehand.
+pub struct Backup {
+    bpm: Option<f64>,
+    fields: Vec<(Field, Option<String>)>,
+}
+
+impl Backup {
+    /// The BPM before the save.
+    pub fn bpm(&self) -> Option<f64> {
+        self.bpm
+    }
+}
+
+/// How the BPM is noted in comments.
+const BPM_NOTE: &str = "BPM: ";
+
+/// The fields written when saving a BPM.
+const SAVED_FIELDS: [Field; 4] = [
+    Field::Bpm,
+    Field::BpmSections,
+    Field::BeatGridOffset,
+    Field::Key,
+];
+
+/// What a track is, for showing in the song list.
+#[derive(Clone, Default)]
+pub struct Metadata {
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+}
+
+/// The parts of the metadata that can be edited from the tui.
+#[derive(Clone, Copy)]
+pub enum MetadataField {
+    Artist,
+    Title,
+    Album,
+    Genre,
+}
+
+impl MetadataField {
+    pub const ALL: [MetadataField; 4] = [
+        MetadataField::Artist,
+        MetadataField::Title,
+        MetadataField::Album,
+        MetadataField::Genre,
+    ];
 
-        let bpm = tag
-            .as_ref()
-            .and_then(|tag| tag.get("TBPM"))
-            .and_then(|bpm| bpm.content().text())
-            .and_then(|bpm| bpm.parse().ok());
+    pub fn name(self) -> &'static str {
+        match self {
+            MetadataField::Artist => "Artist",
+            MetadataField::Title => "Title",
+            MetadataField::Album => "Album",
+            MetadataField::Genre => "Genre",
+        }
+    }
 
-        Ok(Mp3 { path, bpm })
+    fn field(self) -> Field {
+        match self {
+            MetadataField::Artist => Field::Artist,
+            MetadataField::Title => Field::Title,
+            MetadataField::Album => Field::Album,
+            MetadataField::Genre => Field::Genre,
+        }
     }
 }
 
-impl Music for Mp3 {
-    fn path(&self) -> &str {
-        &self.path
+impl Metadata {
+    pub fn get(&self, field: MetadataField) -> Option<&str> {
+        self.field(field).as_deref()
     }
 
-    fn bpm(&self) -> Option<u32> {
-        self.bpm
+    fn field(&self, field: MetadataField) -> &Option<String> {
+        match field {
+            MetadataField::Artist => &self.artist,
+            MetadataField::Title => &self.title,
+            MetadataField::Album => &self.album,
+            MetadataField::Genre => &self.genre,
+        }
     }
 
-    fn set_bpm(&mut self, bpm: u32) -> Result<(), anyhow::Error> {
-        self.bpm = Some(bpm);
-        let mut tag = match id3::Tag::read_from_path(&self.path) {
-            Ok(tag) => tag,
-            Err(id3::Error {
-                kind: id3::ErrorKind::NoTag,
-                ..
-            }) => id3::Tag::default(),
-            Err(e) => {
-                return Err(e.into());
+    fn field_mut(&mut self, field: MetadataField) -> &mut Option<String> {
+        match field {
+            MetadataField::Artist => &mut self.artist,
+            MetadataField::Title => &mut self.title,
+            MetadataField::Album => &mut self.album,
+            MetadataField::Genre => &mut self.genre,
+        }
+    }
+}
+
+/// The musical key of a track.
+#[derive(Clone, Copy)]
+pub struct Key {
+    /// Semitones above C.
+    pub tonic: u8,
+    pub minor: bool,
+}
+
+const MAJOR_NAMES: [&str; 12] = [
+    "C", "Db", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B",
+];
+const MINOR_NAMES: [&str; 12] = [
+    "Cm", "C#m", "Dm", "Ebm", "Em", "Fm", "F#m", "Gm", "G#m", "Am", "Bbm", "Bm",
+];
+
+/// How keys are written to tags.
+#[derive(Clone, Copy, Default, Debug, clap::ValueEnum)]
+pub enum KeyNotation {
+    /// Like `Am` or `F#`
+    #[default]
+    Standard,
+    /// The Camelot wheel used by DJ software, like `8A` or `2B`
+    Camelot,
+}
+
+impl Key {
+    pub fn format(self, notation: KeyNotation) -> String {
+        match notation {
+            KeyNotation::Standard if self.minor => MINOR_NAMES[self.tonic as usize].to_owned(),
+            KeyNotation::Standard => MAJOR_NAMES[self.tonic as usize].to_owned(),
+            KeyNotation::Camelot => {
+                // Minor keys share a number with their relative major, three semitones up, and
+                // each step around the wheel is a fifth.
+                let major = if self.minor {
+                    self.tonic + 3
+                } else {
+                    self.tonic
+                };
+                let number = (7 * major as usize + 7) % 12 + 1;
+                format!("{}{}", number, if self.minor { 'A' } else { 'B' })
             }
+        }
+    }
+}
+
+/// A part of a track with its own tempo.
+#[derive(Clone, Copy)]
+pub struct Section {
+    pub start: Duration,
+    pub end: Duration,
+    pub bpm: f64,
+}
+
+/// Formats a time like `1:30`.
+pub fn format_time(time: Duration) -> String {
+    format!("{}:{:02}", time.as_secs() / 60, time.as_secs() % 60)
+}
+
+/// Formats sections like `0:00-1:30=128, 1:30-3:05=140`.
+pub fn format_sections(sections: &[Section], precision: usize) -> String {
+    sections
+        .iter()
+        .map(|section| {
+            format!(
+                "{}-{}={:.*}",
+                format_time(section.start),
+                format_time(section.end),
+                precision,
+                section.bpm
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The container formats crabtap can tag.
+#[derive(Clone, Copy)]
+pub enum Format {
+    Mp3,
+    Flac,
+    Ogg,
+    M4a,
+    Wav,
+    Aiff,
+    Ape,
+    Mpc,
+    Wma,
+    Dsf,
+}
+
+/// The id3v2 version tags are written with.  Some older players can't read 2.4.
+#[derive(Clone, Copy, Default, Debug, clap::ValueEnum)]
+pub enum Id3Version {
+    #[value(name = "2.3")]
+    V23,
+    #[default]
+    #[value(name = "2.4")]
+    V24,
+}
+
+impl From<Id3Version> for id3::Version {
+    fn from(version: Id3Version) -> id3::Version {
+        match version {
+            Id3Version::V23 => id3::Version::Id3v23,
+            Id3Version::V24 => id3::Version::Id3v24,
+        }
+    }
+}
+
+/// Settings that control how tags are written.
+#[derive(Clone, Default)]
+pub struct WriteOptions {
+    pub id3_version: Id3Version,
+    /// Also write fields to an APEv2 tag on mp3 files.
+    pub mirror_ape: bool,
+    /// The number of decimal places BPMs are written with.
+    pub precision: usize,
+    pub key_notation: KeyNotation,
+    /// Go through the motions of saving without writing anything to the file.
+    pub dry_run: bool,
+    /// Where to copy each file to before writing to it for the first time.
+    pub backup: Option<CopyTo>,
+    /// Another field to write the BPM to, for players that look somewhere unusual.
+    pub bpm_tag: Option<&'static str>,
+    /// Write the BPM only to bpm_tag, leaving the standard field alone.
+    pub bpm_tag_only: bool,
+    /// Also note the BPM in the comment, for players that show nothing else.
+    pub write_comment: bool,
+}
+
+impl WriteOptions {
+    /// The fields the BPM is written to, and read back from in this order.
+    fn bpm_fields(&self) -> impl Iterator<Item = Field> {
+        let standard = (!self.bpm_tag_only || self.bpm_tag.is_none()).then_some(Field::Bpm);
+        self.bpm_tag.map(Field::Custom).into_iter().chain(standard)
+    }
+}
+
+#[derive(Clone)]
+pub enum CopyTo {
+    /// Next to the file, with `.bak` on the end.
+    Sibling,
+    /// Into a directory, under the file's full path so files with the same name don't clash.
+    Dir(PathBuf),
+}
+
+impl CopyTo {
+    fn path(&self, path: &str) -> Result<PathBuf, anyhow::Error> {
+        Ok(match self {
+            CopyTo::Sibling => PathBuf::from(format!("{}.bak", path)),
+            CopyTo::Dir(dir) => dir.join(
+                fs::canonicalize(path)?
+                    .components()
+                    .filter(|component| matches!(component, Component::Normal(_)))
+                    .collect::<PathBuf>(),
+            ),
+        })
+    }
+}
+
+/// The tag fields crabtap reads and writes.  Every tagging scheme maps these to its own keys in
+/// one place below, so the semantics stay the same across formats.
+#[derive(Clone, Copy)]
+enum Field {
+    Bpm,
+    BpmSections,
+    /// Milliseconds from the start of the file to the first downbeat.
+    BeatGridOffset,
+    Key,
+    ReplayGain,
+    /// The EBU R128 gain in 1/256 dB, relative to -23 LUFS.
+    R128Gain,
+    Artist,
+    Title,
+    Album,
+    Genre,
+    Comment,
+    /// A field named by the user, which is the same name in every scheme that has free form
+    /// fields.
+    Custom(&'static str),
+}
+
+/// Fields without a standard id3 frame go in a user defined (TXXX) frame.
+enum Id3Key {
+    Frame(&'static str),
+    Extended(&'static str),
+    /// The comment (COMM) frame without a description, which is the one players show.
+    Comment,
+}
+
+/// Fields without a standard mp4 atom go in a freeform (----) atom.
+enum Mp4Key {
+    /// A standard atom holding an integer.
+    Atom(&'static [u8; 4]),
+    /// A standard atom holding text rather than an integer.
+    Text(&'static [u8; 4]),
+    Freeform(&'static str),
+}
+
+impl Field {
+    fn id3(self) -> Id3Key {
+        match self {
+            Field::Bpm => Id3Key::Frame("TBPM"),
+            Field::BpmSections => Id3Key::Extended("BPM_SECTIONS"),
+            Field::BeatGridOffset => Id3Key::Extended("BEAT_GRID_OFFSET"),
+            Field::Key => Id3Key::Frame("TKEY"),
+            Field::ReplayGain => Id3Key::Extended("REPLAYGAIN_TRACK_GAIN"),
+            Field::R128Gain => Id3Key::Extended("R128_TRACK_GAIN"),
+            Field::Artist => Id3Key::Frame("TPE1"),
+            Field::Title => Id3Key::Frame("TIT2"),
+            Field::Album => Id3Key::Frame("TALB"),
+            Field::Genre => Id3Key::Frame("TCON"),
+            Field::Comment => Id3Key::Comment,
+            // Text frame ids like TBPM are taken as frames, and anything else goes in a TXXX.
+            Field::Custom(name)
+                if name.len() == 4
+                    && name.starts_with('T')
+                    && name
+                        .bytes()
+                        .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit()) =>
+            {
+                Id3Key::Frame(name)
+            }
+            Field::Custom(name) => Id3Key::Extended(name),
+        }
+    }
+
+    fn vorbis(self) -> &'static str {
+        match self {
+            Field::Bpm => "BPM",
+            Field::BpmSections => "BPM_SECTIONS",
+            Field::BeatGridOffset => "BEAT_GRID_OFFSET",
+            Field::Key => "INITIALKEY",
+            Field::ReplayGain => "REPLAYGAIN_TRACK_GAIN",
+            Field::R128Gain => "R128_TRACK_GAIN",
+            Field::Artist => "ARTIST",
+            Field::Title => "TITLE",
+            Field::Album => "ALBUM",
+            Field::Genre => "GENRE",
+            Field::Comment => "COMMENT",
+            Field::Custom(name) => name,
+        }
+    }
+
+    fn ape(self) -> &'static str {
+        match self {
+            Field::Bpm => "BPM",
+            Field::BpmSections => "BPM_SECTIONS",
+            Field::BeatGridOffset => "BEAT_GRID_OFFSET",
+            Field::Key => "Key",
+            Field::ReplayGain => "REPLAYGAIN_TRACK_GAIN",
+            Field::R128Gain => "R128_TRACK_GAIN",
+            Field::Artist => "Artist",
+            Field::Title => "Title",
+            Field::Album => "Album",
+            Field::Genre => "Genre",
+            Field::Comment => "Comment",
+            Field::Custom(name) => name,
+        }
+    }
+
+    fn asf(self) -> &'static str {
+        match self {
+            Field::Bpm => "WM/BeatsPerMinute",
+            Field::BpmSections => "BPM_SECTIONS",
+            Field::BeatGridOffset => "BEAT_GRID_OFFSET",
+            Field::Key => "WM/InitialKey",
+            Field::ReplayGain => "REPLAYGAIN_TRACK_GAIN",
+            Field::R128Gain => "R128_TRACK_GAIN",
+            Field::Artist => "Author",
+            Field::Title => "Title",
+            Field::Album => "WM/AlbumTitle",
+            Field::Genre => "WM/Genre",
+            Field::Comment => "Description",
+            Field::Custom(name) => name,
+        }
+    }
+
+    fn mp4(self) -> Mp4Key {
+        match self {
+            Field::Bpm => Mp4Key::Atom(b"tmpo"),
+            Field::BpmSections => Mp4Key::Freeform("BPM_SECTIONS"),
+            Field::BeatGridOffset => Mp4Key::Freeform("BEAT_GRID_OFFSET"),
+            Field::Key => Mp4Key::Freeform("initialkey"),
+            Field::ReplayGain => Mp4Key::Freeform("replaygain_track_gain"),
+            Field::R128Gain => Mp4Key::Freeform("R128_TRACK_GAIN"),
+            Field::Artist => Mp4Key::Text(b"\xa9ART"),
+            Field::Title => Mp4Key::Text(b"\xa9nam"),
+            Field::Album => Mp4Key::Text(b"\xa9alb"),
+            Field::Genre => Mp4Key::Text(b"\xa9gen"),
+            Field::Comment => Mp4Key::Text(b"\xa9cmt"),
+            Field::Custom(name) => Mp4Key::Freeform(name),
+        }
+    }
+}
+
+/// A tag read from a file, abstracting over the tagging scheme of each format.
+trait Tag {
+    fn get(&self, field: Field) -> Option<String>;
+    fn set(&mut self, field: Field, value: String) -> Result<(), anyhow::Error>;
+    fn remove(&mut self, field: Field);
+    /// The front cover, or else whatever picture comes first, as an image file.
+    fn picture(&self) -> Option<Vec<u8>>;
+    fn write(&mut self, path: &str, options: &WriteOptions) -> Result<(), anyhow::Error>;
+}
+
+/// Treats a missing id3 tag as absent rather than as an error.
+fn id3_or_none(tag: id3::Result<id3::Tag>) -> Result<Option<id3::Tag>, anyhow::Error> {
+    match tag {
+        Ok(tag) => Ok(Some(tag)),
+        Err(id3::Error {
+            kind: id3::ErrorKind::NoTag,
+            ..
+        }) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Replaces a file's contents without ever leaving it half written.  The new contents go to a
+/// temporary file in the same directory, which is synced and then renamed over the original, so
+/// a crash or a full disk leaves either the old song or the new one.
+fn replace_contents(path: &str, contents: &[u8]) -> Result<(), anyhow::Error> {
+    replace_with(path, |file| Ok(file.write_all(contents)?))
+}
+
+/// Like replace_contents, for contents too big to build up in memory, which write streams into
+/// the temporary file.
+fn replace_with<F>(path: &str, write: F) -> Result<(), anyhow::Error>
+where
+    F: FnOnce(&mut File) -> Result<(), anyhow::Error>,
+{
+    let path = Path::new(path);
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("{}: Not a file", path.display()))?;
+    let mut temp_name = OsString::from(".");
+    temp_name.push(name);
+    temp_name.push(".crabtap-tmp");
+    let temp = path.with_file_name(temp_name);
+
+    let replace = || -> Result<(), anyhow::Error> {
+        let mut file = File::create(&temp)?;
+        write(&mut file)?;
+        file.set_permissions(fs::metadata(path)?.permissions())?;
+        file.sync_all()?;
+        fs::rename(&temp, path)?;
+        Ok(())
+    };
+    replace().inspect_err(|_| {
+        let _ = fs::remove_file(&temp);
+    })
+}
+
+/// Mp3, wav and aiff id3 tags are all handled by the id3 crate, which detects the container by
+/// content.  Dsf files keep their id3 tag at an offset stored in the file header.
+struct Id3Tag {
+    tag: id3::Tag,
+    dsf: bool,
+}
+
+impl Tag for Id3Tag {
+    fn get(&self, field: Field) -> Option<String> {
+        match field.id3() {
+            Id3Key::Frame(id) => self
+                .tag
+                .get(id)
+                .and_then(|frame| frame.content().text())
+                .map(str::to_owned),
+            Id3Key::Extended(description) => self
+                .tag
+                .extended_texts()
+                .find(|text| text.description == description)
+                .map(|text| text.value.clone()),
+            Id3Key::Comment => self
+                .tag
+                .comments()
+                .find(|comment| comment.description.is_empty())
+                .map(|comment| comment.text.clone()),
+        }
+    }
+
+    fn set(&mut self, field: Field, value: String) -> Result<(), anyhow::Error> {
+        match field.id3() {
+            Id3Key::Frame(id) => self.tag.set_text(id, value),
+            Id3Key::Extended(description) => {
+                self.tag.add_frame(id3::frame::ExtendedText {
+                    description: description.to_owned(),
+                    value,
+                });
+            }
+            Id3Key::Comment => {
+                let lang = self
+                    .tag
+                    .comments()
+                    .find(|comment| comment.description.is_empty())
+                    .map_or_else(|| "eng".to_owned(), |comment| comment.lang.clone());
+                self.tag.remove_comment(Some(""), None);
+                self.tag.add_frame(id3::frame::Comment {
+                    lang,
+                    description: String::new(),
+                    text: value,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, field: Field) {
+        match field.id3() {
+            Id3Key::Frame(id) => {
+                self.tag.remove(id);
+            }
+            Id3Key::Extended(description) => self.tag.remove_extended_text(Some(description), None),
+            Id3Key::Comment => self.tag.remove_comment(Some(""), None),
+        }
+    }
+
+    fn picture(&self) -> Option<Vec<u8>> {
+        self.tag
+            .pictures()
+            .find(|picture| picture.picture_type == id3::frame::PictureType::CoverFront)
+            .or_else(|| self.tag.pictures().next())
+            .map(|picture| picture.data.clone())
+    }
+
+    fn write(&mut self, path: &str, options: &WriteOptions) -> Result<(), anyhow::Error> {
+        let version = options.id3_version.into();
+        if self.dsf {
+            return dsf::write_tag(path, &self.tag, version);
+        }
+        // Written to a copy of the file in memory, which replaces it once it's complete.
+        let mut file = io::Cursor::new(fs::read(path)?);
+        self.tag.write_to_file(&mut file, version)?;
+        replace_contents(path, file.get_ref())
+    }
+}
+
+/// Some mp3s are tagged only with APEv2, so it's used as a fallback when the id3 tag lacks a
+/// field, and kept in sync on request.  A damaged APEv2 tag is treated as missing, so the file
+/// can still be read and saved, unless it has to be kept in sync.
+struct Mp3Tag {
+    id3: Id3Tag,
+    ape: Result<apev2::Tag, anyhow::Error>,
+}
+
+impl Tag for Mp3Tag {
+    fn get(&self, field: Field) -> Option<String> {
+        self.id3
+            .get(field)
+            .or_else(|| self.ape.as_ref().ok()?.get(field))
+    }
+
+    fn set(&mut self, field: Field, value: String) -> Result<(), anyhow::Error> {
+        self.id3.set(field, value.clone())?;
+        match &mut self.ape {
+            Ok(ape) => ape.set(field, value),
+            Err(_) => Ok(()),
+        }
+    }
+
+    fn remove(&mut self, field: Field) {
+        self.id3.remove(field);
+        if let Ok(ape) = &mut self.ape {
+            ape.remove(field.ape());
+        }
+    }
+
+    fn picture(&self) -> Option<Vec<u8>> {
+        self.id3
+            .picture()
+            .or_else(|| self.ape.as_ref().ok()?.picture())
+    }
+
+    fn write(&mut self, path: &str, options: &WriteOptions) -> Result<(), anyhow::Error> {
+        if !options.mirror_ape {
+            return self.id3.write(path, options);
+        }
+        // Checked first, so the two tags aren't left out of sync.
+        let ape = match &mut self.ape {
+            Ok(ape) => ape,
+            Err(e) => return Err(anyhow::anyhow!("Couldn't mirror to the APEv2 tag: {}", e)),
         };
-        tag.set_text("TBPM", bpm.to_string());
-        tag.write_to_path(&self.path, id3::Version::Id3v24)
-            .map_err(Into::<anyhow::Error>::into)?;
+        self.id3.write(path, options)?;
+        ape.write(path, options)
+    }
+}
+
+impl Tag for metaflac::Tag {
+    fn get(&self, field: Field) -> Option<String> {
+        self.get_vorbis(field.vorbis())
+            .and_then(|mut values| values.next())
+            .map(str::to_owned)
+    }
+
+    fn set(&mut self, field: Field, value: String) -> Result<(), anyhow::Error> {
+        self.set_vorbis(field.vorbis(), vec![value]);
+        Ok(())
+    }
+
+    fn remove(&mut self, field: Field) {
+        self.remove_vorbis(field.vorbis());
+    }
+
+    fn picture(&self) -> Option<Vec<u8>> {
+        self.pictures()
+            .find(|picture| picture.picture_type == metaflac::block::PictureType::CoverFront)
+            .or_else(|| self.pictures().next())
+            .map(|picture| picture.data.clone())
+    }
+
+    fn write(&mut self, path: &str, _options: &WriteOptions) -> Result<(), anyhow::Error> {
+        // The audio comes straight after the metadata blocks, which are all written afresh with
+        // some padding, as metaflac does when they don't fit in the old padding.
+        let audio = metaflac::Tag::skip_metadata(&mut File::open(path)?);
+        self.remove_blocks(metaflac::BlockType::Padding);
+        self.push_block(metaflac::Block::Padding(1024));
+        let mut contents = Vec::new();
+        self.write_to(&mut contents)?;
+        contents.extend_from_slice(&audio);
+        replace_contents(path, &contents)
+    }
+}
+
+impl Tag for vorbis::Comments {
+    fn get(&self, field: Field) -> Option<String> {
+        vorbis::Comments::get(self, field.vorbis()).map(str::to_owned)
+    }
+
+    fn set(&mut self, field: Field, value: String) -> Result<(), anyhow::Error> {
+        vorbis::Comments::set(self, field.vorbis(), value);
+        Ok(())
+    }
+
+    fn remove(&mut self, field: Field) {
+        vorbis::Comments::remove(self, field.vorbis());
+    }
+
+    fn picture(&self) -> Option<Vec<u8>> {
+        let block = BASE64_STANDARD
+            .decode(self.get("METADATA_BLOCK_PICTURE")?)
+            .ok()?;
+        vorbis::picture_data(&block)
+    }
+
+    fn write(&mut self, path: &str, _options: &WriteOptions) -> Result<(), anyhow::Error> {
+        vorbis::write_comments(path, self)
+    }
+}
+
+impl Tag for mp4::Items {
+    fn get(&self, field: Field) -> Option<String> {
+        match field.mp4() {
+            Mp4Key::Atom(kind) => Some(
+                mp4::Items::get(self, kind)?
+                    .iter()
+                    .fold(0u64, |n, byte| (n << 8) | u64::from(*byte))
+                    .to_string(),
+            ),
+            Mp4Key::Text(kind) => std::str::from_utf8(mp4::Items::get(self, kind)?)
+                .ok()
+                .map(str::to_owned),
+            Mp4Key::Freeform(name) => self.get_freeform(name).map(str::to_owned),
+        }
+    }
+
+    fn set(&mut self, field: Field, value: String) -> Result<(), anyhow::Error> {
+        match field.mp4() {
+            Mp4Key::Atom(kind) => {
+                // tmpo can only hold whole numbers.
+                let tmpo = value
+                    .parse::<f64>()
+                    .ok()
+                    .map(f64::round)
+                    .filter(|tmpo| (0.0..=u16::MAX as f64).contains(tmpo))
+                    .ok_or_else(|| anyhow::anyhow!("BPM {} does not fit in tmpo atom", value))?
+                    as u16;
+                // 21 is the well-known type for big endian signed integers.
+                mp4::Items::set(self, kind, 21, &tmpo.to_be_bytes());
+            }
+            // 1 is the well-known type for utf-8 text.
+            Mp4Key::Text(kind) => mp4::Items::set(self, kind, 1, value.as_bytes()),
+            Mp4Key::Freeform(name) => self.set_freeform(name, &value),
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, field: Field) {
+        match field.mp4() {
+            Mp4Key::Atom(kind) | Mp4Key::Text(kind) => mp4::Items::remove(self, kind),
+            Mp4Key::Freeform(name) => self.remove_freeform(name),
+        }
+    }
+
+    fn picture(&self) -> Option<Vec<u8>> {
+        self.get(b"covr").map(<[u8]>::to_vec)
+    }
+
+    fn write(&mut self, path: &str, _options: &WriteOptions) -> Result<(), anyhow::Error> {
+        self.write_to_path(path)
+    }
+}
 
+impl Tag for apev2::Tag {
+    fn get(&self, field: Field) -> Option<String> {
+        self.get_text(field.ape()).map(str::to_owned)
+    }
+
+    fn set(&mut self, field: Field, value: String) -> Result<(), anyhow::Error> {
+        self.set_text(field.ape(), &value);
         Ok(())
     }
+
+    fn remove(&mut self, field: Field) {
+        apev2::Tag::remove(self, field.ape());
+    }
+
+    fn picture(&self) -> Option<Vec<u8>> {
+        apev2::Tag::picture(self)
+    }
+
+    fn write(&mut self, path: &str, _options: &WriteOptions) -> Result<(), anyhow::Error> {
+        self.write_to_path(path)
+    }
 }
 
-pub struct Flac {
+impl Tag for asf::Attributes {
+    fn get(&self, field: Field) -> Option<String> {
+        self.get_text(field.asf())
+    }
+
+    fn set(&mut self, field: Field, value: String) -> Result<(), anyhow::Error> {
+        self.set_text(field.asf(), &value);
+        Ok(())
+    }
+
+    fn remove(&mut self, field: Field) {
+        asf::Attributes::remove(self, field.asf());
+    }
+
+    fn picture(&self) -> Option<Vec<u8>> {
+        asf::Attributes::picture(self)
+    }
+
+    fn write(&mut self, path: &str, _options: &WriteOptions) -> Result<(), anyhow::Error> {
+        self.write_to_path(path)
+    }
+}
+
+/// Every recognized file extension, in lowercase, including common aliases.
+static EXTENSIONS: phf::Map<&'static str, Format> = phf::phf_map! {
+    "mp3" => Format::Mp3,
+    "mp2" => Format::Mp3,
+    "mpga" => Format::Mp3,
+    "flac" => Format::Flac,
+    "fla" => Format::Flac,
+    "ogg" => Format::Ogg,
+    "oga" => Format::Ogg,
+    "m4a" => Format::M4a,
+    "m4b" => Format::M4a,
+    "m4p" => Format::M4a,
+    "mp4" => Format::M4a,
+    "wav" => Format::Wav,
+    "wave" => Format::Wav,
+    "aif" => Format::Aiff,
+    "aiff" => Format::Aiff,
+    "aifc" => Format::Aiff,
+    "ape" => Format::Ape,
+    "mpc" => Format::Mpc,
+    "mpp" => Format::Mpc,
+    "mp+" => Format::Mpc,
+    "wma" => Format::Wma,
+    "asf" => Format::Wma,
+    "dsf" => Format::Dsf,
+};
+
+/// Enough of the start of a file to recognize every supported format.
+const MAGIC_LEN: u64 = 36;
+
+const ASF_GUID: [u8; 16] = [
+    0x30, 0x26, 0xb2, 0x75, 0x8e, 0x66, 0xcf, 0x11, 0xa6, 0xd9, 0x00, 0xaa, 0x00, 0x62, 0xce, 0x6c,
+];
+
+fn read_magic(file: &mut File) -> Result<Vec<u8>, anyhow::Error> {
+    let mut magic = Vec::new();
+    file.take(MAGIC_LEN).read_to_end(&mut magic)?;
+    Ok(magic)
+}
+
+impl Format {
+    /// What the format is called, with the codec where the container doesn't make it obvious.
+    pub fn name(self) -> &'static str {
+        match self {
+            Format::Mp3 => "MP3",
+            Format::Flac => "FLAC",
+            Format::Ogg => "Ogg Vorbis",
+            Format::M4a => "MP4 (AAC or ALAC)",
+            Format::Wav => "WAV",
+            Format::Aiff => "AIFF",
+            Format::Ape => "Monkey's Audio",
+            Format::Mpc => "Musepack",
+            Format::Wma => "WMA",
+            Format::Dsf => "DSD (DSF)",
+        }
+    }
+
+    /// Detects the format of a file by its content, falling back to its extension.
+    pub fn detect(path: &str) -> Result<Option<Format>, anyhow::Error> {
+        let mut file = File::open(path)?;
+        let mut magic = read_magic(&mut file)?;
+
+        // Id3v2 tags can be prepended to anything, so look past them.  Most often it's an mp3.
+        let mut id3 = false;
+        while magic.len() >= 10 && magic.starts_with(b"ID3") {
+            id3 = true;
+            let size = magic[6..10]
+                .iter()
+                .fold(0u64, |size, byte| (size << 7) | u64::from(byte & 0x7f));
+            let footer = if magic[5] & 0x10 != 0 { 10 } else { 0 };
+            let offset = file.stream_position()? - magic.len() as u64;
+            file.seek(SeekFrom::Start(offset + 10 + size + footer))?;
+            magic = read_magic(&mut file)?;
+        }
+
+        let sniffed = Format::sniff(&magic).or(if id3 { Some(Format::Mp3) } else { None });
+        Ok(sniffed.or_else(|| Format::from_extension(path)))
+    }
+
+    fn sniff(magic: &[u8]) -> Option<Format> {
+        let at = |offset: usize, expected: &[u8]| {
+            magic.get(offset..offset + expected.len()) == Some(expected)
+        };
+
+        if at(0, b"fLaC") {
+            Some(Format::Flac)
+        } else if at(0, b"OggS") && at(28, b"\x01vorbis") {
+            Some(Format::Ogg)
+        } else if at(4, b"ftyp") {
+            Some(Format::M4a)
+        } else if at(0, b"RIFF") && at(8, b"WAVE") {
+            Some(Format::Wav)
+        } else if at(0, b"FORM") && (at(8, b"AIFF") || at(8, b"AIFC")) {
+            Some(Format::Aiff)
+        } else if at(0, b"MAC ") {
+            Some(Format::Ape)
+        } else if at(0, b"MPCK") || at(0, b"MP+") {
+            Some(Format::Mpc)
+        } else if at(0, &ASF_GUID) {
+            Some(Format::Wma)
+        } else if at(0, b"DSD ") {
+            Some(Format::Dsf)
+        } else if magic.len() >= 2
+            && magic[0] == 0xff
+            && magic[1] & 0xe0 == 0xe0
+            && magic[1] & 0x06 != 0
+        {
+            // An mpeg audio frame sync with a non-zero layer, which rules out adts aac.
+            Some(Format::Mp3)
+        } else {
+            None
+        }
+    }
+
+    /// Looks up a file's extension case insensitively in the extension table.
+    pub fn from_extension(path: &str) -> Option<Format> {
+        let extension = Path::new(path)
+            .extension()
+            .and_then(OsStr::to_str)?
+            .to_ascii_lowercase();
+        EXTENSIONS.get(extension.as_str()).copied()
+    }
+
+    /// Reads the tag for a file of this format, returning an empty tag if the file has none.
+    fn read_tag(self, path: &str) -> Result<Box<dyn Tag>, anyhow::Error> {
+        Ok(match self {
+            Format::Mp3 => Box::new(Mp3Tag {
+                id3: Id3Tag {
+                    tag: id3_or_none(id3::Tag::read_from_path(path))?.unwrap_or_default(),
+                    dsf: false,
+                },
+                ape: apev2::Tag::read_from_path(path)
+                    .map(|ape| ape.unwrap_or_else(apev2::Tag::new)),
+            }),
+            Format::Wav | Format::Aiff => Box::new(Id3Tag {
+                tag: id3_or_none(id3::Tag::read_from_path(path))?.unwrap_or_default(),
+                dsf: false,
+            }),
+            Format::Dsf => Box::new(Id3Tag {
+                tag: dsf::read_tag(path)?.unwrap_or_default(),
+                dsf: true,
+            }),
+            Format::Flac => Box::new(metaflac::Tag::read_from_path(path)?),
+            Format::Ogg => Box::new(vorbis::read_comments(path)?),
+            Format::M4a => Box::new(mp4::Items::read_from_path(path)?),
+            Format::Ape | Format::Mpc => {
+                Box::new(apev2::Tag::read_from_path(path)?.unwrap_or_else(apev2::Tag::new))
+            }
+            Format::Wma => Box::new(asf::Attributes::read_from_path(path)?),
+        })
+    }
+}
+
+/// Lists a directory for the browser: its subdirectories, then any songs, both by name.  Hidden
+/// files are left out.
+pub fn list_dir(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    let mut songs = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let hidden = path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|name| name.starts_with('.'));
+        if hidden {
+            continue;
+        }
+        if path.is_dir() {
+            dirs.push(path);
+        } else if path.to_str().and_then(Format::from_extension).is_some() {
+            songs.push(path);
+        }
+    }
+    dirs.sort();
+    songs.sort();
+    dirs.extend(songs);
+    Ok(dirs)
+}
+
+/// Every song in a directory and the ones under it, or just the path if it's a song itself.
+pub fn find_songs(path: &Path) -> Vec<PathBuf> {
+    if !path.is_dir() {
+        return vec![path.to_owned()];
+    }
+    // Anything that can't be read is left out, the same as in the browser.
+    list_dir(path)
+        .unwrap_or_default()
+        .iter()
+        .flat_map(|path| find_songs(path))
+        .collect()
+}
+
+/// Replaces any directories among the inputs with every song under them, and any glob patterns,
+/// like `**/*.flac`, with the songs they match in alphabetical order, for shells that don't expand
+/// them.  Paths that aren't valid unicode are left out, since tracks are opened by string.
+pub fn expand_inputs(inputs: Vec<String>) -> Vec<String> {
+    let to_string = |path: PathBuf| path.into_os_string().into_string().ok();
+    inputs
+        .into_iter()
+        .flat_map(|input| {
+            let path = Path::new(&input);
+            if path.is_dir() {
+                return find_songs(path).into_iter().filter_map(to_string).collect();
+            }
+            // A file whose name just happens to look like a pattern is taken as it is.
+            if path.exists() || !input.contains(['*', '?', '[']) {
+                return vec![input];
+            }
+            let options = glob::MatchOptions {
+                require_literal_leading_dot: true,
+                ..Default::default()
+            };
+            let Ok(matches) = glob::glob_with(&input, options) else {
+                return vec![input];
+            };
+            // `**` goes into hidden directories regardless, so they're left out here unless the
+            // pattern names one.
+            let hidden = |path: &Path| {
+                path.components().any(|component| match component {
+                    Component::Normal(name) => name
+                        .to_str()
+                        .is_some_and(|name| name.starts_with('.') && !input.contains(name)),
+                    _ => false,
+                })
+            };
+            let songs = matches
+                .filter_map(Result::ok)
+                .filter(|path| !hidden(path))
+                .flat_map(|path| find_songs(&path))
+                // Unlike a path given by name, anything matched that isn't a song is left alone,
+                // so `*` doesn't trip over cover images.
+                .filter(|path| path.to_str().and_then(Format::from_extension).is_some())
+                .filter_map(to_string)
+                .collect::<Vec<_>>();
+            // Nothing matching is reported the same as a missing file.
+            if songs.is_empty() {
+                vec![input]
+            } else {
+                songs
+            }
+        })
+        .collect()
+}
+
+pub struct Track {
     path: String,
-    bpm: Option<u32>,
+    format: Format,
+    bpm: Option<f64>,
+    gain: Option<f64>,
+    key: Option<String>,
+    metadata: Metadata,
+    options: WriteOptions,
+}
+
+/// Puts a `BPM: 128` note at the start of a comment, where players that only show its first line
+/// still show it, replacing any note from before.
+fn with_bpm_note(comment: &str, bpm: &str) -> String {
+    let note = format!("{}{}", BPM_NOTE, bpm);
+    std::iter::once(note.as_str())
+        .chain(comment.lines().filter(|line| !line.starts_with(BPM_NOTE)))
+        .collect::<Vec<_>>()
+        .join("\n")
 }
 
-impl Flac {
-    pub fn new(path: String) -> Result<Flac, anyhow::Error> {
-        let tag = metaflac::Tag::read_from_path(&path)?;
-        let bpm = tag
-            .get_vorbis("BPM")
-            .and_then(|mut bpm| bpm.next())
-            .and_then(|bpm| bpm.parse().ok());
+/// Reads the track gain, preferring ReplayGain's `-6.50 dB` over R128.
+fn read_gain(tag: &dyn Tag) -> Option<f64> {
+    let replay_gain = tag.get(Field::ReplayGain).and_then(|gain| {
+        let gain = gain.trim();
+        let gain = gain
+            .strip_suffix("dB")
+            .or_else(|| gain.strip_suffix("db"))
+            .unwrap_or(gain);
+        gain.trim().parse().ok()
+    });
+    // R128 aims 5 dB quieter than ReplayGain.
+    replay_gain.or_else(|| {
+        let gain = tag.get(Field::R128Gain)?.trim().parse::<i16>().ok()?;
+        Some(f64::from(gain) / 256.0 + 5.0)
+    })
+}
+
+impl Track {
+    pub fn new(
+        path: String,
+        format: Format,
+        options: WriteOptions,
+    ) -> Result<Track, anyhow::Error> {
+        let tag = format.read_tag(&path)?;
+        let bpm = options
+            .bpm_fields()
+            .find_map(|field| tag.get(field).and_then(|bpm| bpm.trim().parse().ok()));
+        let gain = read_gain(tag.as_ref());
+        let text = |field| tag.get(field).filter(|text| !text.trim().is_empty());
+        let metadata = Metadata {
+            artist: text(Field::Artist),
+            title: text(Field::Title),
+            album: text(Field::Album),
+            genre: text(Field::Genre),
+        };
+        let key = text(Field::Key);
+
+        Ok(Track {
+            path,
+            format,
+            bpm,
+            gain,
+            key,
+            metadata,
+            options,
+        })
+    }
+
+    fn set_bpm_fields(&self, tag: &mut dyn Tag, bpm: f64) -> Result<(), anyhow::Error> {
+        let bpm = format!("{:.*}", self.options.precision, bpm);
+        for field in self.options.bpm_fields() {
+            tag.set(field, bpm.clone())?;
+        }
+        if self.options.write_comment {
+            let comment = tag.get(Field::Comment).unwrap_or_default();
+            tag.set(Field::Comment, with_bpm_note(&comment, &bpm))?;
+        }
+        Ok(())
+    }
+
+    /// Reads the tag, sets the BPM, anything saved with it and the key, and writes it all back
+    /// in one go, so a save either happens or it doesn't.
+    fn save(
+        &mut self,
+        bpm: Option<f64>,
+        extra: Option<(Field, String)>,
+        key: Option<Key>,
+    ) -> Result<(), anyhow::Error> {
+        let mut tag = self.format.read_tag(&self.path)?;
+        let has_key = tag
+            .get(Field::Key)
+            .is_some_and(|key| !key.trim().is_empty());
+        let key = key
+            .filter(|_| !has_key)
+            .map(|key| key.format(self.options.key_notation));
+        if bpm.is_none() && extra.is_none() && key.is_none() {
+            return Ok(());
+        }
+
+        let set = || -> Result<(), anyhow::Error> {
+            if let Some(bpm) = bpm {
+                self.set_bpm_fields(tag.as_mut(), bpm)?;
+            }
+            if let Some((field, value)) = extra {
+                tag.set(field, value)?;
+            }
+            if let Some(key) = &key {
+                tag.set(Field::Key, key.clone())?;
+            }
+            Ok(())
+        };
+        set().map_err(|e| anyhow::anyhow!("{}: {}", self.path, e))?;
+        self.write(tag.as_mut())?;
+        if bpm.is_some() {
+            self.bpm = bpm;
+        }
+        if key.is_some() {
+            self.key = key;
+        }
+
+        Ok(())
+    }
 
-        Ok(Flac { path, bpm })
+    fn write(&self, tag: &mut dyn Tag) -> Result<(), anyhow::Error> {
+        if self.options.dry_run {
+            return Ok(());
+        }
+        if let Some(backup) = &self.options.backup {
+            // Anything already there is the original from an earlier session, so it's kept.
+            let copy = backup.path(&self.path)?;
+            if !copy.exists() {
+                if let Some(dir) = copy.parent() {
+                    fs::create_dir_all(dir)?;
+                }
+                fs::copy(&self.path, &copy)
+                    .map_err(|e| anyhow::anyhow!("{}: Can't back up: {}", self.path, e))?;
+            }
+        }
+        tag.write(&self.path, &self.options)
     }
 }
 
-impl Music for Flac {
+impl Music for Track {
     fn path(&self) -> &str {
         &self.path
     }
 
-    fn bpm(&self) -> Option<u32> {
+    fn format(&self) -> Format {
+        self.format
+    }
+
+    fn bpm(&self) -> Option<f64> {
         self.bpm
     }
 
-    fn set_bpm(&mut self, bpm: u32) -> Result<(), anyhow::Error> {
-        self.bpm = Some(bpm);
-        let mut tag =
-            metaflac::Tag::read_from_path(&self.path).map_err(Into::<anyhow::Error>::into)?;
-        tag.set_vorbis("BPM", vec![bpm.to_string()]);
-        tag.save().map_err(Into::<anyhow::Error>::into)?;
+    fn gain(&self) -> Option<f64> {
+        self.gain
+    }
+
+    fn key(&self) -> Option<&str> {
+        self.key.as_deref()
+    }
+
+    fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    fn set_bpm(&mut self, bpm: f64, key: Option<Key>) -> Result<(), anyhow::Error> {
+        self.save(Some(bpm), None, key)
+    }
+
+    fn set_sections(
+        &mut self,
+        sections: &[Section],
+        key: Option<Key>,
+    ) -> Result<(), anyhow::Error> {
+        let Some(primary) = sections
+            .iter()
+            .max_by(|a, b| (a.end - a.start).cmp(&(b.end - b.start)))
+        else {
+            return Err(anyhow::anyhow!("{}: No sections to save", self.path));
+        };
+
+        let sections_field = (
+            Field::BpmSections,
+            format_sections(sections, self.options.precision),
+        );
+        self.save(Some(primary.bpm), Some(sections_field), key)
+    }
+
+    fn set_beat_grid(
+        &mut self,
+        bpm: f64,
+        offset: Duration,
+        key: Option<Key>,
+    ) -> Result<(), anyhow::Error> {
+        let offset_field = (Field::BeatGridOffset, offset.as_millis().to_string());
+        self.save(Some(bpm), Some(offset_field), key)
+    }
+
+    fn set_key(&mut self, key: Key) -> Result<(), anyhow::Error> {
+        self.save(None, None, Some(key))
+    }
+
+    fn backup(&self) -> Result<Backup, anyhow::Error> {
+        let tag = self.format.read_tag(&self.path)?;
+        Ok(Backup {
+            bpm: self.bpm,
+            fields: SAVED_FIELDS
+                .iter()
+                .copied()
+                .chain(self.options.bpm_tag.map(Field::Custom))
+                .chain(self.options.write_comment.then_some(Field::Comment))
+                .map(|field| (field, tag.get(field)))
+                .collect(),
+        })
+    }
+
+    fn restore(&mut self, backup: Backup) -> Result<(), anyhow::Error> {
+        let mut tag = self.format.read_tag(&self.path)?;
+        let mut key = self.key.clone();
+        for (field, value) in backup.fields {
+            if matches!(field, Field::Key) {
+                key.clone_from(&value);
+            }
+            match value {
+                Some(value) => tag
+                    .set(field, value)
+                    .map_err(|e| anyhow::anyhow!("{}: {}", self.path, e))?,
+                None => tag.remove(field),
+            }
+        }
+        self.write(tag.as_mut())?;
+        self.bpm = backup.bpm;
+        self.key = key;
+
+        Ok(())
+    }
+
+    fn picture(&self) -> Result<Option<Vec<u8>>, anyhow::Error> {
+        Ok(self.format.read_tag(&self.path)?.picture())
+    }
+
+    fn set_metadata(&mut self, edits: &[(MetadataField, String)]) -> Result<(), anyhow::Error> {
+        let mut tag = self.format.read_tag(&self.path)?;
+        for (field, value) in edits {
+            let value = value.trim();
+            if value.is_empty() {
+                tag.remove(field.field());
+            } else {
+                tag.set(field.field(), value.to_owned())
+                    .map_err(|e| anyhow::anyhow!("{}: {}", self.path, e))?;
+            }
+        }
+        self.write(tag.as_mut())?;
+        for (field, value) in edits {
+            let value = value.trim();
+            *self.metadata.field_mut(*field) = (!value.is_empty()).then(|| value.to_owned());
+        }
 
         Ok(())
     }
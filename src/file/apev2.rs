@@ -0,0 +1,286 @@
+use std::{
+    fs::{self, File},
+    io::{Cursor, Read, Seek, SeekFrom},
+};
+
+const PREAMBLE: &[u8] = b"APETAGEX";
+const VERSION: u32 = 2000;
+const HEADER_LEN: usize = 32;
+const ID3V1_LEN: u64 = 128;
+
+const FLAG_HAS_HEADER: u32 = 1 << 31;
+const FLAG_IS_HEADER: u32 = 1 << 29;
+const FLAG_ITEM_TYPE: u32 = 0b110;
+const ITEM_TYPE_BINARY: u32 = 0b010;
+/// Binary items holding cover art, from most to least wanted.
+const COVER_KEYS: [&str; 2] = ["Cover Art (Front)", "Cover Art (Other)"];
+
+struct Item {
+    key: String,
+    flags: u32,
+    value: Vec<u8>,
+}
+
+/// An APEv2 tag, stored at the end of the file (before any ID3v1 tag).
+pub struct Tag {
+    items: Vec<Item>,
+}
+
+/// The position of an existing tag in a file.
+struct Location {
+    start: u64,
+    end: u64,
+}
+
+struct Footer {
+    size: u32,
+    count: u32,
+    flags: u32,
+}
+
+fn parse_footer(data: &[u8; HEADER_LEN]) -> Option<Footer> {
+    if &data[0..8] != PREAMBLE {
+        return None;
+    }
+    let field = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+
+    Some(Footer {
+        size: field(12),
+        count: field(16),
+        flags: field(20),
+    })
+}
+
+/// Returns where an APEv2 tag ends (or would end), which is before any ID3v1 tag.
+fn tag_end(file: &mut (impl Read + Seek)) -> Result<u64, anyhow::Error> {
+    let len = file.seek(SeekFrom::End(0))?;
+    if len >= ID3V1_LEN {
+        let mut magic = [0; 3];
+        file.seek(SeekFrom::Start(len - ID3V1_LEN))?;
+        file.read_exact(&mut magic)?;
+        if &magic == b"TAG" {
+            return Ok(len - ID3V1_LEN);
+        }
+    }
+    Ok(len)
+}
+
+fn locate(file: &mut (impl Read + Seek)) -> Result<(Location, Option<Footer>), anyhow::Error> {
+    let end = tag_end(file)?;
+    let missing = Location { start: end, end };
+    if end < HEADER_LEN as u64 {
+        return Ok((missing, None));
+    }
+
+    let mut data = [0; HEADER_LEN];
+    file.seek(SeekFrom::Start(end - HEADER_LEN as u64))?;
+    file.read_exact(&mut data)?;
+    let Some(footer) = parse_footer(&data) else {
+        return Ok((missing, None));
+    };
+
+    let mut size = footer.size as u64;
+    if footer.flags & FLAG_HAS_HEADER != 0 {
+        size += HEADER_LEN as u64;
+    }
+    if size > end || (footer.size as usize) < HEADER_LEN {
+        return Err(anyhow::anyhow!("Invalid APEv2 tag size"));
+    }
+
+    Ok((
+        Location {
+            start: end - size,
+            end,
+        },
+        Some(footer),
+    ))
+}
+
+impl Tag {
+    pub fn new() -> Tag {
+        Tag { items: Vec::new() }
+    }
+
+    pub fn read_from_path(path: &str) -> Result<Option<Tag>, anyhow::Error> {
+        Tag::read_from(&mut File::open(path)?)
+    }
+
+    fn read_from(file: &mut (impl Read + Seek)) -> Result<Option<Tag>, anyhow::Error> {
+        let (location, Some(footer)) = locate(file)? else {
+            return Ok(None);
+        };
+
+        let items_len = footer.size as usize - HEADER_LEN;
+        let mut data = vec![0; items_len];
+        file.seek(SeekFrom::Start(location.end - footer.size as u64))?;
+        file.read_exact(&mut data)?;
+
+        let mut items = Vec::new();
+        let mut rest = data.as_slice();
+        for _ in 0..footer.count {
+            if rest.len() < 8 {
+                return Err(anyhow::anyhow!("Truncated APEv2 tag"));
+            }
+            let value_len = u32::from_le_bytes(rest[0..4].try_into()?) as usize;
+            let flags = u32::from_le_bytes(rest[4..8].try_into()?);
+            rest = &rest[8..];
+
+            let key_len = rest
+                .iter()
+                .position(|b| *b == 0)
+                .ok_or_else(|| anyhow::anyhow!("Truncated APEv2 tag"))?;
+            let key = String::from_utf8_lossy(&rest[..key_len]).into_owned();
+            rest = &rest[key_len + 1..];
+
+            if rest.len() < value_len {
+                return Err(anyhow::anyhow!("Truncated APEv2 tag"));
+            }
+            items.push(Item {
+                key,
+                flags,
+                value: rest[..value_len].to_vec(),
+            });
+            rest = &rest[value_len..];
+        }
+
+        Ok(Some(Tag { items }))
+    }
+
+    /// Returns the value of a text item.  Keys are case insensitive.
+    pub fn get_text(&self, key: &str) -> Option<&str> {
+        self.items
+            .iter()
+            .find(|item| item.key.eq_ignore_ascii_case(key) && item.flags & FLAG_ITEM_TYPE == 0)
+            .and_then(|item| std::str::from_utf8(&item.value).ok())
+    }
+
+    pub fn set_text(&mut self, key: &str, value: &str) {
+        self.remove(key);
+        self.items.push(Item {
+            key: key.to_owned(),
+            flags: 0,
+            value: value.as_bytes().to_vec(),
+        });
+    }
+
+    /// Returns the front cover's image data.  Cover items start with the image's file name.
+    pub fn picture(&self) -> Option<Vec<u8>> {
+        let item = COVER_KEYS.iter().find_map(|key| {
+            self.items.iter().find(|item| {
+                item.key.eq_ignore_ascii_case(key)
+                    && item.flags & FLAG_ITEM_TYPE == ITEM_TYPE_BINARY
+            })
+        })?;
+        let name_len = item.value.iter().position(|byte| *byte == 0)?;
+        Some(item.value[name_len + 1..].to_vec())
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.items
+            .retain(|item| !item.key.eq_ignore_ascii_case(key));
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut items = Vec::new();
+        for item in &self.items {
+            items.extend_from_slice(&(item.value.len() as u32).to_le_bytes());
+            items.extend_from_slice(&item.flags.to_le_bytes());
+            items.extend_from_slice(item.key.as_bytes());
+            items.push(0);
+            items.extend_from_slice(&item.value);
+        }
+
+        let header = |flags: u32| {
+            let mut data = PREAMBLE.to_vec();
+            data.extend_from_slice(&VERSION.to_le_bytes());
+            data.extend_from_slice(&((items.len() + HEADER_LEN) as u32).to_le_bytes());
+            data.extend_from_slice(&(self.items.len() as u32).to_le_bytes());
+            data.extend_from_slice(&flags.to_le_bytes());
+            data.extend_from_slice(&[0; 8]);
+            data
+        };
+
+        let mut data = header(FLAG_HAS_HEADER | FLAG_IS_HEADER);
+        data.extend_from_slice(&items);
+        data.extend(header(FLAG_HAS_HEADER));
+        data
+    }
+
+    /// Replaces any existing APEv2 tag in the file, keeping a trailing ID3v1 tag in place.
+    pub fn write_to_path(&self, path: &str) -> Result<(), anyhow::Error> {
+        let out = self.rewrite(&fs::read(path)?)?;
+        super::replace_contents(path, &out)?;
+
+        Ok(())
+    }
+
+    /// The whole of a file with this tag in place of the old one.
+    fn rewrite(&self, data: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let (location, _) = locate(&mut Cursor::new(data))?;
+        let mut out = data[..location.start as usize].to_vec();
+        out.extend(self.serialize());
+        out.extend_from_slice(&data[location.end as usize..]);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUDIO: &[u8] = b"not really audio";
+
+    fn id3v1() -> Vec<u8> {
+        let mut tag = b"TAG".to_vec();
+        tag.resize(ID3V1_LEN as usize, b' ');
+        tag
+    }
+
+    fn read(data: &[u8]) -> Tag {
+        Tag::read_from(&mut Cursor::new(data)).unwrap().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut tag = Tag::new();
+        tag.set_text("Title", "Title");
+        tag.items.push(Item {
+            key: "Unknown".to_owned(),
+            flags: ITEM_TYPE_BINARY,
+            value: vec![1, 2, 3],
+        });
+        let mut file = AUDIO.to_vec();
+        file.extend(tag.serialize());
+        file.extend(id3v1());
+
+        let mut tag = read(&file);
+        tag.set_text("BPM", "128");
+        let file = tag.rewrite(&file).unwrap();
+
+        assert!(file.starts_with(AUDIO));
+        assert!(file.ends_with(&id3v1()));
+        let tag = read(&file);
+        assert_eq!(tag.get_text("bpm"), Some("128"));
+        assert_eq!(tag.get_text("Title"), Some("Title"));
+        let unknown = tag.items.iter().find(|item| item.key == "Unknown").unwrap();
+        assert_eq!(
+            (unknown.flags, &unknown.value[..]),
+            (ITEM_TYPE_BINARY, &[1, 2, 3][..])
+        );
+    }
+
+    #[test]
+    fn added_before_id3v1() {
+        let mut file = AUDIO.to_vec();
+        file.extend(id3v1());
+        assert!(Tag::read_from(&mut Cursor::new(&file)).unwrap().is_none());
+
+        let mut tag = Tag::new();
+        tag.set_text("BPM", "128");
+        let file = tag.rewrite(&file).unwrap();
+
+        assert!(file.starts_with(AUDIO));
+        assert!(file.ends_with(&id3v1()));
+        assert_eq!(read(&file).get_text("BPM"), Some("128"));
+    }
+}
@@ -0,0 +1,425 @@
+use std::{
+    fs::{self, File},
+    io::Read,
+};
+
+type Guid = [u8; 16];
+
+const HEADER_GUID: Guid = [
+    0x30, 0x26, 0xb2, 0x75, 0x8e, 0x66, 0xcf, 0x11, 0xa6, 0xd9, 0x00, 0xaa, 0x00, 0x62, 0xce, 0x6c,
+];
+const FILE_PROPERTIES_GUID: Guid = [
+    0xa1, 0xdc, 0xab, 0x8c, 0x47, 0xa9, 0xcf, 0x11, 0x8e, 0xe4, 0x00, 0xc0, 0x0c, 0x20, 0x53, 0x65,
+];
+const CONTENT_DESCRIPTION_GUID: Guid = [
+    0x33, 0x26, 0xb2, 0x75, 0x8e, 0x66, 0xcf, 0x11, 0xa6, 0xd9, 0x00, 0xaa, 0x00, 0x62, 0xce, 0x6c,
+];
+const EXTENDED_CONTENT_GUID: Guid = [
+    0x40, 0xa4, 0xd0, 0xd2, 0x07, 0xe3, 0xd2, 0x11, 0x97, 0xf0, 0x00, 0xa0, 0xc9, 0x5e, 0xa8, 0x50,
+];
+
+/// Header object GUID, size, object count and two reserved bytes.
+const HEADER_LEN: usize = 30;
+/// Object GUID and size.
+const OBJECT_HEADER_LEN: usize = 24;
+
+const TYPE_UNICODE: u16 = 0;
+const TYPE_BYTES: u16 = 1;
+const TYPE_DWORD: u16 = 3;
+const TYPE_QWORD: u16 = 4;
+const TYPE_WORD: u16 = 5;
+
+struct Descriptor {
+    name: String,
+    value_type: u16,
+    value: Vec<u8>,
+}
+
+/// The attributes of an ASF extended content description object, along with the title, author
+/// and so on from the plain content description object.
+pub struct Attributes {
+    descriptors: Vec<Descriptor>,
+    /// The strings of the content description object, in the order of DESCRIPTION_FIELDS, or
+    /// None if the file doesn't have one.  Changing any of them rewrites it, since that's where
+    /// other players look for the title and author.
+    description: Option<[String; 5]>,
+}
+
+/// The strings in a content description object, in order.
+const DESCRIPTION_FIELDS: [&str; 5] = ["Title", "Author", "Copyright", "Description", "Rating"];
+
+fn utf16_decode(data: &[u8]) -> String {
+    let units = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .take_while(|c| *c != 0)
+        .collect::<Vec<_>>();
+    String::from_utf16_lossy(&units)
+}
+
+fn utf16_encode(s: &str) -> Vec<u8> {
+    s.encode_utf16()
+        .chain(std::iter::once(0))
+        .flat_map(u16::to_le_bytes)
+        .collect()
+}
+
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], anyhow::Error> {
+    if data.len() < len {
+        return Err(anyhow::anyhow!("Truncated ASF object"));
+    }
+    let (bytes, rest) = data.split_at(len);
+    *data = rest;
+    Ok(bytes)
+}
+
+fn take_u16(data: &mut &[u8]) -> Result<u16, anyhow::Error> {
+    Ok(u16::from_le_bytes(take(data, 2)?.try_into()?))
+}
+
+/// Splits the header objects into (guid, complete object) pairs.
+fn objects(header: &[u8]) -> Result<Vec<(Guid, &[u8])>, anyhow::Error> {
+    let mut rest = &header[HEADER_LEN..];
+    let mut objects = Vec::new();
+    while rest.len() >= OBJECT_HEADER_LEN {
+        let guid = rest[0..16].try_into()?;
+        let size = u64::from_le_bytes(rest[16..24].try_into()?) as usize;
+        if size < OBJECT_HEADER_LEN {
+            return Err(anyhow::anyhow!("Invalid ASF object size"));
+        }
+        objects.push((guid, take(&mut rest, size)?));
+    }
+    Ok(objects)
+}
+
+fn read_header(path: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let mut file = File::open(path)?;
+    let mut header = vec![0; HEADER_LEN];
+    file.read_exact(&mut header)?;
+    if header[0..16] != HEADER_GUID {
+        return Err(anyhow::anyhow!("{}: Not an ASF file", path));
+    }
+    let size = u64::from_le_bytes(header[16..24].try_into()?);
+    if size < HEADER_LEN as u64 || size > file.metadata()?.len() {
+        return Err(anyhow::anyhow!("{}: Invalid ASF header size", path));
+    }
+    header.resize(size as usize, 0);
+    file.read_exact(&mut header[HEADER_LEN..])?;
+    Ok(header)
+}
+
+impl Attributes {
+    fn parse(mut data: &[u8]) -> Result<Attributes, anyhow::Error> {
+        let count = take_u16(&mut data)?;
+        let descriptors = (0..count)
+            .map(|_| -> Result<Descriptor, anyhow::Error> {
+                let name_len = take_u16(&mut data)? as usize;
+                let name = utf16_decode(take(&mut data, name_len)?);
+                let value_type = take_u16(&mut data)?;
+                let value_len = take_u16(&mut data)? as usize;
+                let value = take(&mut data, value_len)?.to_vec();
+                Ok(Descriptor {
+                    name,
+                    value_type,
+                    value,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Attributes {
+            descriptors,
+            description: None,
+        })
+    }
+
+    fn parse_description(mut data: &[u8]) -> Result<[String; 5], anyhow::Error> {
+        let mut lengths = [0; 5];
+        for len in &mut lengths {
+            *len = take_u16(&mut data)?;
+        }
+        let mut description = <[String; 5]>::default();
+        for (value, len) in description.iter_mut().zip(lengths) {
+            *value = utf16_decode(take(&mut data, len as usize)?);
+        }
+        Ok(description)
+    }
+
+    fn serialize_description(description: &[String; 5]) -> Vec<u8> {
+        // Blank strings are left out entirely rather than written as just a terminator.
+        let values = description
+            .iter()
+            .map(|value| {
+                if value.is_empty() {
+                    Vec::new()
+                } else {
+                    utf16_encode(value)
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut data = CONTENT_DESCRIPTION_GUID.to_vec();
+        data.extend_from_slice(&[0; 8]);
+        for value in &values {
+            data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+        }
+        for value in values {
+            data.extend(value);
+        }
+        let size = data.len() as u64;
+        data[16..24].copy_from_slice(&size.to_le_bytes());
+        data
+    }
+
+    /// Where a field lives in the content description object, if that's where it goes.
+    fn description_index(name: &str) -> Option<usize> {
+        DESCRIPTION_FIELDS.iter().position(|field| *field == name)
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = EXTENDED_CONTENT_GUID.to_vec();
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&(self.descriptors.len() as u16).to_le_bytes());
+        for descriptor in &self.descriptors {
+            let name = utf16_encode(&descriptor.name);
+            data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            data.extend(name);
+            data.extend_from_slice(&descriptor.value_type.to_le_bytes());
+            data.extend_from_slice(&(descriptor.value.len() as u16).to_le_bytes());
+            data.extend_from_slice(&descriptor.value);
+        }
+        let size = data.len() as u64;
+        data[16..24].copy_from_slice(&size.to_le_bytes());
+        data
+    }
+
+    pub fn read_from_path(path: &str) -> Result<Attributes, anyhow::Error> {
+        Attributes::from_header(&read_header(path)?)
+    }
+
+    fn from_header(header: &[u8]) -> Result<Attributes, anyhow::Error> {
+        let objects = objects(header)?;
+        let find = |wanted: Guid| {
+            objects
+                .iter()
+                .find(|(guid, _)| *guid == wanted)
+                .map(|(_, object)| &object[OBJECT_HEADER_LEN..])
+        };
+        let mut attributes = match find(EXTENDED_CONTENT_GUID) {
+            Some(object) => Attributes::parse(object)?,
+            None => Attributes {
+                descriptors: Vec::new(),
+                description: None,
+            },
+        };
+        if let Some(object) = find(CONTENT_DESCRIPTION_GUID) {
+            attributes.description = Some(Attributes::parse_description(object)?);
+        }
+        Ok(attributes)
+    }
+
+    /// Returns the attribute formatted as text, for string and integer attributes.  Fields of
+    /// the content description object are read from there first.
+    pub fn get_text(&self, name: &str) -> Option<String> {
+        let described = Attributes::description_index(name)
+            .zip(self.description.as_ref())
+            .map(|(index, description)| &description[index])
+            .filter(|value| !value.is_empty());
+        if let Some(value) = described {
+            return Some(value.clone());
+        }
+        let descriptor = self.descriptors.iter().find(|d| d.name == name)?;
+        let value = descriptor.value.as_slice();
+        match descriptor.value_type {
+            TYPE_UNICODE => Some(utf16_decode(value)),
+            TYPE_DWORD => Some(u32::from_le_bytes(value.try_into().ok()?).to_string()),
+            TYPE_QWORD => Some(u64::from_le_bytes(value.try_into().ok()?).to_string()),
+            TYPE_WORD => Some(u16::from_le_bytes(value.try_into().ok()?).to_string()),
+            _ => None,
+        }
+    }
+
+    pub fn set_text(&mut self, name: &str, value: &str) {
+        self.remove(name);
+        if let Some(index) = Attributes::description_index(name) {
+            self.description.get_or_insert_with(Default::default)[index] = value.to_owned();
+            return;
+        }
+        self.descriptors.push(Descriptor {
+            name: name.to_owned(),
+            value_type: TYPE_UNICODE,
+            value: utf16_encode(value),
+        });
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.descriptors.retain(|d| d.name != name);
+        if let Some((index, description)) =
+            Attributes::description_index(name).zip(self.description.as_mut())
+        {
+            description[index].clear();
+        }
+    }
+
+    /// Returns the image data of the first WM/Picture attribute.
+    pub fn picture(&self) -> Option<Vec<u8>> {
+        let descriptor = self
+            .descriptors
+            .iter()
+            .find(|d| d.name == "WM/Picture" && d.value_type == TYPE_BYTES)?;
+        // A picture type byte and the data's length come before the mime type and description,
+        // which are both nul terminated utf-16.
+        let value = descriptor.value.as_slice();
+        let len = u32::from_le_bytes(value.get(1..5)?.try_into().ok()?) as usize;
+        let mut rest = &value[5..];
+        for _ in 0..2 {
+            let end = rest.chunks_exact(2).position(|c| c == [0, 0])?;
+            rest = &rest[(end + 1) * 2..];
+        }
+        rest.get(..len).map(<[u8]>::to_vec)
+    }
+
+    /// Rewrites the header with the new extended content description object, and the content
+    /// description object if there is one.  Everything after the header is left untouched.
+    pub fn write_to_path(&self, path: &str) -> Result<(), anyhow::Error> {
+        let header = read_header(path)?;
+        let out = self.rewrite(&header, &fs::read(path)?)?;
+        super::replace_contents(path, &out)?;
+
+        Ok(())
+    }
+
+    /// The whole of an ASF file with the given header, rewritten with these attributes.
+    fn rewrite(&self, header: &[u8], file: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let mut body = Vec::new();
+        let mut count = 0u32;
+        let mut replaced = false;
+        let mut described = false;
+        let mut file_properties = None;
+        for (guid, object) in objects(header)? {
+            if guid == FILE_PROPERTIES_GUID && object.len() >= 48 {
+                file_properties = Some(HEADER_LEN + body.len());
+            }
+            match (guid, &self.description) {
+                (EXTENDED_CONTENT_GUID, _) if replaced => continue,
+                (EXTENDED_CONTENT_GUID, _) => {
+                    body.extend(self.serialize());
+                    replaced = true;
+                }
+                (CONTENT_DESCRIPTION_GUID, Some(_)) if described => continue,
+                (CONTENT_DESCRIPTION_GUID, Some(description)) => {
+                    body.extend(Attributes::serialize_description(description));
+                    described = true;
+                }
+                _ => body.extend_from_slice(object),
+            }
+            count += 1;
+        }
+        if !replaced {
+            body.extend(self.serialize());
+            count += 1;
+        }
+        if let (false, Some(description)) = (described, &self.description) {
+            body.extend(Attributes::serialize_description(description));
+            count += 1;
+        }
+
+        let mut out = header[..HEADER_LEN].to_vec();
+        out[16..24].copy_from_slice(&((HEADER_LEN + body.len()) as u64).to_le_bytes());
+        out[24..28].copy_from_slice(&count.to_le_bytes());
+        out.extend(body);
+        out.extend_from_slice(&file[header.len()..]);
+
+        // The file properties object records the size of the whole file.
+        if let Some(pos) = file_properties {
+            let file_size = out.len() as u64;
+            out[pos + 40..pos + 48].copy_from_slice(&file_size.to_le_bytes());
+        }
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"not really a data object";
+
+    fn object(guid: Guid, payload: &[u8]) -> Vec<u8> {
+        let mut object = guid.to_vec();
+        object.extend_from_slice(&((OBJECT_HEADER_LEN + payload.len()) as u64).to_le_bytes());
+        object.extend_from_slice(payload);
+        object
+    }
+
+    fn header(objects: &[Vec<u8>]) -> Vec<u8> {
+        let body = objects.concat();
+        let mut header = HEADER_GUID.to_vec();
+        header.extend_from_slice(&((HEADER_LEN + body.len()) as u64).to_le_bytes());
+        header.extend_from_slice(&(objects.len() as u32).to_le_bytes());
+        header.extend_from_slice(&[1, 2]);
+        header.extend(body);
+        header
+    }
+
+    fn header_of(file: &[u8]) -> &[u8] {
+        let size = u64::from_le_bytes(file[16..24].try_into().unwrap());
+        &file[..size as usize]
+    }
+
+    #[test]
+    fn round_trip() {
+        let unknown = Descriptor {
+            name: "Unknown".to_owned(),
+            value_type: TYPE_BYTES,
+            value: vec![1, 2, 3],
+        };
+        let attributes = Attributes {
+            descriptors: vec![unknown],
+            description: Some([
+                "Title".to_owned(),
+                "Artist".to_owned(),
+                String::new(),
+                String::new(),
+                String::new(),
+            ]),
+        };
+        // The file size goes 40 bytes into the file properties object, as in a real one.
+        let file_properties = object(FILE_PROPERTIES_GUID, &[0; 80]);
+        let other = object([7; 16], b"some other object");
+        let mut file = header(&[
+            file_properties,
+            Attributes::serialize_description(attributes.description.as_ref().unwrap()),
+            attributes.serialize(),
+            other.clone(),
+        ]);
+        file.extend_from_slice(DATA);
+
+        let mut attributes = Attributes::from_header(header_of(&file)).unwrap();
+        attributes.set_text("WM/BeatsPerMinute", "128");
+        attributes.set_text("Author", "Someone Else");
+        let file = attributes.rewrite(header_of(&file), &file).unwrap();
+
+        assert!(file.ends_with(DATA));
+        let header = header_of(&file);
+        let objects = objects(header).unwrap();
+        assert_eq!(u32::from_le_bytes(header[24..28].try_into().unwrap()), 4);
+        assert!(objects.iter().any(|(_, object)| *object == &other[..]));
+        let (_, file_properties) = objects[0];
+        let file_size = u64::from_le_bytes(file_properties[40..48].try_into().unwrap());
+        assert_eq!(file_size, file.len() as u64);
+
+        let attributes = Attributes::from_header(header).unwrap();
+        let get = |name| attributes.get_text(name);
+        assert_eq!(get("WM/BeatsPerMinute").as_deref(), Some("128"));
+        assert_eq!(get("Title").as_deref(), Some("Title"));
+        assert_eq!(get("Author").as_deref(), Some("Someone Else"));
+        let unknown = attributes
+            .descriptors
+            .iter()
+            .find(|d| d.name == "Unknown")
+            .unwrap();
+        assert_eq!(
+            (unknown.value_type, &unknown.value[..]),
+            (TYPE_BYTES, &[1, 2, 3][..])
+        );
+    }
+}
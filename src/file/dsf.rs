@@ -0,0 +1,162 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+};
+
+/// The "DSD " chunk: id, chunk size, total file size and the metadata pointer.
+const HEADER_LEN: usize = 28;
+
+struct Header {
+    metadata_offset: u64,
+}
+
+fn parse_header(data: &[u8]) -> Result<Header, anyhow::Error> {
+    if data.len() < HEADER_LEN || &data[0..4] != b"DSD " {
+        return Err(anyhow::anyhow!("Not a DSF file"));
+    }
+
+    Ok(Header {
+        metadata_offset: u64::from_le_bytes(data[20..28].try_into()?),
+    })
+}
+
+/// Reads the ID3v2 tag the metadata pointer refers to, if there is one.
+pub fn read_tag(path: &str) -> Result<Option<id3::Tag>, anyhow::Error> {
+    read_from(BufReader::new(File::open(path)?))
+}
+
+fn read_from(mut file: impl Read + Seek) -> Result<Option<id3::Tag>, anyhow::Error> {
+    let mut data = [0; HEADER_LEN];
+    file.read_exact(&mut data)?;
+    let header = parse_header(&data)?;
+    if header.metadata_offset == 0 {
+        return Ok(None);
+    }
+
+    file.seek(SeekFrom::Start(header.metadata_offset))?;
+    super::id3_or_none(id3::Tag::read_from2(file))
+}
+
+/// Replaces the ID3v2 tag at the end of the file and updates the header to point at it.
+pub fn write_tag(path: &str, tag: &id3::Tag, version: id3::Version) -> Result<(), anyhow::Error> {
+    let mut file = BufReader::new(File::open(path)?);
+    super::replace_with(path, |out| {
+        let mut out = BufWriter::new(out);
+        rewrite(&mut file, &mut out, tag, version)?;
+        Ok(out.flush()?)
+    })
+}
+
+/// Copies a dsf file with a new tag in place of the old one.  The audio is streamed across, since
+/// it can run to gigabytes.
+fn rewrite(
+    file: &mut (impl Read + Seek),
+    out: &mut impl Write,
+    tag: &id3::Tag,
+    version: id3::Version,
+) -> Result<(), anyhow::Error> {
+    let len = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut data = [0; HEADER_LEN];
+    file.read_exact(&mut data)?;
+    let header = parse_header(&data)?;
+
+    // The tag always comes after the audio data, so anything from the old pointer on is replaced.
+    let metadata_offset = match header.metadata_offset {
+        offset if offset >= HEADER_LEN as u64 && offset < len => offset,
+        _ => len,
+    };
+    let mut encoded = Vec::new();
+    tag.write_to(&mut encoded, version)?;
+    let file_size = metadata_offset + encoded.len() as u64;
+
+    data[12..20].copy_from_slice(&file_size.to_le_bytes());
+    data[20..28].copy_from_slice(&metadata_offset.to_le_bytes());
+    out.write_all(&data)?;
+    let audio_len = metadata_offset - HEADER_LEN as u64;
+    if io::copy(&mut file.take(audio_len), out)? != audio_len {
+        return Err(anyhow::anyhow!("Truncated dsf file"));
+    }
+    out.write_all(&encoded)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use id3::TagLike;
+
+    use super::*;
+
+    const AUDIO: &[u8] = b"fmt and data chunks";
+
+    fn dsf(tag: Option<&id3::Tag>) -> Vec<u8> {
+        let mut file = b"DSD ".to_vec();
+        file.extend_from_slice(&(HEADER_LEN as u64).to_le_bytes());
+        file.extend_from_slice(&[0; 16]);
+        file.extend_from_slice(AUDIO);
+        if let Some(tag) = tag {
+            let offset = file.len() as u64;
+            file[20..28].copy_from_slice(&offset.to_le_bytes());
+            tag.write_to(&mut file, id3::Version::Id3v24).unwrap();
+        }
+        let size = file.len() as u64;
+        file[12..20].copy_from_slice(&size.to_le_bytes());
+        file
+    }
+
+    fn round_trip(file: &[u8], tag: &id3::Tag) -> (Vec<u8>, id3::Tag) {
+        let mut out = Vec::new();
+        rewrite(&mut Cursor::new(file), &mut out, tag, id3::Version::Id3v24).unwrap();
+        let tag = read_from(Cursor::new(&out)).unwrap().unwrap();
+        (out, tag)
+    }
+
+    fn check_header(file: &[u8]) {
+        let header = |i: usize| u64::from_le_bytes(file[i..i + 8].try_into().unwrap());
+        assert_eq!(header(12), file.len() as u64);
+        assert_eq!(header(20), (HEADER_LEN + AUDIO.len()) as u64);
+        assert_eq!(&file[HEADER_LEN..HEADER_LEN + AUDIO.len()], AUDIO);
+    }
+
+    #[test]
+    fn round_trip_existing() {
+        let mut tag = id3::Tag::new();
+        tag.set_title("Title");
+        tag.set_text("TCOM", "Composer");
+        let file = dsf(Some(&tag));
+
+        let mut tag = read_from(Cursor::new(&file)).unwrap().unwrap();
+        tag.set_text("TBPM", "128");
+        let (file, tag) = round_trip(&file, &tag);
+
+        check_header(&file);
+        assert_eq!(
+            tag.get("TBPM").and_then(|f| f.content().text()),
+            Some("128")
+        );
+        assert_eq!(tag.title(), Some("Title"));
+        assert_eq!(
+            tag.get("TCOM").and_then(|f| f.content().text()),
+            Some("Composer")
+        );
+    }
+
+    #[test]
+    fn round_trip_untagged() {
+        let file = dsf(None);
+        assert!(read_from(Cursor::new(&file)).unwrap().is_none());
+
+        let mut tag = id3::Tag::new();
+        tag.set_text("TBPM", "128");
+        let (file, tag) = round_trip(&file, &tag);
+
+        check_header(&file);
+        assert_eq!(
+            tag.get("TBPM").and_then(|f| f.content().text()),
+            Some("128")
+        );
+    }
+}
@@ -0,0 +1,429 @@
+use std::{
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+};
+
+type Kind = [u8; 4];
+
+const FREEFORM: &Kind = b"----";
+/// The namespace iTunes and most taggers put freeform items in.
+const FREEFORM_MEAN: &str = "com.apple.iTunes";
+const TYPE_UTF8: u32 = 1;
+
+struct Header {
+    kind: Kind,
+    header_len: usize,
+    size: usize,
+}
+
+fn parse_header(data: &[u8]) -> Result<Header, anyhow::Error> {
+    if data.len() < 8 {
+        return Err(anyhow::anyhow!("Truncated mp4 atom"));
+    }
+    let kind = data[4..8].try_into()?;
+    let (header_len, size) = match u32::from_be_bytes(data[0..4].try_into()?) {
+        0 => (8, data.len()),
+        1 => {
+            if data.len() < 16 {
+                return Err(anyhow::anyhow!("Truncated mp4 atom"));
+            }
+            (16, u64::from_be_bytes(data[8..16].try_into()?) as usize)
+        }
+        size => (8, size as usize),
+    };
+    if size < header_len || size > data.len() {
+        return Err(anyhow::anyhow!("Invalid mp4 atom size"));
+    }
+
+    Ok(Header {
+        kind,
+        header_len,
+        size,
+    })
+}
+
+/// Splits the payload of a container atom into its children.
+fn children(mut data: &[u8]) -> Result<Vec<(Kind, &[u8])>, anyhow::Error> {
+    let mut children = Vec::new();
+    while !data.is_empty() {
+        let header = parse_header(data)?;
+        children.push((header.kind, &data[header.header_len..header.size]));
+        data = &data[header.size..];
+    }
+    Ok(children)
+}
+
+fn child<'a>(data: &'a [u8], kind: &Kind) -> Result<Option<&'a [u8]>, anyhow::Error> {
+    Ok(children(data)?
+        .into_iter()
+        .find(|(k, _)| k == kind)
+        .map(|(_, payload)| payload))
+}
+
+fn atom(kind: &Kind, payload: &[u8]) -> Vec<u8> {
+    let mut data = ((payload.len() + 8) as u32).to_be_bytes().to_vec();
+    data.extend_from_slice(kind);
+    data.extend_from_slice(payload);
+    data
+}
+
+/// Rebuilds a container payload, replacing (or appending) the child of the given kind with the
+/// payload returned by f.
+fn update_child<F>(data: &[u8], kind: &Kind, f: F) -> Result<Vec<u8>, anyhow::Error>
+where
+    F: FnOnce(Option<&[u8]>) -> Result<Vec<u8>, anyhow::Error>,
+{
+    let mut f = Some(f);
+    let mut out = Vec::new();
+    for (k, payload) in children(data)? {
+        if &k == kind {
+            if let Some(f) = f.take() {
+                out.extend(atom(&k, &f(Some(payload))?));
+                continue;
+            }
+        }
+        out.extend(atom(&k, payload));
+    }
+    if let Some(f) = f {
+        out.extend(atom(kind, &f(None)?));
+    }
+    Ok(out)
+}
+
+/// The meta atom is a full box in iTunes style files, but a plain container in some QuickTime
+/// files.
+fn meta_header_len(meta: &[u8]) -> usize {
+    if meta.get(4..8) == Some(b"hdlr") {
+        0
+    } else {
+        4
+    }
+}
+
+fn default_meta() -> Vec<u8> {
+    let mut hdlr = vec![0; 8];
+    hdlr.extend_from_slice(b"mdirappl");
+    hdlr.extend_from_slice(&[0; 9]);
+
+    let mut meta = vec![0; 4];
+    meta.extend(atom(b"hdlr", &hdlr));
+    meta
+}
+
+/// Reads the complete top level moov atom without reading the (potentially huge) mdat.
+fn read_moov(path: &str) -> Result<Vec<u8>, anyhow::Error> {
+    let mut file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut pos = 0;
+    while pos < len {
+        let mut header = [0; 16];
+        file.seek(SeekFrom::Start(pos))?;
+        file.read_exact(&mut header[..8])?;
+        let size = match u32::from_be_bytes(header[0..4].try_into()?) {
+            0 => len - pos,
+            1 => {
+                file.read_exact(&mut header[8..])?;
+                u64::from_be_bytes(header[8..16].try_into()?)
+            }
+            size => size as u64,
+        };
+        if size < 8 || size > len - pos {
+            return Err(anyhow::anyhow!("Invalid mp4 atom size"));
+        }
+
+        if &header[4..8] == b"moov" {
+            let mut moov = vec![0; size as usize];
+            file.seek(SeekFrom::Start(pos))?;
+            file.read_exact(&mut moov)?;
+            return Ok(moov);
+        }
+        pos += size;
+    }
+
+    Err(anyhow::anyhow!("{}: Missing moov atom", path))
+}
+
+/// Offsets in the chunk offset tables are absolute, so they have to move along with everything
+/// after the moov atom when its size changes.
+fn shift_chunk_offsets(data: &mut [u8], after: u64, delta: i64) -> Result<(), anyhow::Error> {
+    let mut pos = 0;
+    while pos < data.len() {
+        let header = parse_header(&data[pos..])?;
+        let payload = &mut data[pos + header.header_len..pos + header.size];
+        match &header.kind {
+            b"trak" | b"mdia" | b"minf" | b"stbl" => shift_chunk_offsets(payload, after, delta)?,
+            b"stco" | b"co64" => {
+                let width = if &header.kind == b"stco" { 4 } else { 8 };
+                for entry in payload
+                    .get_mut(8..)
+                    .unwrap_or_default()
+                    .chunks_exact_mut(width)
+                {
+                    let offset = if width == 4 {
+                        u32::from_be_bytes(entry.try_into()?) as u64
+                    } else {
+                        u64::from_be_bytes(entry.try_into()?)
+                    };
+                    if offset < after {
+                        continue;
+                    }
+                    let offset = (offset as i64 + delta) as u64;
+                    if width == 4 {
+                        entry.copy_from_slice(&u32::try_from(offset)?.to_be_bytes());
+                    } else {
+                        entry.copy_from_slice(&offset.to_be_bytes());
+                    }
+                }
+            }
+            _ => {}
+        }
+        pos += header.size;
+    }
+    Ok(())
+}
+
+/// The items of an iTunes style ilst atom.  Each item is kept as its raw payload, so items crabtap
+/// doesn't understand survive a rewrite untouched.
+pub struct Items {
+    items: Vec<(Kind, Vec<u8>)>,
+}
+
+impl Items {
+    pub fn read_from_path(path: &str) -> Result<Items, anyhow::Error> {
+        Items::parse(&read_moov(path)?)
+    }
+
+    fn parse(moov: &[u8]) -> Result<Items, anyhow::Error> {
+        let header = parse_header(moov)?;
+        let ilst = match child(&moov[header.header_len..], b"udta")? {
+            Some(udta) => match child(udta, b"meta")? {
+                Some(meta) => child(&meta[meta_header_len(meta)..], b"ilst")?,
+                None => None,
+            },
+            None => None,
+        };
+
+        let items = children(ilst.unwrap_or_default())?
+            .into_iter()
+            .map(|(kind, payload)| (kind, payload.to_vec()))
+            .collect();
+
+        Ok(Items { items })
+    }
+
+    /// Returns the value of the first data atom of the given item.
+    pub fn get(&self, item: &Kind) -> Option<&[u8]> {
+        let (_, payload) = self.items.iter().find(|(kind, _)| kind == item)?;
+        let data = child(payload, b"data").ok()??;
+
+        // Skip the type indicator and locale.
+        data.get(8..)
+    }
+
+    /// Replaces the given item with a single data atom.
+    pub fn set(&mut self, item: &Kind, data_type: u32, value: &[u8]) {
+        let mut data = data_type.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(value);
+
+        self.remove(item);
+        self.items.push((*item, atom(b"data", &data)));
+    }
+
+    pub fn remove(&mut self, item: &Kind) {
+        self.items.retain(|(kind, _)| kind != item);
+    }
+
+    fn freeform_name(payload: &[u8]) -> Option<&[u8]> {
+        // Skip the version and flags.
+        child(payload, b"name").ok()??.get(4..)
+    }
+
+    /// Returns the text of a freeform (----) item, which is identified by name instead of kind.
+    pub fn get_freeform(&self, name: &str) -> Option<&str> {
+        let (_, payload) = self.items.iter().find(|(kind, payload)| {
+            kind == FREEFORM && Items::freeform_name(payload) == Some(name.as_bytes())
+        })?;
+        let data = child(payload, b"data").ok()??;
+        std::str::from_utf8(data.get(8..)?).ok()
+    }
+
+    /// Replaces the freeform item with the given name with a single utf-8 data atom.
+    pub fn set_freeform(&mut self, name: &str, value: &str) {
+        let field = |kind: &Kind, value: &[u8]| {
+            let mut payload = vec![0; 4];
+            payload.extend_from_slice(value);
+            atom(kind, &payload)
+        };
+        let mut data = TYPE_UTF8.to_be_bytes().to_vec();
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(value.as_bytes());
+
+        let mut payload = field(b"mean", FREEFORM_MEAN.as_bytes());
+        payload.extend(field(b"name", name.as_bytes()));
+        payload.extend(atom(b"data", &data));
+
+        self.remove_freeform(name);
+        self.items.push((*FREEFORM, payload));
+    }
+
+    pub fn remove_freeform(&mut self, name: &str) {
+        self.items.retain(|(kind, payload)| {
+            kind != FREEFORM || Items::freeform_name(payload) != Some(name.as_bytes())
+        });
+    }
+
+    /// Replaces the ilst atom, creating the udta/meta/ilst hierarchy if necessary.
+    pub fn write_to_path(&self, path: &str) -> Result<(), anyhow::Error> {
+        let file = fs::read(path)?;
+        let out = self
+            .rewrite(&file)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path, e))?;
+        super::replace_contents(path, &out)?;
+
+        Ok(())
+    }
+
+    /// The whole of an mp4 file with its ilst atom replaced.
+    fn rewrite(&self, file: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+        let mut pos = 0;
+        let (moov_start, moov_end) = loop {
+            if pos >= file.len() {
+                return Err(anyhow::anyhow!("Missing moov atom"));
+            }
+            let header = parse_header(&file[pos..])?;
+            if &header.kind == b"moov" {
+                break (pos, pos + header.size);
+            }
+            pos += header.size;
+        };
+
+        let ilst = self
+            .items
+            .iter()
+            .flat_map(|(kind, payload)| atom(kind, payload))
+            .collect::<Vec<_>>();
+
+        let moov = &file[moov_start..moov_end];
+        let header = parse_header(moov)?;
+        let payload = update_child(&moov[header.header_len..], b"udta", |udta| {
+            update_child(udta.unwrap_or_default(), b"meta", |meta| {
+                let meta = meta.map(<[u8]>::to_vec).unwrap_or_else(default_meta);
+                let (meta_header, meta_children) = meta.split_at(meta_header_len(&meta));
+                let mut out = meta_header.to_vec();
+                out.extend(update_child(meta_children, b"ilst", |_| Ok(ilst))?);
+                Ok(out)
+            })
+        })?;
+
+        let mut new_moov = atom(b"moov", &payload);
+        let delta = new_moov.len() as i64 - moov.len() as i64;
+        if delta != 0 {
+            shift_chunk_offsets(&mut new_moov[8..], moov_end as u64, delta)?;
+        }
+
+        let mut out = file[..moov_start].to_vec();
+        out.extend(new_moov);
+        out.extend_from_slice(&file[moov_end..]);
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUDIO: &[u8] = b"not really aac";
+
+    /// A chunk offset table pointing at the given offset.
+    fn stco(offset: u32) -> Vec<u8> {
+        let mut payload = vec![0; 4];
+        payload.extend_from_slice(&1u32.to_be_bytes());
+        payload.extend_from_slice(&offset.to_be_bytes());
+        let stbl = atom(b"stbl", &atom(b"stco", &payload));
+        atom(b"trak", &atom(b"mdia", &atom(b"minf", &stbl)))
+    }
+
+    /// A moov atom with a title, an item crabtap doesn't know, and a chunk offset.
+    fn moov(offset: u32) -> Vec<u8> {
+        let mut items = Items { items: Vec::new() };
+        items.set(b"\xa9nam", TYPE_UTF8, b"Title");
+        items.set(b"abcd", 0, &[1, 2, 3]);
+        let ilst = items
+            .items
+            .iter()
+            .flat_map(|(kind, payload)| atom(kind, payload))
+            .collect::<Vec<_>>();
+        let mut meta = default_meta();
+        meta.extend(atom(b"ilst", &ilst));
+
+        let mut payload = stco(offset);
+        payload.extend(atom(b"udta", &atom(b"meta", &meta)));
+        atom(b"moov", &payload)
+    }
+
+    /// Where the single chunk offset table points.
+    fn chunk_offset(moov: &[u8]) -> usize {
+        let mut atom = &moov[8..];
+        for kind in [b"trak", b"mdia", b"minf", b"stbl", b"stco"] {
+            atom = child(atom, kind).unwrap().unwrap();
+        }
+        u32::from_be_bytes(atom[8..12].try_into().unwrap()) as usize
+    }
+
+    fn moov_of(file: &[u8]) -> &[u8] {
+        let mut pos = 0;
+        loop {
+            let header = parse_header(&file[pos..]).unwrap();
+            if &header.kind == b"moov" {
+                return &file[pos..pos + header.size];
+            }
+            pos += header.size;
+        }
+    }
+
+    /// Sets a BPM and sections, which grows the moov atom, then checks they read back along with
+    /// what was already there.
+    fn round_trip(file: &[u8]) -> Vec<u8> {
+        let mut items = Items::parse(moov_of(file)).unwrap();
+        items.set(b"tmpo", 21, &128u16.to_be_bytes());
+        items.set_freeform("BPM_SECTIONS", "0:00-1:00 128");
+        let file = items.rewrite(file).unwrap();
+
+        let items = Items::parse(moov_of(&file)).unwrap();
+        assert_eq!(items.get(b"tmpo"), Some(&128u16.to_be_bytes()[..]));
+        assert_eq!(items.get_freeform("BPM_SECTIONS"), Some("0:00-1:00 128"));
+        assert_eq!(items.get(b"\xa9nam"), Some(&b"Title"[..]));
+        assert_eq!(items.get(b"abcd"), Some(&[1, 2, 3][..]));
+        file
+    }
+
+    #[test]
+    fn moov_before_mdat() {
+        let ftyp = atom(b"ftyp", b"M4A \0\0\0\0");
+        // The offset doesn't depend on itself, so it's worked out with a placeholder.
+        let offset = ftyp.len() + moov(0).len() + 8;
+        let mut file = ftyp;
+        file.extend(moov(offset as u32));
+        file.extend(atom(b"mdat", AUDIO));
+
+        // The moov atom grows, so the audio moves along.
+        let file = round_trip(&file);
+        let moved = chunk_offset(moov_of(&file));
+        assert!(moved > offset);
+        assert_eq!(&file[moved..moved + AUDIO.len()], AUDIO);
+    }
+
+    #[test]
+    fn mdat_before_moov() {
+        let ftyp = atom(b"ftyp", b"M4A \0\0\0\0");
+        let offset = ftyp.len() + 8;
+        let mut file = ftyp;
+        file.extend(atom(b"mdat", AUDIO));
+        file.extend(moov(offset as u32));
+
+        let file = round_trip(&file);
+        assert_eq!(chunk_offset(moov_of(&file)), offset);
+        assert_eq!(&file[offset..offset + AUDIO.len()], AUDIO);
+    }
+}
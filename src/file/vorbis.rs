@@ -0,0 +1,248 @@
+use std::{
+    fs::File,
+    io::{BufReader, Read, Seek},
+};
+
+use ogg::{PacketReader, PacketWriteEndInfo, PacketWriter};
+
+const COMMENT_HEADER: &[u8] = b"\x03vorbis";
+
+/// The vorbis comment header of an ogg stream.
+pub struct Comments {
+    vendor: String,
+    comments: Vec<(String, String)>,
+    /// Comments without an `=`, which aren't valid but are kept as they were.
+    malformed: Vec<String>,
+}
+
+impl Comments {
+    fn parse(data: &[u8]) -> Result<Comments, anyhow::Error> {
+        let mut body = data
+            .strip_prefix(COMMENT_HEADER)
+            .ok_or_else(|| anyhow::anyhow!("Missing vorbis comment header"))?;
+
+        let vendor = read_string(&mut body)?;
+        let count = read_u32(&mut body)?;
+        let mut comments = Vec::new();
+        let mut malformed = Vec::new();
+        for _ in 0..count {
+            let comment = read_string(&mut body)?;
+            match comment.split_once('=') {
+                Some((key, value)) => comments.push((key.to_owned(), value.to_owned())),
+                None => malformed.push(comment),
+            }
+        }
+
+        Ok(Comments {
+            vendor,
+            comments,
+            malformed,
+        })
+    }
+
+    fn serialize(&self) -> Vec<u8> {
+        let mut data = COMMENT_HEADER.to_vec();
+        write_string(&mut data, &self.vendor);
+        let count = self.comments.len() + self.malformed.len();
+        data.extend_from_slice(&(count as u32).to_le_bytes());
+        for (key, value) in &self.comments {
+            write_string(&mut data, &format!("{}={}", key, value));
+        }
+        for comment in &self.malformed {
+            write_string(&mut data, comment);
+        }
+        // Framing bit
+        data.push(1);
+        data
+    }
+
+    /// Returns the first value for the given key.  Keys are case insensitive.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.comments
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Replaces all values for the given key with a single value.
+    pub fn set(&mut self, key: &str, value: String) {
+        self.remove(key);
+        self.comments.push((key.to_owned(), value));
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        self.comments.retain(|(k, _)| !k.eq_ignore_ascii_case(key));
+    }
+}
+
+fn read_u32_be(data: &mut &[u8]) -> Option<u32> {
+    let (bytes, rest) = data.split_first_chunk::<4>()?;
+    *data = rest;
+    Some(u32::from_be_bytes(*bytes))
+}
+
+/// Reads the image data out of a flac picture block, which is how cover art is stored in vorbis
+/// comments.  Unlike the rest of a comment header, its numbers are big endian.
+pub fn picture_data(mut block: &[u8]) -> Option<Vec<u8>> {
+    // The picture type, then the mime type and description after their lengths.
+    read_u32_be(&mut block)?;
+    for _ in 0..2 {
+        let len = read_u32_be(&mut block)? as usize;
+        block = block.get(len..)?;
+    }
+    // The width, height, colour depth and palette size.
+    for _ in 0..4 {
+        read_u32_be(&mut block)?;
+    }
+    let len = read_u32_be(&mut block)? as usize;
+    block.get(..len).map(<[u8]>::to_vec)
+}
+
+fn read_u32(data: &mut &[u8]) -> Result<u32, anyhow::Error> {
+    if data.len() < 4 {
+        return Err(anyhow::anyhow!("Truncated vorbis comment header"));
+    }
+    let (bytes, rest) = data.split_at(4);
+    *data = rest;
+    Ok(u32::from_le_bytes(bytes.try_into()?))
+}
+
+fn read_string(data: &mut &[u8]) -> Result<String, anyhow::Error> {
+    let len = read_u32(data)? as usize;
+    if data.len() < len {
+        return Err(anyhow::anyhow!("Truncated vorbis comment header"));
+    }
+    let (bytes, rest) = data.split_at(len);
+    *data = rest;
+    Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn write_string(data: &mut Vec<u8>, s: &str) {
+    data.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    data.extend_from_slice(s.as_bytes());
+}
+
+pub fn read_comments(path: &str) -> Result<Comments, anyhow::Error> {
+    read_from(BufReader::new(File::open(path)?))
+}
+
+fn read_from(file: impl Read + Seek) -> Result<Comments, anyhow::Error> {
+    let mut reader = PacketReader::new(file);
+
+    // The comment header is always the second packet in the stream.
+    reader.read_packet_expected()?;
+    let packet = reader.read_packet_expected()?;
+
+    Comments::parse(&packet.data)
+}
+
+/// Rewrites the ogg file at path with a new comment header, keeping every other packet and its
+/// page layout intact.
+pub fn write_comments(path: &str, comments: &Comments) -> Result<(), anyhow::Error> {
+    let out = rewrite(BufReader::new(File::open(path)?), comments)?;
+    super::replace_contents(path, &out)?;
+
+    Ok(())
+}
+
+fn rewrite(file: impl Read + Seek, comments: &Comments) -> Result<Vec<u8>, anyhow::Error> {
+    let mut reader = PacketReader::new(file);
+    let mut writer = PacketWriter::new(Vec::new());
+
+    let mut index = 0;
+    while let Some(packet) = reader.read_packet()? {
+        let serial = packet.stream_serial();
+        let absgp = packet.absgp_page();
+        let end_info = if packet.last_in_stream() {
+            PacketWriteEndInfo::EndStream
+        } else if packet.last_in_page() {
+            PacketWriteEndInfo::EndPage
+        } else {
+            PacketWriteEndInfo::NormalPacket
+        };
+
+        let data = if index == 1 {
+            comments.serialize()
+        } else {
+            packet.data
+        };
+
+        writer.write_packet(data.into_boxed_slice(), serial, end_info, absgp)?;
+        index += 1;
+    }
+    Ok(writer.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    const SERIAL: u32 = 1;
+
+    /// An ogg stream with the three vorbis headers and a couple of audio packets.
+    fn stream(comments: &Comments) -> Vec<u8> {
+        let mut writer = PacketWriter::new(Vec::new());
+        let packets = [
+            (
+                b"\x01vorbis identification".to_vec(),
+                PacketWriteEndInfo::EndPage,
+                0,
+            ),
+            (comments.serialize(), PacketWriteEndInfo::NormalPacket, 0),
+            (b"\x05vorbis setup".to_vec(), PacketWriteEndInfo::EndPage, 0),
+            (b"first audio".to_vec(), PacketWriteEndInfo::EndPage, 1024),
+            (b"last audio".to_vec(), PacketWriteEndInfo::EndStream, 2048),
+        ];
+        for (data, end_info, absgp) in packets {
+            writer
+                .write_packet(data.into_boxed_slice(), SERIAL, end_info, absgp)
+                .unwrap();
+        }
+        writer.into_inner()
+    }
+
+    fn packets(file: &[u8]) -> Vec<(Vec<u8>, u64)> {
+        let mut reader = PacketReader::new(Cursor::new(file));
+        let mut packets = Vec::new();
+        while let Some(packet) = reader.read_packet().unwrap() {
+            let absgp = packet.absgp_page();
+            packets.push((packet.data, absgp));
+        }
+        packets
+    }
+
+    #[test]
+    fn round_trip() {
+        let comments = Comments {
+            vendor: "crabtap".to_owned(),
+            comments: vec![
+                ("TITLE".to_owned(), "Title".to_owned()),
+                ("UNKNOWN".to_owned(), "Something".to_owned()),
+            ],
+            malformed: vec!["no equals sign".to_owned()],
+        };
+        let file = stream(&comments);
+
+        let mut comments = read_from(Cursor::new(&file)).unwrap();
+        comments.set("BPM", "128".to_owned());
+        let rewritten = rewrite(Cursor::new(&file), &comments).unwrap();
+
+        let comments = read_from(Cursor::new(&rewritten)).unwrap();
+        assert_eq!(comments.vendor, "crabtap");
+        assert_eq!(comments.get("bpm"), Some("128"));
+        assert_eq!(comments.get("Title"), Some("Title"));
+        assert_eq!(comments.get("UNKNOWN"), Some("Something"));
+        assert_eq!(comments.malformed, ["no equals sign"]);
+
+        // Everything but the comment header comes through as it was.
+        let (before, after) = (packets(&file), packets(&rewritten));
+        assert_eq!(before.len(), after.len());
+        for (i, (before, after)) in before.iter().zip(&after).enumerate() {
+            if i != 1 {
+                assert_eq!(before, after);
+            }
+        }
+    }
+}
@@ -1,36 +1,67 @@
-use std::{ffi::OsStr, path::Path};
-
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
+#[allow(dead_code)]
 mod file;
+#[allow(dead_code)]
+mod json;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
     inputs: Vec<String>,
+
+    /// How to print the songs found
+    #[clap(long, value_enum, default_value_t)]
+    format: Output,
+
+    /// List every song, not just the ones without a BPM.  The json output includes each one's
+    /// BPM
+    #[clap(long)]
+    all: bool,
+}
+
+#[derive(Clone, Copy, Default, Debug, ValueEnum)]
+enum Output {
+    /// One path to a line
+    #[default]
+    Paths,
+    /// One json object to a line, with the path and format, and the BPM with --all
+    Json,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Args::parse();
-    let inputs = args
-        .inputs
+    let inputs = file::expand_inputs(args.inputs)
         .into_iter()
         .map(|input| -> Result<Box<dyn file::Music>, anyhow::Error> {
-            let f = match Path::new(&input).extension().and_then(OsStr::to_str) {
-                Some("mp3") => Box::new(file::Mp3::new(input)?) as Box<dyn file::Music>,
-                Some("flac") => Box::new(file::Flac::new(input)?) as Box<dyn file::Music>,
-                _ => return Err(anyhow::anyhow!("Unsupported file type")),
-            };
+            let format = file::Format::detect(&input)?
+                .ok_or_else(|| anyhow::anyhow!("Unsupported file type"))?;
 
-            Ok(f)
+            Ok(Box::new(file::Track::new(
+                input,
+                format,
+                file::WriteOptions::default(),
+            )?))
         })
         .collect::<Result<Vec<_>, _>>()?;
 
     inputs
         .into_iter()
-        .filter(|f| f.bpm().is_none())
-        .for_each(|f| {
-            println!("{}", f.path());
+        .filter(|f| args.all || f.bpm().is_none())
+        .for_each(|f| match args.format {
+            Output::Paths => println!("{}", f.path()),
+            // Without --all, the BPM would always be null.
+            Output::Json if args.all => println!(
+                "{{\"path\":{},\"bpm\":{},\"format\":{}}}",
+                json::string(f.path()),
+                json::number(f.bpm()),
+                json::string(f.format().name())
+            ),
+            Output::Json => println!(
+                "{{\"path\":{},\"format\":{}}}",
+                json::string(f.path()),
+                json::string(f.format().name())
+            ),
         });
 
     Ok(())
@@ -1,54 +1,526 @@
-use clap::Parser;
+use clap::{Parser, Subcommand, ValueEnum};
 use crossterm::{
+    cursor::MoveTo,
     event::{Event, KeyCode, KeyEvent, KeyModifiers},
-    execute,
+    execute, queue,
+    style::Print,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen},
 };
 use ratatui::{
     backend::CrosstermBackend,
-    layout::{Alignment, Constraint, Direction, Layout, Rect},
+    layout::{Alignment, Constraint, Direction, Flex, Layout, Margin, Rect},
     style::{Modifier, Style},
     text::{Line, Span},
-    widgets::{Block, Borders, Clear, Paragraph, Row, Table, TableState},
+    widgets::{
+        Block, Borders, Clear, Gauge, Paragraph, Row, Scrollbar, ScrollbarOrientation,
+        ScrollbarState, Table, TableState, Wrap,
+    },
     CompletedFrame, Frame, Terminal,
 };
-use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink};
+use rodio::{
+    decoder::DecoderError, source::Amplify, Decoder, OutputStream, OutputStreamHandle, Sink, Source,
+};
 use std::{
-    ffi::OsStr,
-    fs::File,
-    io::{self, BufReader},
-    iter,
-    path::Path,
+    cmp::Ordering,
+    collections::{hash_map::RandomState, HashSet, VecDeque},
+    fs::{self, File},
+    hash::BuildHasher,
+    io::{self, BufReader, Write},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+    sync::{
+        atomic::{self, AtomicBool},
+        mpsc, Arc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
 
+mod aiff;
+mod analysis;
+mod click;
+mod config;
+mod cover;
 mod file;
+mod json;
+mod meter;
+mod online;
+mod session;
+mod theme;
+
+use theme::Theme;
 
 /// A tui for generating tap BPMs in rust
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = EXIT_CODES)]
 struct Args {
-    /// Any flac or mp3 file
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Any flac, mp3, ogg, m4a, wav, aiff, ape, mpc, wma, or dsf file, or a directory to look
+    /// for them in.  `-` is the same as --stdin
     inputs: Vec<String>,
 
+    /// Read inputs from standard input, one to a line, as well as any given here
+    #[clap(long)]
+    stdin: bool,
+
     /// Confirm the BPM before saving
     #[clap(short, long)]
     confirm: bool,
 
-    /// The maximum time between taps to consider a new BPM in seconds
-    #[clap(long, default_value = "5")]
-    max_time: u32,
+    /// The maximum time between taps to consider a new BPM in seconds [default: 5]
+    #[clap(long)]
+    max_time: Option<u32>,
+
+    /// The name of the output device to play through [default: the system's default]
+    #[clap(long)]
+    device: Option<String>,
+
+    /// The number of taps needed before a BPM can be saved [default: 2]
+    #[clap(long)]
+    min_taps: Option<usize>,
+
+    /// Save automatically once this many bpms in a row agree
+    #[clap(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    auto_confirm: Option<usize>,
+
+    /// How close, in percent, the bpms have to be to each other to save automatically
+    /// [default: 2]
+    #[clap(long)]
+    auto_tolerance: Option<f64>,
+
+    /// The number of beats each tap counts for, e.g. 2 to tap half notes or 4 to tap whole bars
+    /// [default: 1]
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    beats_per_tap: Option<u32>,
+
+    /// Clear the taps when the gap between two taps is longer than this many beats, or 0 to never
+    /// clear them [default: 4]
+    #[clap(long)]
+    idle_reset: Option<f64>,
+
+    /// The number of bpms to average, or "unbounded" to average every tap since the last reset
+    /// [default: 10]
+    #[clap(long)]
+    num_avg: Option<Window>,
+
+    /// The audio output latency in milliseconds, as measured by calibrating with C [default: 0]
+    #[clap(long, allow_negative_numbers = true)]
+    latency: Option<f64>,
+
+    /// The number of decimal places to write BPMs with [default: 0]
+    #[clap(long)]
+    precision: Option<usize>,
+
+    /// How to combine the tapped bpms [default: mean]
+    #[clap(long, value_enum)]
+    estimator: Option<Estimator>,
+
+    /// How far from the running tempo a bpm can be, in percent, before the trimmed estimator
+    /// discards it [default: 10]
+    #[clap(long)]
+    outlier_threshold: Option<f64>,
+
+    /// The range of BPMs that are plausible.  Saving anything outside it asks first
+    /// [default: 40-250]
+    #[clap(long)]
+    bpm_range: Option<BpmRange>,
+
+    /// How to round the tapped BPM [default: fractional]
+    #[clap(long, value_enum)]
+    rounding: Option<Rounding>,
+
+    /// The id3 version to write mp3, wav, aiff, and dsf tags with [default: 2.4]
+    #[clap(long, value_enum)]
+    id3_version: Option<file::Id3Version>,
+
+    /// How keys are written to tags [default: standard]
+    #[clap(long, value_enum)]
+    key_notation: Option<file::KeyNotation>,
+
+    /// The colours of the tui [default: default]
+    #[clap(long, value_enum)]
+    theme: Option<Theme>,
+
+    /// How much of the screen the song list takes up, as a percentage [default: the rest]
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..=100))]
+    table_height: Option<u16>,
+
+    /// Leave out the song list and show the playing song with a large BPM, for small terminals
+    /// and tmux splits
+    #[clap(long)]
+    compact: bool,
+
+    /// How to draw cover art [default: auto]
+    #[clap(long, value_enum)]
+    graphics: Option<cover::Graphics>,
+
+    /// Panels to leave out, like waveform,beat-grid
+    #[clap(long, value_enum, value_delimiter = ',')]
+    hide: Option<Vec<Panel>>,
+
+    /// Write the paths of songs flagged with F to this file on quitting, instead of printing them
+    #[clap(long)]
+    flagged: Option<String>,
+
+    /// Start playing each song this far in, as seconds or a percentage like 50%, to skip intros
+    /// [default: 0]
+    #[clap(long)]
+    start_at: Option<StartAt>,
+
+    /// Only play this many seconds of each song, from where it starts, over and over
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    preview: Option<u32>,
+
+    /// The playback volume as a percentage [default: 100]
+    #[clap(long, value_parser = clap::value_parser!(u32).range(0..=MAX_VOLUME as i64))]
+    volume: Option<u32>,
+
+    /// Estimate the BPM and key of each song in the background as it starts playing
+    #[clap(long)]
+    estimate: bool,
+
+    /// Recognize each song by its audio as it starts playing, with this AcoustID application
+    /// key, to check the artist and title of what's being tapped.  Needs crabtap built with the
+    /// online feature
+    #[clap(long)]
+    acoustid_key: Option<String>,
+
+    /// Fetch each song's BPM by its artist and title as it starts playing, from this url with
+    /// `{artist}` and `{title}` where they go, to offer in place of the tapped one with O.
+    /// Needs crabtap built with the online feature
+    #[clap(long)]
+    bpm_api: Option<String>,
+
+    /// Go through tapping and saving as usual without changing any files, printing what would
+    /// have been saved on quitting
+    #[clap(long)]
+    dry_run: bool,
 
-    /// The number of bpms to average
-    #[clap(long, default_value = "10")]
-    num_avg: usize,
+    /// Copy each file to the same name with .bak on the end before first changing it
+    #[clap(long)]
+    backup: bool,
+
+    /// Copy each file into this directory instead, before first changing it
+    #[clap(long)]
+    backup_dir: Option<PathBuf>,
+
+    /// Leave out songs that already have a BPM, to pick up where a big list was left off
+    #[clap(long)]
+    skip_tagged: bool,
+
+    /// Play the songs in a random order, rather than a whole album at the same tempo in a row
+    #[clap(long)]
+    shuffle: bool,
+
+    /// Pick up the song list where the last session was quit, followed by any songs given here
+    #[clap(long)]
+    resume: bool,
+
+    /// How to sort the song list to begin with [default: given]
+    #[clap(long, value_enum)]
+    sort: Option<SortBy>,
+
+    /// Show a spectrogram of each song, to pick out kick drums that are hard to hear
+    #[clap(long)]
+    spectrogram: bool,
+
+    /// Play each song at its ReplayGain or R128 track gain, so songs are about as loud as each other
+    #[clap(long)]
+    replay_gain: bool,
+
+    /// Also write the BPM to an APEv2 tag on mp3 files
+    #[clap(long)]
+    mirror_ape: bool,
+
+    /// Also write the BPM to this field, which is a TXXX frame on id3 tags unless it's the id of
+    /// a text frame, and a free form field everywhere else
+    #[clap(long)]
+    bpm_tag: Option<String>,
+
+    /// Write the BPM to the --bpm-tag field instead of the standard one
+    #[clap(long)]
+    bpm_tag_only: bool,
+
+    /// Also note the BPM at the start of the comment, like "BPM: 128", for players that show
+    /// nothing else
+    #[clap(long)]
+    write_comment: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Estimate and save the BPM of songs from their audio, without tapping or playing anything
+    Analyze {
+        /// Any flac, mp3, ogg, wav, or aiff file, or a directory to look for them in.  `-` reads
+        /// more from standard input, one to a line
+        inputs: Vec<String>,
+
+        /// Print the estimates without saving them
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Print what crabtap reads from songs' tags, without the tui
+    Show {
+        /// Any supported file, or a directory to look for them in.  `-` reads more from standard
+        /// input, one to a line
+        inputs: Vec<String>,
+
+        /// How to print them
+        #[clap(long, value_enum, default_value_t)]
+        format: Listing,
+    },
+    /// Save a BPM to songs without the tui, for scripts
+    Set {
+        bpm: f64,
+
+        /// Any supported file, or a directory to look for them in.  `-` reads more from standard
+        /// input, one to a line
+        inputs: Vec<String>,
+    },
+}
+
+#[derive(Clone, Copy, Default, Debug, ValueEnum)]
+enum Listing {
+    /// Lined up in columns
+    #[default]
+    Table,
+    /// One json object to a line
+    Json,
 }
 
 enum State {
     Playing,
-    Finished { bpm: u32 },
-    Manual { manual_bpm: u32 },
+    Notice {
+        message: String,
+    },
+    /// Leaving a song without saving the BPM tapped for it.
+    Unsaved {
+        leaving: Leaving,
+    },
+    /// The selected song's stream properties, or why they couldn't be read.
+    Info {
+        lines: Vec<String>,
+    },
+    Finished {
+        bpm: Option<f64>,
+        /// The estimate the BPM came from, if it came from the audio.
+        estimate: Option<analysis::Tempo>,
+    },
+    Manual {
+        manual_bpm: String,
+    },
+    /// Editing the selected song's tags.
+    Edit {
+        /// What each of file::MetadataField::ALL is being changed to.
+        values: Vec<String>,
+        selected: usize,
+    },
+    Jump {
+        time: String,
+    },
+    Calibrating {
+        started: Instant,
+        /// How late each tap was compared to its click, in milliseconds.
+        offsets: Vec<f64>,
+    },
+    Disconnected {
+        /// Why the last attempt to reconnect failed, if there was one.
+        error: Option<String>,
+    },
+    Help,
+    Search {
+        query: String,
+        /// Which of the matches is highlighted.
+        selected: usize,
+    },
+    History {
+        /// Which entry is highlighted, counting back from the latest.
+        selected: usize,
+    },
+    Browse {
+        dir: PathBuf,
+        entries: Vec<PathBuf>,
+        selected: usize,
+        /// How many songs have been added since the browser was opened.
+        added: usize,
+    },
+}
+
+/// Where to go once the unsaved BPM has been dealt with.
+enum Leaving {
+    Quit,
+    /// The song's order, so it stays right if the list is sorted in the meantime.
+    Song(usize),
+}
+
+/// How well a query matches some text, if it does, from its characters turning up in order.
+/// Lower is better: it's how far apart the matched characters are spread.
+fn fuzzy_score(query: &str, text: &str) -> Option<usize> {
+    let text = text.to_lowercase().chars().collect::<Vec<_>>();
+    let mut score = 0;
+    let mut next = 0;
+    for c in query.to_lowercase().chars().filter(|c| !c.is_whitespace()) {
+        let found = text[next..].iter().position(|t| *t == c)?;
+        // The first character can be anywhere without counting against the match.
+        if next > 0 {
+            score += found;
+        }
+        next += found + 1;
+    }
+    Some(score)
+}
+
+/// The indices of the songs matching a query, best match first.
+fn search(inputs: &[Input], query: &str) -> Vec<usize> {
+    let mut matches = inputs
+        .iter()
+        .enumerate()
+        .filter_map(|(i, input)| {
+            let score = [input.name(), input.track.path().to_owned()]
+                .iter()
+                .filter_map(|text| fuzzy_score(query, text))
+                .min()?;
+            Some((score, i))
+        })
+        .collect::<Vec<_>>();
+    matches.sort();
+    matches.into_iter().map(|(_, i)| i).collect()
 }
 
+/// Every key binding, grouped by when it applies.
+const HELP: [(&str, &[(&str, &str)]); 9] = [
+    (
+        "Playing",
+        &[
+            ("Space", "Tap along with the beat"),
+            ("Enter", "Save the BPM and move on to the next song"),
+            ("U", "Undo the last save and go back to that song"),
+            ("L", "Show every BPM saved this session"),
+            ("Shift+A", "Add more songs or folders"),
+            (
+                "Shift+I",
+                "Show the song's format, sample rate, channels and bitrate",
+            ),
+            ("Up/K Down/J", "Change songs"),
+            ("PgUp PgDn", "Skip a page of songs"),
+            ("Ctrl+U Ctrl+D", "Skip half a page of songs"),
+            ("Home/G G End/Shift+G", "Go to the first or last song"),
+            ("N", "Skip to the next song without a BPM"),
+            (
+                "1-9",
+                "Type a count before moving, like 5J, or 12 Shift+G for the 12th song",
+            ),
+            ("R", "Restart the song"),
+            ("P", "Pause or resume"),
+            ("G 0-9", "Jump to a time in the song, like G 1:30"),
+            ("/", "Search for a song by name"),
+            (
+                "Shift+S",
+                "Sort the songs by name, age, BPM, or whether they have one",
+            ),
+            ("I O", "Mark the start and end of a loop, O again to stop"),
+            ("+ -", "Turn the volume up or down"),
+            ("X", "Mute or unmute"),
+            ("T", "Play a click at the tapped BPM"),
+            ("F", "Flag the song to come back to later"),
+            ("V", "Mark the song to save the same BPM to"),
+            (
+                "Shift+V",
+                "Mark every song from the last one marked to here",
+            ),
+            (", .", "Slow down or speed up playback"),
+            ("M", "Type in a BPM"),
+            ("E", "Edit the artist, title, album and genre"),
+            ("H/[ D/]", "Halve or double the tapped BPM"),
+            ("S", "End a tempo section here"),
+            ("A", "Estimate the BPM and key from the audio"),
+            ("C", "Calibrate for output latency"),
+            ("B", "Switch how many beats each tap counts for"),
+            ("?", "Show this help"),
+            ("Esc/Q", "Quit"),
+        ],
+    ),
+    ("Saving", &[("Y N", "Save the BPM or go back to tapping")]),
+    (
+        "Searching",
+        &[
+            ("Up Down", "Pick a match"),
+            ("Enter", "Play it"),
+            ("Esc", "Go back to tapping"),
+        ],
+    ),
+    (
+        "Session history",
+        &[
+            ("Up/K Down/J", "Pick a save"),
+            ("Enter", "Go back to its song to tap it again"),
+            ("Esc/L", "Go back to tapping"),
+        ],
+    ),
+    (
+        "Adding songs",
+        &[
+            ("Up/K Down/J", "Pick a file or folder"),
+            ("Enter/L", "Open a folder, or add a song"),
+            ("Backspace/H", "Go up a folder"),
+            ("Space/A", "Add the song, or every song in the folder"),
+            ("Esc/Q", "Go back to tapping"),
+        ],
+    ),
+    (
+        "Editing tags",
+        &[
+            ("Up Down/Tab", "Pick a field"),
+            ("Enter", "Save the changes"),
+            ("Esc", "Go back to tapping without saving"),
+        ],
+    ),
+    (
+        "Typing a BPM or time",
+        &[
+            ("Enter", "Use it"),
+            ("Backspace", "Delete the last character"),
+            ("Esc", "Go back to tapping"),
+        ],
+    ),
+    (
+        "Calibrating",
+        &[
+            ("Space", "Tap along with the clicks"),
+            ("Enter", "Use the measured latency"),
+            ("Esc/C", "Cancel"),
+        ],
+    ),
+    (
+        "Audio output lost",
+        &[
+            ("R/Enter", "Reconnect to the output device"),
+            ("Esc/Q", "Quit"),
+        ],
+    ),
+];
+
+/// Parses a time like `90`, `1:30`, or `1:01:30`.
+fn parse_time(time: &str) -> Option<Duration> {
+    let mut parts = time.split(':');
+    let first = parts.next()?.parse::<u64>().ok()?;
+    let seconds = parts.try_fold(first, |total, part| {
+        // Everything after the first part is minutes or seconds.
+        let part = part.parse::<u64>().ok().filter(|part| *part < 60)?;
+        Some(total * 60 + part)
+    })?;
+    Some(Duration::from_secs(seconds))
+}
+
+/// The tempo of the calibration click.
+const CLICK_BPM: f64 = 120.0;
+/// The number of taps needed before a calibration can be used.
+const CALIBRATION_TAPS: usize = 4;
+
 enum PlayCommands {
     Quit,
     Confirm,
@@ -56,11 +528,57 @@ enum PlayCommands {
     Tap,
     Up,
     Down,
+    PageUp,
+    PageDown,
+    HalfPageUp,
+    HalfPageDown,
+    Top,
+    Bottom,
+    NextUntagged,
+    /// A digit of a count for the next command.
+    Count(usize),
     Manual,
+    Half,
+    Double,
+    BeatsPerTap,
+    Calibrate,
+    Section,
+    Analyze,
+    Pause,
+    Jump,
+    VolumeUp,
+    VolumeDown,
+    Faster,
+    Slower,
+    LoopIn,
+    LoopOut,
+    Mute,
+    ClickTrack,
+    Help,
+    Search,
+    Sort,
+    Flag,
+    Mark,
+    MarkRange,
+    Undo,
+    History,
+    Browse,
+    Info,
+    Edit,
 }
 
 fn play_keys(key: KeyEvent) -> Option<PlayCommands> {
-    if key.modifiers != KeyModifiers::empty() {
+    if key.modifiers == KeyModifiers::CONTROL {
+        return match key.code {
+            KeyCode::Char('u') => Some(PlayCommands::HalfPageUp),
+            KeyCode::Char('d') => Some(PlayCommands::HalfPageDown),
+            _ => None,
+        };
+    }
+
+    // Some keys, like +, need shift to type.
+    let shifted_char = key.modifiers == KeyModifiers::SHIFT && matches!(key.code, KeyCode::Char(_));
+    if key.modifiers != KeyModifiers::empty() && !shifted_char {
         return None;
     }
 
@@ -71,7 +589,43 @@ fn play_keys(key: KeyEvent) -> Option<PlayCommands> {
         KeyCode::Enter => Some(PlayCommands::Confirm),
         KeyCode::Up | KeyCode::Char('k') => Some(PlayCommands::Up),
         KeyCode::Down | KeyCode::Char('j') => Some(PlayCommands::Down),
+        KeyCode::PageUp => Some(PlayCommands::PageUp),
+        KeyCode::PageDown => Some(PlayCommands::PageDown),
+        KeyCode::Home => Some(PlayCommands::Top),
+        KeyCode::End | KeyCode::Char('G') => Some(PlayCommands::Bottom),
+        KeyCode::Char('n') => Some(PlayCommands::NextUntagged),
+        KeyCode::Char(c @ '0'..='9') => Some(PlayCommands::Count(c as usize - '0' as usize)),
         KeyCode::Char('m') => Some(PlayCommands::Manual),
+        KeyCode::Char('h') | KeyCode::Char('[') => Some(PlayCommands::Half),
+        KeyCode::Char('d') | KeyCode::Char(']') => Some(PlayCommands::Double),
+        KeyCode::Char('b') => Some(PlayCommands::BeatsPerTap),
+        KeyCode::Char('c') => Some(PlayCommands::Calibrate),
+        KeyCode::Char('s') => Some(PlayCommands::Section),
+        KeyCode::Char('a') => Some(PlayCommands::Analyze),
+        KeyCode::Char('p') => Some(PlayCommands::Pause),
+        KeyCode::Char('g') => Some(PlayCommands::Jump),
+        KeyCode::Char('+') | KeyCode::Char('=') => Some(PlayCommands::VolumeUp),
+        KeyCode::Char('-') => Some(PlayCommands::VolumeDown),
+        KeyCode::Char('.') | KeyCode::Char('>') => Some(PlayCommands::Faster),
+        KeyCode::Char(',') | KeyCode::Char('<') => Some(PlayCommands::Slower),
+        KeyCode::Char('i') => Some(PlayCommands::LoopIn),
+        KeyCode::Char('o') => Some(PlayCommands::LoopOut),
+        KeyCode::Char('x') => Some(PlayCommands::Mute),
+        KeyCode::Char('t') => Some(PlayCommands::ClickTrack),
+        KeyCode::Char('?') => Some(PlayCommands::Help),
+        KeyCode::Char('/') => Some(PlayCommands::Search),
+        // Lowercase s ends a section.
+        KeyCode::Char('S') => Some(PlayCommands::Sort),
+        KeyCode::Char('f') => Some(PlayCommands::Flag),
+        KeyCode::Char('v') => Some(PlayCommands::Mark),
+        KeyCode::Char('V') => Some(PlayCommands::MarkRange),
+        KeyCode::Char('u') => Some(PlayCommands::Undo),
+        KeyCode::Char('l') => Some(PlayCommands::History),
+        // Lowercase a estimates the BPM.
+        KeyCode::Char('A') => Some(PlayCommands::Browse),
+        // Lowercase i marks the start of a loop.
+        KeyCode::Char('I') => Some(PlayCommands::Info),
+        KeyCode::Char('e') => Some(PlayCommands::Edit),
         _ => None,
     }
 }
@@ -84,6 +638,7 @@ fn confirm_keys(key: KeyEvent) -> Option<ConfirmCommands> {
     match key.code {
         KeyCode::Char('y') => Some(ConfirmCommands::Yes),
         KeyCode::Char('n') => Some(ConfirmCommands::No),
+        KeyCode::Char('o') => Some(ConfirmCommands::Online),
         _ => None,
     }
 }
@@ -91,6 +646,127 @@ fn confirm_keys(key: KeyEvent) -> Option<ConfirmCommands> {
 enum ConfirmCommands {
     Yes,
     No,
+    /// Use the BPM fetched from the database instead.
+    Online,
+}
+
+fn unsaved_keys(key: KeyEvent) -> Option<UnsavedCommands> {
+    if key.modifiers != KeyModifiers::empty() {
+        return None;
+    }
+
+    match key.code {
+        KeyCode::Char('y') => Some(UnsavedCommands::Save),
+        KeyCode::Char('n') => Some(UnsavedCommands::Discard),
+        KeyCode::Esc => Some(UnsavedCommands::Cancel),
+        _ => None,
+    }
+}
+
+enum UnsavedCommands {
+    Save,
+    Discard,
+    Cancel,
+}
+
+fn calibrate_keys(key: KeyEvent) -> Option<CalibrateCommands> {
+    if key.modifiers != KeyModifiers::empty() {
+        return None;
+    }
+
+    match key.code {
+        KeyCode::Char(' ') => Some(CalibrateCommands::Tap),
+        KeyCode::Enter => Some(CalibrateCommands::Accept),
+        KeyCode::Esc | KeyCode::Char('c') => Some(CalibrateCommands::Cancel),
+        _ => None,
+    }
+}
+
+enum CalibrateCommands {
+    Tap,
+    Accept,
+    Cancel,
+}
+
+fn reconnect_keys(key: KeyEvent) -> Option<ReconnectCommands> {
+    if key.modifiers != KeyModifiers::empty() {
+        return None;
+    }
+
+    match key.code {
+        KeyCode::Char('r') | KeyCode::Enter => Some(ReconnectCommands::Reconnect),
+        KeyCode::Esc | KeyCode::Char('q') => Some(ReconnectCommands::Quit),
+        _ => None,
+    }
+}
+
+enum ReconnectCommands {
+    Reconnect,
+    Quit,
+}
+
+fn history_keys(key: KeyEvent) -> Option<HistoryCommands> {
+    if key.modifiers != KeyModifiers::empty() {
+        return None;
+    }
+
+    match key.code {
+        KeyCode::Up | KeyCode::Char('k') => Some(HistoryCommands::Up),
+        KeyCode::Down | KeyCode::Char('j') => Some(HistoryCommands::Down),
+        KeyCode::Enter => Some(HistoryCommands::Open),
+        KeyCode::Esc | KeyCode::Char('l') | KeyCode::Char('q') => Some(HistoryCommands::Close),
+        _ => None,
+    }
+}
+
+enum HistoryCommands {
+    Up,
+    Down,
+    Open,
+    Close,
+}
+
+fn browse_keys(key: KeyEvent) -> Option<BrowseCommands> {
+    if key.modifiers != KeyModifiers::empty() {
+        return None;
+    }
+
+    match key.code {
+        KeyCode::Up | KeyCode::Char('k') => Some(BrowseCommands::Up),
+        KeyCode::Down | KeyCode::Char('j') => Some(BrowseCommands::Down),
+        KeyCode::Enter | KeyCode::Right | KeyCode::Char('l') => Some(BrowseCommands::Open),
+        KeyCode::Backspace | KeyCode::Left | KeyCode::Char('h') => Some(BrowseCommands::Parent),
+        KeyCode::Char(' ') | KeyCode::Char('a') => Some(BrowseCommands::Add),
+        KeyCode::Esc | KeyCode::Char('q') => Some(BrowseCommands::Close),
+        _ => None,
+    }
+}
+
+enum BrowseCommands {
+    Up,
+    Down,
+    Open,
+    Parent,
+    Add,
+    Close,
+}
+
+/// Moves a tap back by the output latency, to when the beat it was aimed at was heard.
+fn compensate(tap: Instant, latency: f64) -> Instant {
+    let offset = Duration::from_secs_f64(latency.abs() / 1000.0);
+    if latency >= 0.0 {
+        tap.checked_sub(offset).unwrap_or(tap)
+    } else {
+        tap + offset
+    }
+}
+
+/// The average of the calibration offsets, once there are enough of them.
+fn measured_latency(offsets: &[f64]) -> Option<f64> {
+    if offsets.len() < CALIBRATION_TAPS {
+        return None;
+    }
+    Some(offsets.iter().sum::<f64>() / offsets.len() as f64)
 }
 
 struct RAIITerminal {
@@ -108,12 +784,24 @@ impl RAIITerminal {
         })
     }
 
-    fn draw<F>(&mut self, f: F) -> io::Result<CompletedFrame>
+    fn draw<F>(&mut self, f: F) -> io::Result<CompletedFrame<'_>>
     where
         F: FnOnce(&mut Frame),
     {
         self.terminal.draw(f)
     }
+
+    /// Writes an escape sequence at a spot on the screen, for images drawn over the tui.
+    fn overlay(&mut self, x: u16, y: u16, escape: &str) -> io::Result<()> {
+        let backend = self.terminal.backend_mut();
+        queue!(backend, MoveTo(x, y), Print(escape))?;
+        backend.flush()
+    }
+
+    /// Redraws everything on the next draw, over anything overlaid.
+    fn clear(&mut self) -> io::Result<()> {
+        self.terminal.clear()
+    }
 }
 
 impl Drop for RAIITerminal {
@@ -144,265 +832,3746 @@ fn centered_rect(percent_x: u16, percent_y: u16, r: Rect) -> Rect {
     .split(popup_layout[1])[1]
 }
 
-fn on_keypress<Command, F: Fn(KeyEvent) -> Option<Command>>(
+/// How often the screen is redrawn while waiting for a key, to keep the progress bar and meters
+/// moving.
+const TICK: Duration = Duration::from_millis(250);
+/// How often the screen is redrawn while there's a beat grid or a tap to flash.
+const FAST_TICK: Duration = Duration::from_millis(25);
+
+/// Waits for a key that maps to a command, giving up after the timeout so the caller can redraw.
+fn poll_keypress<Command, F: Fn(KeyEvent) -> Option<Command>>(
     keys: F,
-) -> Result<Command, anyhow::Error> {
+    timeout: Duration,
+) -> Result<Option<Command>, anyhow::Error> {
+    let deadline = Instant::now() + timeout;
     loop {
-        let key = crossterm::event::read()?;
-        if let Event::Key(key) = key {
+        if !crossterm::event::poll(deadline.saturating_duration_since(Instant::now()))? {
+            return Ok(None);
+        }
+        if let Event::Key(key) = crossterm::event::read()? {
             if let Some(command) = keys(key) {
-                return Ok(command);
+                return Ok(Some(command));
             }
         }
     }
 }
 
-struct Bpms {
-    bpms: Box<[f64]>,
-    next: usize,
-    size: usize,
+/// How the tapped BPM is rounded before it is shown and saved.
+#[derive(Clone, Copy, Default, Debug, ValueEnum)]
+enum Rounding {
+    /// Round to the nearest whole BPM
+    Nearest,
+    /// Round down to a whole BPM
+    Floor,
+    /// Round up to a whole BPM
+    Ceil,
+    /// Round to the nearest 0.5
+    Half,
+    /// Keep the fractional part, up to the write precision
+    #[default]
+    Fractional,
 }
 
-impl Bpms {
-    fn new(num_bpms: usize) -> Bpms {
-        Bpms {
-            bpms: iter::repeat(0.0).take(num_bpms).collect(),
-            next: 0,
-            size: 0,
+impl Rounding {
+    fn apply(self, bpm: f64) -> f64 {
+        match self {
+            Rounding::Nearest => bpm.round(),
+            Rounding::Floor => bpm.floor(),
+            Rounding::Ceil => bpm.ceil(),
+            Rounding::Half => (bpm * 2.0).round() / 2.0,
+            Rounding::Fractional => bpm,
         }
     }
+}
 
-    fn push(&mut self, bpm: f64) {
-        self.bpms[self.next] = bpm;
-        self.next = (self.next + 1) % self.bpms.len();
-        if self.size < self.bpms.len() {
-            self.size += 1;
+/// How many of the most recent bpms are averaged together.
+#[derive(Clone, Copy, Debug)]
+enum Window {
+    Taps(usize),
+    Unbounded,
+}
+
+impl FromStr for Window {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Window, anyhow::Error> {
+        if s == "unbounded" {
+            return Ok(Window::Unbounded);
+        }
+        match s.parse() {
+            Ok(0) | Err(_) => Err(anyhow::anyhow!(
+                "Expected a positive number or \"unbounded\""
+            )),
+            Ok(taps) => Ok(Window::Taps(taps)),
         }
     }
+}
 
-    fn avg(&self) -> Option<u32> {
-        if self.size == 0 {
-            None
-        } else {
-            Some((self.bpms.iter().take(self.size).sum::<f64>() / self.size as f64) as u32)
+/// Where in each song playback starts.
+#[derive(Clone, Copy, Debug)]
+enum StartAt {
+    Seconds(f64),
+    Percent(f64),
+}
+
+impl StartAt {
+    fn position(self, path: &str) -> Duration {
+        match self {
+            StartAt::Seconds(seconds) => Duration::from_secs_f64(seconds),
+            // Songs that can't be decoded play silence anyway, so they can start anywhere.
+            StartAt::Percent(percent) => analysis::duration(path)
+                .map(|duration| duration.mul_f64(percent / 100.0))
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl FromStr for StartAt {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<StartAt, anyhow::Error> {
+        let (number, is_percent) = match s.strip_suffix('%') {
+            Some(number) => (number, true),
+            None => (s, false),
+        };
+        match number.parse::<f64>() {
+            Ok(percent) if is_percent && (0.0..100.0).contains(&percent) => {
+                Ok(StartAt::Percent(percent))
+            }
+            Ok(seconds) if !is_percent && seconds >= 0.0 && seconds.is_finite() => {
+                Ok(StartAt::Seconds(seconds))
+            }
+            _ => Err(anyhow::anyhow!(
+                "Expected a number of seconds, or a percentage under 100%"
+            )),
         }
     }
 }
 
-struct AudioStream<'a> {
-    handle: &'a OutputStreamHandle,
+#[derive(Clone, Copy, Debug)]
+struct BpmRange {
+    min: f64,
+    max: f64,
 }
 
-impl<'a> AudioStream<'a> {
-    fn new(handle: &'a OutputStreamHandle) -> AudioStream<'a> {
-        AudioStream { handle }
+impl BpmRange {
+    fn contains(&self, bpm: f64) -> bool {
+        (self.min..=self.max).contains(&bpm)
     }
+}
 
-    fn play(&'a self, input: &str) -> Result<Sink, anyhow::Error> {
-        let sink = Sink::try_new(self.handle)?;
-        let source = Decoder::new_looped(BufReader::new(File::open(input)?))?;
-        sink.append(source);
-        sink.play();
-        Ok(sink)
+impl FromStr for BpmRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<BpmRange, anyhow::Error> {
+        let range = s.split_once('-').and_then(|(min, max)| {
+            Some(BpmRange {
+                min: min.trim().parse().ok()?,
+                max: max.trim().parse().ok()?,
+            })
+        });
+        match range {
+            Some(range) if range.min <= range.max => Ok(range),
+            _ => Err(anyhow::anyhow!("Expected a range like 40-250")),
+        }
     }
 }
 
-fn draw_ui(
-    f: &mut Frame,
-    inputs: &[Box<dyn file::Music>],
-    table_state: &mut TableState,
-    bpm: Option<u32>,
-) {
-    let chunks = Layout::default()
-        .direction(Direction::Vertical)
-        .margin(1)
-        .constraints([Constraint::Percentage(90), Constraint::Percentage(10)].as_ref())
-        .split(f.size());
+impl std::fmt::Display for BpmRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}-{}", self.min, self.max)
+    }
+}
 
-    let input_table = inputs
-        .into_iter()
-        .map(|input| {
-            let bpm_str = match input.bpm() {
-                Some(bpm) => format!("{}", bpm),
-                None => "None".to_owned(),
-            };
+/// How the bpms in the window are combined into one.
+#[derive(Clone, Copy, Default, Debug, ValueEnum)]
+enum Estimator {
+    /// Average every bpm
+    #[default]
+    Mean,
+    /// Take the median bpm
+    Median,
+    /// Average the bpms close to the median, discarding mistimed taps
+    Trimmed,
+}
 
-            Row::new(vec![input.path().to_owned(), bpm_str])
-        })
-        .collect::<Table>()
-        .widths(&[Constraint::Percentage(90), Constraint::Percentage(10)])
-        .block(Block::default().borders(Borders::ALL))
-        .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+fn median(bpms: &mut [f64]) -> f64 {
+    bpms.sort_by(f64::total_cmp);
+    let mid = bpms.len() / 2;
+    if bpms.len().is_multiple_of(2) {
+        (bpms[mid - 1] + bpms[mid]) / 2.0
+    } else {
+        bpms[mid]
+    }
+}
 
-    f.render_stateful_widget(input_table, chunks[0], table_state);
+/// How settled the tapped tempo is.
+#[derive(Clone, Copy, Debug)]
+enum Confidence {
+    Low,
+    Medium,
+    High,
+}
 
-    let bpm_part = Paragraph::new(vec![Line::from(match bpm {
-        Some(bpm) => format!("BPM: {}", bpm),
-        None => String::new(),
-    })])
-    .block(
-        Block::default()
-            .borders(Borders::ALL)
-            .title("Tap Space for BPM!")
-            .title_alignment(Alignment::Center),
-    );
+/// How much the tempo can change across the window, as a fraction, before it's called drifting.
+const DRIFT_THRESHOLD: f64 = 0.04;
+/// The number of bpms needed to tell drift apart from a few bad taps.
+const DRIFT_TAPS: usize = 6;
 
-    f.render_widget(bpm_part, chunks[1]);
+struct Stats {
+    taps: usize,
+    /// The standard deviation of the tap intervals in milliseconds.
+    std_dev: f64,
+    min: f64,
+    max: f64,
+    confidence: Confidence,
+    /// How much the tempo changed across the window, as a fraction, if it is drifting.
+    drift: Option<f64>,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let (_stream, stream_handle) = OutputStream::try_default()?;
-    let audio_stream = AudioStream::new(&stream_handle);
+/// Settings that control how taps are turned into a BPM.
+struct TapOptions {
+    window: Window,
+    estimator: Estimator,
+    /// The fraction of the running tempo a bpm can be off by before the trimmed estimator
+    /// discards it.
+    threshold: f64,
+    /// Can be changed while tapping, and is kept between songs.
+    beats_per_tap: u32,
+    min_taps: usize,
+    /// The number of consecutive bpms that have to agree to save automatically.
+    auto_confirm: Option<usize>,
+    /// The fraction of their mean the bpms can differ by and still agree.
+    auto_tolerance: f64,
+    /// The audio output latency in milliseconds.
+    latency: f64,
+}
 
-    let args = Args::parse();
-    let mut inputs = args
-        .inputs
-        .into_iter()
-        .map(|input| -> Result<Box<dyn file::Music>, anyhow::Error> {
-            let f = match Path::new(&input).extension().and_then(OsStr::to_str) {
-                Some("mp3") => Box::new(file::Mp3::new(input)?) as Box<dyn file::Music>,
-                Some("flac") => Box::new(file::Flac::new(input)?) as Box<dyn file::Music>,
-                _ => return Err(anyhow::anyhow!("{}: Unsupported file type", input)),
-            };
+struct Bpms {
+    /// The number of bpms pushed since the last reset, including ones outside the window.
+    taps: usize,
+    bpms: VecDeque<f64>,
+    /// Applied to the average, for tapping at half or double time.
+    scale: f64,
+    options: TapOptions,
+}
 
-            Ok(f)
-        })
-        .collect::<Result<Vec<_>, _>>()?;
+impl Bpms {
+    fn new(options: TapOptions) -> Bpms {
+        Bpms {
+            taps: 0,
+            bpms: VecDeque::new(),
+            scale: 1.0,
+            options,
+        }
+    }
 
-    if inputs.len() == 0 {
-        return Ok(());
+    fn reset(&mut self) {
+        self.clear();
+        self.scale = 1.0;
     }
-    let mut table_state = TableState::default();
-    table_state.select(Some(0));
-    let mut _player = audio_stream.play(&inputs[0].path())?;
-    let mut last_press_at = None;
-    let mut bpms = Bpms::new(args.num_avg);
 
-    let mut state = State::Playing;
+    /// Forgets the taps, but not the half or double time scale.
+    fn clear(&mut self) {
+        self.taps = 0;
+        self.bpms.clear();
+    }
 
-    let mut terminal = RAIITerminal::new()?;
+    /// Whether there have been enough taps to save the BPM.
+    fn ready(&self) -> bool {
+        !self.bpms.is_empty() && self.taps + 1 >= self.options.min_taps
+    }
 
-    loop {
-        match state {
-            State::Playing => {
-                terminal.draw(|f| {
-                    draw_ui(f, &inputs, &mut table_state, bpms.avg());
-                })?;
+    /// Whether the last few bpms agree closely enough to save without waiting for Enter.
+    fn locked(&self) -> bool {
+        let Some(count) = self.options.auto_confirm else {
+            return false;
+        };
+        let count = match self.options.window {
+            Window::Taps(taps) => count.min(taps),
+            Window::Unbounded => count,
+        };
+        if !self.ready() || self.bpms.len() < count {
+            return false;
+        }
 
-                let command = on_keypress(play_keys)?;
+        let mut recent = self.bpms.iter().skip(self.bpms.len() - count);
+        let mean = recent.clone().sum::<f64>() / count as f64;
+        recent.all(|bpm| (bpm - mean).abs() <= mean * self.options.auto_tolerance)
+    }
 
-                match command {
-                    PlayCommands::Quit => {
-                        break;
-                    }
-                    PlayCommands::Confirm => match bpms.avg() {
-                        Some(bpm) => {
-                            if args.confirm {
-                                state = State::Finished { bpm };
-                            } else {
-                                inputs[table_state.selected().unwrap()].set_bpm(bpm)?;
-                                let input_idx =
-                                    (table_state.selected().unwrap() + 1) % inputs.len();
-                                table_state.select(Some(input_idx));
-                                _player = audio_stream.play(&inputs[input_idx].path())?;
-                                last_press_at = None;
-                                bpms = Bpms::new(args.num_avg);
-                            }
-                        }
-                        None => {}
-                    },
-                    PlayCommands::Restart => {
-                        _player =
-                            audio_stream.play(&inputs[table_state.selected().unwrap()].path())?;
-                        last_press_at = None;
-                        bpms = Bpms::new(args.num_avg);
-                    }
-                    PlayCommands::Tap => {
-                        let now = chrono::Utc::now();
-                        if let Some(last_press_at) = last_press_at {
-                            let diff: chrono::TimeDelta = now - last_press_at;
-                            if diff.num_seconds() < args.max_time.into() {
-                                let bpm = 60000.0 / (diff.num_milliseconds() as f64);
-                                bpms.push(bpm);
-                            }
-                        }
-                        last_press_at = Some(now);
-                    }
-                    PlayCommands::Up => {
-                        if inputs.len() == 1 {
-                            continue;
-                        }
+    /// The time between each tap in the window and the one before, in milliseconds.
+    fn intervals(&self) -> impl Iterator<Item = f64> + Clone + '_ {
+        self.bpms.iter().map(|bpm| 60000.0 / bpm)
+    }
 
-                        let input_idx =
-                            (table_state.selected().unwrap() + inputs.len() - 1) % inputs.len();
-                        table_state.select(Some(input_idx));
-                        _player = audio_stream.play(&inputs[input_idx].path())?;
-                        last_press_at = None;
-                        bpms = Bpms::new(args.num_avg);
-                    }
-                    PlayCommands::Down => {
-                        if inputs.len() == 1 {
-                            continue;
-                        }
+    /// The mean time between taps in the window, in milliseconds.
+    fn beat_period(&self) -> Option<f64> {
+        if self.bpms.is_empty() {
+            return None;
+        }
+        Some(self.intervals().sum::<f64>() / self.bpms.len() as f64)
+    }
 
-                        let input_idx = (table_state.selected().unwrap() + 1) % inputs.len();
-                        table_state.select(Some(input_idx));
-                        _player = audio_stream.play(&inputs[input_idx].path())?;
-                        last_press_at = None;
-                        bpms = Bpms::new(args.num_avg);
-                    }
+    fn push(&mut self, bpm: f64) {
+        self.taps += 1;
+        self.bpms.push_back(bpm);
+        if let Window::Taps(taps) = self.options.window {
+            if self.bpms.len() > taps {
+                self.bpms.pop_front();
+            }
+        }
+    }
 
-                    PlayCommands::Manual => {
-                        state = State::Manual { manual_bpm: 0 };
-                    }
+    fn avg(&self) -> Option<f64> {
+        if self.bpms.is_empty() {
+            return None;
+        }
+
+        let mean = |bpms: &[f64]| bpms.iter().sum::<f64>() / bpms.len() as f64;
+        let mut bpms = Vec::from(self.bpms.clone());
+        let bpm = match self.options.estimator {
+            Estimator::Mean => mean(&bpms),
+            Estimator::Median => median(&mut bpms),
+            Estimator::Trimmed => {
+                let tempo = median(&mut bpms);
+                bpms.retain(|bpm| (bpm - tempo).abs() <= tempo * self.options.threshold);
+                if bpms.is_empty() {
+                    tempo
+                } else {
+                    mean(&bpms)
                 }
             }
-            State::Finished { bpm } => {
-                terminal.draw(|f| {
-                    draw_ui(f, &inputs, &mut table_state, Some(bpm));
-                    let popup = Paragraph::new(vec![
-                        Line::from("Save BPM?"),
-                        Line::from(vec![
-                            Span::styled("y", Style::default().add_modifier(Modifier::BOLD)),
-                            Span::raw("es/"),
-                            Span::styled("n", Style::default().add_modifier(Modifier::BOLD)),
-                            Span::raw("o"),
-                        ]),
-                    ])
-                    .block(Block::default().borders(Borders::ALL))
-                    .alignment(Alignment::Center);
-                    let area = centered_rect(10, 10, f.size());
+        };
+        Some(self.song_bpm(bpm))
+    }
+
+    /// The BPM from just the last two taps.
+    fn last(&self) -> Option<f64> {
+        self.bpms.back().map(|bpm| self.song_bpm(*bpm))
+    }
+
+    fn stats(&self) -> Option<Stats> {
+        if self.bpms.is_empty() {
+            return None;
+        }
+
+        let intervals = self.intervals().collect::<Vec<_>>();
+        let mean = intervals.iter().sum::<f64>() / intervals.len() as f64;
+        let variance =
+            intervals.iter().map(|i| (i - mean).powi(2)).sum::<f64>() / intervals.len() as f64;
+        let std_dev = variance.sqrt();
+
+        // Judge by the spread relative to the beat, so fast and slow tempos are treated alike.
+        let confidence = match std_dev / mean {
+            _ if self.bpms.len() < 4 => Confidence::Low,
+            spread if spread < 0.02 => Confidence::High,
+            spread if spread < 0.05 => Confidence::Medium,
+            _ => Confidence::Low,
+        };
+
+        // Fit a line through the bpms to see whether the tempo is trending up or down.
+        let n = self.bpms.len() as f64;
+        let mean_index = (n - 1.0) / 2.0;
+        let mean_bpm = self.bpms.iter().sum::<f64>() / n;
+        let (covariance, index_variance) = self.bpms.iter().enumerate().fold(
+            (0.0, 0.0),
+            |(covariance, index_variance), (i, bpm)| {
+                let di = i as f64 - mean_index;
+                (covariance + di * (bpm - mean_bpm), index_variance + di * di)
+            },
+        );
+        let drift = if self.bpms.len() < DRIFT_TAPS {
+            None
+        } else {
+            Some(covariance / index_variance * (n - 1.0) / mean_bpm)
+                .filter(|drift| drift.abs() > DRIFT_THRESHOLD)
+        };
+
+        Some(Stats {
+            // The first tap only starts the first interval.
+            taps: self.taps + 1,
+            std_dev,
+            min: self.song_bpm(self.bpms.iter().copied().fold(f64::INFINITY, f64::min)),
+            max: self.song_bpm(self.bpms.iter().copied().fold(f64::NEG_INFINITY, f64::max)),
+            confidence,
+            drift,
+        })
+    }
+
+    fn halve(&mut self) {
+        self.scale /= 2.0;
+    }
+
+    fn double(&mut self) {
+        self.scale *= 2.0;
+    }
+
+    /// Switches between tapping every beat, every 2nd beat, and every 4th beat.
+    fn cycle_beats_per_tap(&mut self) {
+        self.options.beats_per_tap = match self.options.beats_per_tap {
+            1 => 2,
+            2 => 4,
+            _ => 1,
+        };
+    }
+
+    /// Converts a bpm of the taps themselves to the bpm of the song.
+    fn song_bpm(&self, bpm: f64) -> f64 {
+        bpm * self.scale * self.options.beats_per_tap as f64
+    }
+}
+
+/// A song being tapped along to.
+struct Song {
+    sink: Sink,
+    started: Instant,
+    paused_at: Option<Instant>,
+    last_press_at: Option<Instant>,
+    /// When the last tap was registered, without the latency taken off, to flash the readout.
+    tapped_at: Option<Instant>,
+    /// The first tap since the taps were last cleared, which the beat grid is laid from.
+    anchor: Option<Instant>,
+    /// The sections finished so far, for songs that change tempo.
+    sections: Vec<file::Section>,
+    section_start: Duration,
+    /// The tempo estimated from the audio, to save if nothing is tapped.
+    estimate: Option<analysis::Tempo>,
+    /// The key estimated from the audio, saved along with the BPM.
+    key: Option<file::Key>,
+    estimating: Option<mpsc::Receiver<Result<analysis::Estimate, String>>>,
+    /// Why the song couldn't be estimated, if it couldn't.
+    unestimated: Option<String>,
+    /// Whether the estimate was asked for with A, to offer saving it as soon as it's ready.
+    analyze: bool,
+    /// How long the song is, once it's been worked out.
+    duration: Option<Duration>,
+    /// Where the next loop starts, once it's been marked.
+    loop_in: Option<Duration>,
+    /// The stretch of the song being looped over, if any.
+    looping: Option<(Duration, Duration)>,
+    measuring: Option<mpsc::Receiver<Duration>>,
+    /// How loud the song is as it plays.
+    levels: Arc<meter::Levels>,
+    /// The track gain being applied, in dB.
+    gain: Option<f64>,
+    /// How loud the song is over time, once it's been decoded.
+    waveform: Option<Vec<f32>>,
+    sketching: Option<(mpsc::Receiver<Vec<f32>>, Cancel)>,
+    /// How loud the song is at each frequency over time, when asked for.
+    spectrogram: Option<Vec<Spectrum>>,
+    listening: Option<mpsc::Receiver<Vec<Spectrum>>>,
+    /// A click at the tapped tempo played over the song, along with what it was last lined up
+    /// with.
+    click: Option<(Sink, ClickSync)>,
+    /// Why the song isn't playing, if it couldn't be opened.
+    error: Option<String>,
+    cover: Option<cover::Cover>,
+    uncovering: Option<mpsc::Receiver<cover::Cover>>,
+    /// What AcoustID recognized the song as, if it knew it, or why it couldn't be looked up.
+    identified: Option<Result<Option<online::Recording>, String>>,
+    identifying: Option<(Identified, Cancel)>,
+    /// The BPM the database has for the song, if it knew it, or why it couldn't be fetched.
+    fetched: Option<Result<Option<f64>, String>>,
+    fetching: Option<mpsc::Receiver<Result<Option<f64>, String>>>,
+}
+
+type Identified = mpsc::Receiver<Result<Option<online::Recording>, String>>;
+
+type Spectrum = [f32; analysis::SPECTROGRAM_BANDS.len()];
+
+/// Stops work on another thread once it's dropped, for when nothing's waiting on it any more.
+struct Cancel(Arc<AtomicBool>);
+
+impl Drop for Cancel {
+    fn drop(&mut self) {
+        self.0.store(true, atomic::Ordering::Relaxed);
+    }
+}
+
+/// The tempo, first tap, speed and volume a click track is lined up with.
+type ClickSync = (f64, Instant, f32, f32);
+
+impl Song {
+    fn new(sink: Sink, levels: Arc<meter::Levels>) -> Song {
+        Song {
+            sink,
+            started: Instant::now(),
+            paused_at: None,
+            last_press_at: None,
+            tapped_at: None,
+            anchor: None,
+            sections: Vec::new(),
+            section_start: Duration::ZERO,
+            estimate: None,
+            key: None,
+            estimating: None,
+            unestimated: None,
+            analyze: false,
+            duration: None,
+            measuring: None,
+            loop_in: None,
+            looping: None,
+            click: None,
+            levels,
+            gain: None,
+            waveform: None,
+            sketching: None,
+            spectrogram: None,
+            listening: None,
+            error: None,
+            cover: None,
+            uncovering: None,
+            identified: None,
+            identifying: None,
+            fetched: None,
+            fetching: None,
+        }
+    }
+
+    /// Decodes the song's cover art on another thread, if it has any.
+    fn start_cover(&mut self, track: &dyn file::Music) {
+        let Ok(Some(data)) = track.picture() else {
+            return;
+        };
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            if let Ok(cover) = cover::Cover::decode(data) {
+                let _ = sender.send(cover);
+            }
+        });
+        self.uncovering = Some(receiver);
+    }
+
+    /// Picks up the cover art once it's been decoded.
+    fn poll_cover(&mut self) {
+        let Some(receiver) = &self.uncovering else {
+            return;
+        };
+        match receiver.try_recv() {
+            Ok(cover) => self.cover = Some(cover),
+            Err(mpsc::TryRecvError::Empty) => return,
+            Err(mpsc::TryRecvError::Disconnected) => {}
+        }
+        self.uncovering = None;
+    }
+
+    /// Looks the song up on AcoustID by its audio on another thread.  It's given up on if the
+    /// song is changed before it's been fingerprinted.
+    fn start_identify(&mut self, path: &str, key: &str) {
+        let (sender, receiver) = mpsc::channel();
+        let (path, key) = (path.to_owned(), key.to_owned());
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let cancel = Cancel(cancelled.clone());
+        thread::spawn(move || {
+            let identified = online::identify(&path, &key, &cancelled);
+            let _ = sender.send(identified.map_err(|e| e.to_string()));
+        });
+        self.identifying = Some((receiver, cancel));
+    }
+
+    /// Picks up what the song was recognized as, once it's been looked up.
+    fn poll_identified(&mut self) {
+        let Some((receiver, _)) = &self.identifying else {
+            return;
+        };
+        match receiver.try_recv() {
+            Ok(identified) => self.identified = Some(identified),
+            Err(mpsc::TryRecvError::Empty) => return,
+            Err(mpsc::TryRecvError::Disconnected) => {}
+        }
+        self.identifying = None;
+    }
+
+    /// Asks the BPM database for the song by its tags on another thread, if it has an artist
+    /// and title to ask with.
+    fn start_fetch(&mut self, api: &str, metadata: &file::Metadata) {
+        let (Some(artist), Some(title)) = (&metadata.artist, &metadata.title) else {
+            return;
+        };
+        let (sender, receiver) = mpsc::channel();
+        let (api, artist, title) = (api.to_owned(), artist.clone(), title.clone());
+        thread::spawn(move || {
+            let fetched = online::fetch_bpm(&api, &artist, &title);
+            let _ = sender.send(fetched.map_err(|e| e.to_string()));
+        });
+        self.fetching = Some(receiver);
+    }
+
+    /// Picks up the database's BPM once it's been fetched.
+    fn poll_fetched(&mut self) {
+        let Some(receiver) = &self.fetching else {
+            return;
+        };
+        match receiver.try_recv() {
+            Ok(fetched) => self.fetched = Some(fetched),
+            Err(mpsc::TryRecvError::Empty) => return,
+            Err(mpsc::TryRecvError::Disconnected) => {}
+        }
+        self.fetching = None;
+    }
+
+    /// The BPM the database has for the song, once it's been fetched.
+    fn online_bpm(&self) -> Option<f64> {
+        match self.fetched {
+            Some(Ok(bpm)) => bpm,
+            _ => None,
+        }
+    }
+
+    /// Starts estimating the BPM and key on another thread, since decoding takes a while.
+    fn start_estimate(&mut self, path: &str, range: BpmRange) {
+        let (sender, receiver) = mpsc::channel();
+        let path = path.to_owned();
+        thread::spawn(move || {
+            let estimate = analysis::estimate(&path, range.min, range.max);
+            // The receiver is gone if the song was changed in the meantime.
+            let _ = sender.send(estimate.map_err(|e| e.to_string()));
+        });
+        self.estimating = Some(receiver);
+    }
+
+    /// Works out how long the song is on another thread, since some formats have to be decoded
+    /// all the way through.
+    fn start_measure(&mut self, path: &str) {
+        let (sender, receiver) = mpsc::channel();
+        let path = path.to_owned();
+        thread::spawn(move || {
+            // Songs that can't be decoded just don't show their length.
+            if let Ok(duration) = analysis::duration(&path) {
+                let _ = sender.send(duration);
+            }
+        });
+        self.measuring = Some(receiver);
+    }
+
+    /// Decodes the whole song on another thread to draw its waveform.  It stops partway if the
+    /// song is changed in the meantime.
+    fn start_sketch(&mut self, path: &str) {
+        let (sender, receiver) = mpsc::channel();
+        let path = path.to_owned();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let cancel = Cancel(cancelled.clone());
+        thread::spawn(move || {
+            if let Ok(waveform) = analysis::waveform(&path, &cancelled) {
+                let _ = sender.send(waveform);
+            }
+        });
+        self.sketching = Some((receiver, cancel));
+    }
+
+    /// Picks up the waveform if it's been drawn.
+    fn poll_waveform(&mut self) {
+        let Some((receiver, _)) = &self.sketching else {
+            return;
+        };
+        match receiver.try_recv() {
+            Ok(waveform) => self.waveform = Some(waveform),
+            Err(mpsc::TryRecvError::Empty) => return,
+            Err(mpsc::TryRecvError::Disconnected) => {}
+        }
+        self.sketching = None;
+    }
+
+    /// Measures the song's frequencies on another thread for the spectrogram.
+    fn start_spectrogram(&mut self, path: &str) {
+        let (sender, receiver) = mpsc::channel();
+        let path = path.to_owned();
+        thread::spawn(move || {
+            if let Ok(spectrogram) = analysis::spectrogram(&path) {
+                let _ = sender.send(spectrogram);
+            }
+        });
+        self.listening = Some(receiver);
+    }
+
+    /// Picks up the spectrogram if it's been measured.
+    fn poll_spectrogram(&mut self) {
+        let Some(receiver) = &self.listening else {
+            return;
+        };
+        match receiver.try_recv() {
+            Ok(spectrogram) => self.spectrogram = Some(spectrogram),
+            Err(mpsc::TryRecvError::Empty) => return,
+            Err(mpsc::TryRecvError::Disconnected) => {}
+        }
+        self.listening = None;
+    }
+
+    /// Picks up the song's length if it's been worked out.
+    fn poll_duration(&mut self) {
+        let Some(receiver) = &self.measuring else {
+            return;
+        };
+        match receiver.try_recv() {
+            Ok(duration) => self.duration = Some(duration),
+            Err(mpsc::TryRecvError::Empty) => return,
+            Err(mpsc::TryRecvError::Disconnected) => {}
+        }
+        self.measuring = None;
+    }
+
+    /// Picks up the estimate if it's done.
+    fn poll_estimate(&mut self) {
+        let Some(receiver) = &self.estimating else {
+            return;
+        };
+        match receiver.try_recv() {
+            Ok(Ok(estimate)) => {
+                self.estimate = estimate.tempo;
+                self.key = estimate.key;
+            }
+            // Songs that can't be analyzed just don't get an estimate, unless it was asked for.
+            Ok(Err(e)) => self.unestimated = Some(e),
+            Err(mpsc::TryRecvError::Empty) => return,
+            Err(mpsc::TryRecvError::Disconnected) => {}
+        }
+        self.estimating = None;
+    }
+
+    fn pause(&mut self) {
+        self.sink.pause();
+        self.paused_at.get_or_insert_with(Instant::now);
+    }
+
+    fn resume(&mut self) {
+        // Nothing played while paused, which shouldn't look like the output going away.
+        self.levels.reset();
+        if let Some(paused_at) = self.paused_at.take() {
+            let paused = paused_at.elapsed();
+            self.started += paused;
+            // Keep the beat grid in step with the music, and don't count the pause as time
+            // between taps.
+            for time in [&mut self.anchor, &mut self.last_press_at]
+                .into_iter()
+                .flatten()
+            {
+                *time += paused;
+            }
+        }
+        self.sink.play();
+    }
+
+    fn paused(&self) -> bool {
+        self.paused_at.is_some()
+    }
+
+    /// Whether a tap was registered just now.
+    fn flashing(&self) -> bool {
+        self.tapped_at
+            .is_some_and(|tapped_at| tapped_at.elapsed() < TAP_FLASH)
+    }
+
+    /// Switches to a sink playing from somewhere else in the song.
+    fn jump(&mut self, sink: Sink, position: Duration) {
+        let paused = self.paused();
+        self.sink = sink;
+        self.paused_at = None;
+        self.looping = None;
+        self.rebase(position);
+        if paused {
+            self.pause();
+        }
+        // The gap between the taps either side of a jump isn't a beat.
+        self.last_press_at = None;
+        self.anchor = None;
+    }
+
+    /// Switches to a sink looping over part of the song, starting from the top of the loop.
+    fn start_loop(&mut self, sink: Sink, start: Duration, end: Duration) {
+        self.jump(sink, start);
+        self.loop_in = None;
+        self.looping = Some((start, end));
+    }
+
+    /// Changes how fast the song plays, as a fraction of its normal speed.
+    fn set_speed(&mut self, speed: f32) {
+        let position = self.position();
+        self.sink.set_speed(speed);
+        self.rebase(position);
+        // Taps either side of the change were made to different tempos.
+        self.last_press_at = None;
+        self.anchor = None;
+    }
+
+    /// Moves the start of the song so playback is at the given position now.
+    fn rebase(&mut self, position: Duration) {
+        let now = self.paused_at.unwrap_or_else(Instant::now);
+        self.started = now
+            .checked_sub(position.div_f32(self.sink.speed()))
+            .unwrap_or(now);
+    }
+
+    /// How far into the song playback is, ignoring any loop.  Songs loop, so this can run past
+    /// the end.
+    fn unlooped_position(&self, at: Instant) -> Duration {
+        at.saturating_duration_since(self.started)
+            .mul_f32(self.sink.speed())
+    }
+
+    /// How many times round the loop playback has gone.
+    fn lap(&self, at: Instant) -> u64 {
+        match self.looping {
+            Some((start, end)) if end > start => {
+                let into = self.unlooped_position(at).saturating_sub(start);
+                (into.as_secs_f64() / (end - start).as_secs_f64()) as u64
+            }
+            _ => 0,
+        }
+    }
+
+    /// How far into the song playback is.  Songs loop, so this can run past the end.
+    fn position(&self) -> Duration {
+        let position = self.unlooped_position(self.paused_at.unwrap_or_else(Instant::now));
+        match self.looping {
+            Some((start, end)) if position > start && end > start => {
+                let into = (position - start).as_secs_f64() % (end - start).as_secs_f64();
+                start + Duration::from_secs_f64(into)
+            }
+            _ => position,
+        }
+    }
+
+    /// Converts time that went by while playing to time in the song, which differ when the
+    /// song is slowed down or sped up.
+    fn song_time(&self, elapsed: Duration) -> Duration {
+        elapsed.mul_f32(self.sink.speed())
+    }
+
+    /// Where playback is on the beat grid at the given tempo, as the number of the current beat
+    /// and how far into it, from 0 to 1.
+    fn beat(&self, bpm: f64, latency: f64) -> Option<(u64, f64)> {
+        // Taps are moved back by the latency, so move the grid forward again to line up with
+        // what's heard.
+        let now = self.paused_at.unwrap_or_else(Instant::now);
+        let elapsed = now.duration_since(self.anchor?).as_secs_f64() * 1000.0 - latency;
+        if elapsed < 0.0 {
+            return None;
+        }
+        let elapsed = elapsed * f64::from(self.sink.speed());
+        let beats = elapsed * bpm / 60000.0;
+        Some((beats as u64, beats.fract()))
+    }
+
+    /// Keeps the click track in step with the tapped tempo, or stops it when there's no tempo to
+    /// follow.
+    fn sync_click(
+        &mut self,
+        bpm: Option<f64>,
+        audio_stream: &AudioStream,
+    ) -> Result<(), anyhow::Error> {
+        let wanted = match (bpm, self.anchor) {
+            (Some(bpm), Some(anchor)) if !self.paused() => {
+                Some((bpm, anchor, self.sink.speed(), self.sink.volume()))
+            }
+            _ => None,
+        };
+        if self.click.as_ref().map(|(_, synced)| *synced) == wanted {
+            return Ok(());
+        }
+
+        self.click = match wanted {
+            Some(synced @ (bpm, _, speed, volume)) => {
+                // The anchor is already moved back by the latency, so a click lined up with it
+                // is heard in time with the song.
+                let (_, phase) = self.beat(bpm, 0.0).unwrap_or_default();
+                let sink = audio_stream.click(bpm * f64::from(speed), phase)?;
+                sink.set_volume(volume);
+                Some((sink, synced))
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// The current section, if it ended here.
+    fn section(&self, bpm: f64) -> file::Section {
+        file::Section {
+            start: self.section_start,
+            end: self.position(),
+            bpm,
+        }
+    }
+
+    /// Ends the current section here, starting the next one.
+    fn end_section(&mut self, bpm: f64) {
+        let section = self.section(bpm);
+        self.sections.push(section);
+        self.section_start = section.end;
+        self.last_press_at = None;
+        self.anchor = None;
+    }
+
+    /// Saves the BPM of the final section, along with any earlier sections, to the selected song
+    /// and any marked ones.  The final section can be left out if it was already ended.  Returns
+    /// how the songs were beforehand.
+    ///
+    /// Songs that can't be saved are marked with the error, and the returned message says what
+    /// went wrong.
+    fn save(
+        &mut self,
+        audio_stream: &mut AudioStream,
+        inputs: &mut [Input],
+        selected: usize,
+        bpm: Option<f64>,
+        estimate: Option<analysis::Tempo>,
+    ) -> (Vec<Saved>, Option<String>) {
+        for (i, input) in inputs.iter().enumerate() {
+            if i == selected || input.marked {
+                audio_stream.forget(input.track.path());
+            }
+        }
+        let mut saved = Vec::new();
+        let mut errors = Vec::new();
+        let input = &mut inputs[selected];
+        match self.write(input, bpm, estimate) {
+            Ok(backup) => {
+                saved.push(backup);
+                input.error = None;
+            }
+            Err(e) => {
+                errors.push(format!("Couldn't save {}: {}", input.name(), e));
+                input.error = Some(e.to_string());
+            }
+        }
+
+        let (marked, marked_errors) = save_marked(inputs, bpm);
+        saved.extend(marked);
+        errors.extend(marked_errors);
+        (saved, summarize(errors))
+    }
+
+    /// Saves the BPM to just the one song, returning how it was beforehand.
+    fn write(
+        &mut self,
+        input: &mut Input,
+        bpm: Option<f64>,
+        estimate: Option<analysis::Tempo>,
+    ) -> Result<Saved, anyhow::Error> {
+        let saved = Saved::new(input)?;
+        let track = input.track.as_mut();
+        match (bpm, estimate) {
+            // The key doesn't depend on the tempo, so it's saved however the BPM was found.
+            (Some(bpm), Some(estimate)) if self.sections.is_empty() => {
+                track.set_beat_grid(bpm, estimate.offset, self.key)?
+            }
+            (Some(bpm), None) if self.sections.is_empty() => track.set_bpm(bpm, self.key)?,
+            (bpm, _) => {
+                // The final section is only ended once it's saved, so trying again after a
+                // failed save doesn't add it twice.
+                let mut sections = self.sections.clone();
+                sections.extend(bpm.map(|bpm| self.section(bpm)));
+                track.set_sections(&sections, self.key)?;
+                if let Some(bpm) = bpm {
+                    self.end_section(bpm);
+                }
+            }
+        }
+        input.confidence = estimate.map(|estimate| estimate.confidence);
+        Ok(saved)
+    }
+}
+
+/// A song in the list.
+struct Input {
+    track: Box<dyn file::Music>,
+    /// How confident the estimate was, if the BPM was saved from one this session.
+    confidence: Option<f64>,
+    /// Where the song was given on the command line.
+    order: usize,
+    /// How long the song is, once it's been worked out.
+    duration: Option<Duration>,
+    /// Whether the song's been flagged to come back to.
+    flagged: bool,
+    /// Whether the next BPM saved goes to this song too.
+    marked: bool,
+    /// What went wrong the last time the song was played or saved.
+    error: Option<String>,
+    /// When the file was last changed, as of reading it, so saving doesn't move it in the list.
+    modified: Option<SystemTime>,
+}
+
+impl Input {
+    fn new(track: file::Track, order: usize) -> Input {
+        let modified = fs::metadata(file::Music::path(&track))
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        Input {
+            modified,
+            track: Box::new(track),
+            confidence: None,
+            order,
+            duration: None,
+            flagged: false,
+            marked: false,
+            error: None,
+        }
+    }
+
+    /// The artist and title if the song is tagged with them, or else its path.
+    fn name(&self) -> String {
+        let metadata = self.track.metadata();
+        match (&metadata.artist, &metadata.title) {
+            (Some(artist), Some(title)) => format!("{} – {}", artist, title),
+            (None, Some(title)) => title.clone(),
+            _ => self.track.path().to_owned(),
+        }
+    }
+}
+
+/// Cuts the middle out of text that's wider than the given number of columns.  Paths keep their
+/// file name, since that's what tells songs apart.
+fn truncate_middle(text: &str, width: usize) -> String {
+    if text.width() <= width {
+        return text.to_owned();
+    }
+    let Some(budget) = width.checked_sub(1) else {
+        return String::new();
+    };
+    let (head, tail) = match text.rfind('/') {
+        Some(i) if text[i..].width() <= budget => (budget - text[i..].width(), text[i..].width()),
+        Some(i) => return truncate_middle(&text[i + 1..], width),
+        None => (budget - budget / 2, budget / 2),
+    };
+
+    let fit = |chars: &mut dyn Iterator<Item = char>, width: usize| {
+        let mut used = 0;
+        chars
+            .take_while(|c| {
+                used += c.width().unwrap_or(0);
+                used <= width
+            })
+            .collect::<String>()
+    };
+    let start = fit(&mut text.chars(), head);
+    let end = fit(&mut text.chars().rev(), tail)
+        .chars()
+        .rev()
+        .collect::<String>();
+    format!("{}…{}", start, end)
+}
+
+/// Describes a song's stream, for spotting files the output device might not take.
+fn stream_info(track: &dyn file::Music) -> Result<Vec<String>, anyhow::Error> {
+    let info = analysis::stream_info(track.path())?;
+    let channels = match info.channels {
+        1 => "1 (mono)".to_owned(),
+        2 => "2 (stereo)".to_owned(),
+        channels => channels.to_string(),
+    };
+    let mut lines = vec![
+        format!("Format: {}", track.format().name()),
+        format!("Sample rate: {} kHz", f64::from(info.sample_rate) / 1000.0),
+        format!("Channels: {}", channels),
+    ];
+    // Tags and artwork are counted in with the audio, so it's only roughly right.
+    let size = fs::metadata(track.path())?.len();
+    if let Some(duration) = info.duration.filter(|duration| !duration.is_zero()) {
+        let kbps = size as f64 * 8.0 / duration.as_secs_f64() / 1000.0;
+        lines.push(format!("Bitrate: about {:.0} kbps", kbps));
+    }
+    Ok(lines)
+}
+
+fn read_track(
+    path: String,
+    write_options: &file::WriteOptions,
+) -> Result<file::Track, anyhow::Error> {
+    let format = file::Format::detect(&path)
+        .map_err(|e| anyhow::anyhow!("{}: {}", path, e))?
+        .ok_or_else(|| anyhow::anyhow!("{}: Unsupported file type", path))?;
+    file::Track::new(path.clone(), format, write_options.clone())
+        .map_err(|e| anyhow::anyhow!("{}: {}", path, e))
+}
+
+/// Opens the output device with the given name, or the default one.
+fn open_output(device: Option<&str>) -> Result<(OutputStream, OutputStreamHandle), anyhow::Error> {
+    use rodio::cpal::traits::{DeviceTrait, HostTrait};
+
+    let Some(name) = device else {
+        return Ok(OutputStream::try_default()?);
+    };
+    let mut names = Vec::new();
+    for device in rodio::cpal::default_host().output_devices()? {
+        let Ok(device_name) = device.name() else {
+            continue;
+        };
+        if device_name == name {
+            return Ok(OutputStream::try_from_device(&device)?);
+        }
+        names.push(device_name);
+    }
+    Err(anyhow::anyhow!(
+        "No output device called {}, the ones there are: {}",
+        name,
+        names.join(", ")
+    ))
+}
+
+/// Reads every song's tags on another thread, sending each one back with its order as it goes,
+/// so there's no waiting for a long list before tapping the first song.
+fn load_all(
+    paths: Vec<String>,
+    write_options: file::WriteOptions,
+) -> mpsc::Receiver<(usize, Result<file::Track, anyhow::Error>)> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for (order, path) in paths.into_iter().enumerate() {
+            if sender
+                .send((order, read_track(path, &write_options)))
+                .is_err()
+            {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// Saves the BPM to every marked song as well as the one it was tapped on, for different versions
+/// of a song at the same tempo.  Only the BPM itself is copied, since the beat grid and sections
+/// are peculiar to each version.
+fn save_marked(inputs: &mut [Input], bpm: Option<f64>) -> (Vec<Saved>, Vec<String>) {
+    let mut saved = Vec::new();
+    let mut errors = Vec::new();
+    for input in inputs.iter_mut().filter(|input| input.marked) {
+        input.marked = false;
+        let Some(bpm) = bpm else {
+            continue;
+        };
+        let result = Saved::new(input).and_then(|backup| {
+            input.track.set_bpm(bpm, None)?;
+            Ok(backup)
+        });
+        match result {
+            Ok(backup) => {
+                saved.push(backup);
+                input.confidence = None;
+                input.error = None;
+            }
+            Err(e) => {
+                errors.push(format!("Couldn't save {}: {}", input.name(), e));
+                input.error = Some(e.to_string());
+            }
+        }
+    }
+    (saved, errors)
+}
+
+/// Boils a list of errors down to the first, and how many more there were.
+fn summarize(errors: Vec<String>) -> Option<String> {
+    let count = errors.len();
+    let first = errors.into_iter().next()?;
+    Some(match count {
+        1 => first,
+        _ => format!("{} (and {} more)", first, count - 1),
+    })
+}
+
+/// A BPM saved, or put back by undoing, this session.
+struct Entry {
+    order: usize,
+    old: Option<f64>,
+    new: Option<f64>,
+    at: Instant,
+}
+
+/// Adds the songs just saved to the session history.
+fn record(history: &mut Vec<Entry>, inputs: &[Input], saved: &[Saved]) {
+    let at = Instant::now();
+    for saved in saved {
+        if let Some(input) = inputs.iter().find(|input| input.order == saved.order) {
+            history.push(Entry {
+                order: saved.order,
+                old: saved.backup.bpm(),
+                new: input.track.bpm(),
+                at,
+            });
+        }
+    }
+}
+
+/// A song as it was before a save, so the save can be undone.
+struct Saved {
+    order: usize,
+    confidence: Option<f64>,
+    backup: file::Backup,
+}
+
+impl Saved {
+    fn new(input: &Input) -> Result<Saved, anyhow::Error> {
+        Ok(Saved {
+            order: input.order,
+            confidence: input.confidence,
+            backup: input.track.backup()?,
+        })
+    }
+}
+
+/// Adds songs to the end of the list, skipping any already in it and any that can't be read.
+/// Returns how many were added.
+fn add_songs(
+    inputs: &mut Vec<Input>,
+    paths: Vec<PathBuf>,
+    next_order: &mut usize,
+    write_options: &file::WriteOptions,
+) -> usize {
+    let before = inputs.len();
+    for path in paths {
+        let Some(path) = path.to_str().map(str::to_owned) else {
+            continue;
+        };
+        if inputs.iter().any(|input| input.track.path() == path) {
+            continue;
+        }
+        if let Ok(track) = read_track(path, write_options) {
+            inputs.push(Input::new(track, *next_order));
+            *next_order += 1;
+        }
+    }
+    inputs.len() - before
+}
+
+/// Works out how long songs are on another thread as they're sent to it, sending back each one's
+/// order along with its length.
+fn measure_all(paths: mpsc::Receiver<(usize, String)>) -> mpsc::Receiver<(usize, Duration)> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for (order, path) in paths {
+            // Songs that can't be decoded just don't show their length.
+            if let Ok(duration) = analysis::duration(&path) {
+                if sender.send((order, duration)).is_err() {
+                    return;
+                }
+            }
+        }
+    });
+    receiver
+}
+
+/// How the song list is sorted.
+#[derive(Clone, Copy, Default, Debug, ValueEnum)]
+enum SortBy {
+    /// In the order given on the command line
+    #[default]
+    Given,
+    Path,
+    /// The most recently changed files first
+    Modified,
+    Bpm,
+    /// Songs without a BPM first, so they can be worked through
+    Untagged,
+}
+
+impl SortBy {
+    fn next(self) -> SortBy {
+        match self {
+            SortBy::Given => SortBy::Path,
+            SortBy::Path => SortBy::Modified,
+            SortBy::Modified => SortBy::Bpm,
+            SortBy::Bpm => SortBy::Untagged,
+            SortBy::Untagged => SortBy::Given,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            SortBy::Given => "as given",
+            SortBy::Path => "by path",
+            SortBy::Modified => "newest first",
+            SortBy::Bpm => "by BPM",
+            SortBy::Untagged => "untagged first",
+        }
+    }
+
+    /// Sorts the songs, breaking ties by the order they were given in.
+    fn sort(self, inputs: &mut [Input]) {
+        match self {
+            SortBy::Given => inputs.sort_by_key(|input| input.order),
+            SortBy::Path => inputs.sort_by(|a, b| a.track.path().cmp(b.track.path())),
+            SortBy::Modified => {
+                inputs.sort_by_key(|input| (std::cmp::Reverse(input.modified), input.order));
+            }
+            // Songs without a BPM go last.
+            SortBy::Bpm => inputs.sort_by(|a, b| match (a.track.bpm(), b.track.bpm()) {
+                (Some(a), Some(b)) => a.total_cmp(&b),
+                (a, b) => b.is_some().cmp(&a.is_some()),
+            }),
+            SortBy::Untagged => {
+                inputs.sort_by_key(|input| (input.track.bpm().is_some(), input.order));
+            }
+        }
+    }
+}
+
+/// Estimates less confident than this are flagged to be checked by ear.
+const LOW_CONFIDENCE: f64 = 0.3;
+
+/// How long the readout's border lights up for after each tap, to show it was registered.
+const TAP_FLASH: Duration = Duration::from_millis(120);
+
+/// Settings that control how BPMs and keys are shown.
+#[derive(Clone, Copy)]
+struct DisplayOptions {
+    precision: usize,
+    rounding: Rounding,
+    range: BpmRange,
+    key_notation: file::KeyNotation,
+    sort_by: SortBy,
+    theme: Theme,
+    layout: LayoutOptions,
+    /// How many songs have been read out of how many there are, while they're still being read.
+    loading: Option<(usize, usize)>,
+    graphics: cover::Graphics,
+    /// Saving doesn't write anything.
+    dry_run: bool,
+}
+
+/// The parts of the tui besides the song list and the readout, which can be left out.
+#[derive(Clone, Copy, PartialEq, Debug, ValueEnum)]
+enum Panel {
+    /// The progress bar and level meter
+    Progress,
+    Waveform,
+    Spectrogram,
+    BeatGrid,
+    /// A histogram of the time between taps
+    Intervals,
+    /// The cover art beside the song list
+    Cover,
+}
+
+/// How the tui is laid out.
+#[derive(Clone, Copy)]
+struct LayoutOptions {
+    /// How much of the screen the song list takes up as a percentage, or None to give it
+    /// whatever's left over.
+    table_height: Option<u16>,
+    /// Show only the playing song above the panels, and the BPM in large digits.
+    compact: bool,
+    /// The panels to leave out.
+    hide: Panels,
+}
+
+/// A set of panels.
+#[derive(Clone, Copy, Default)]
+struct Panels(u8);
+
+impl Panels {
+    fn contains(self, panel: &Panel) -> bool {
+        self.0 & (1 << *panel as u8) != 0
+    }
+}
+
+impl FromIterator<Panel> for Panels {
+    fn from_iter<I: IntoIterator<Item = Panel>>(panels: I) -> Panels {
+        Panels(
+            panels
+                .into_iter()
+                .fold(0, |set, panel| set | 1 << panel as u8),
+        )
+    }
+}
+
+/// The song list is collapsed down to just the playing song when it has fewer rows than this,
+/// including its borders.
+const MIN_TABLE_HEIGHT: u16 = 5;
+/// Cover art narrower than this, borders included, isn't worth showing.
+const MIN_COVER_WIDTH: u16 = 8;
+/// Two lines of readout, plus borders.
+const READOUT_HEIGHT: u16 = 4;
+/// The readout with large digits above the text, in the compact layout.
+const BIG_READOUT_HEIGHT: u16 = BIG_DIGIT_ROWS + 4;
+
+impl DisplayOptions {
+    /// The BPM to save from the taps so far, if there have been enough.
+    fn tapped(&self, bpms: &Bpms) -> Option<f64> {
+        bpms.avg()
+            .filter(|_| bpms.ready())
+            .map(|bpm| self.rounding.apply(bpm))
+    }
+}
+
+/// The loudest playback can be turned up to, as a percentage.
+const MAX_VOLUME: u32 = 200;
+/// How much each press of + or - changes the volume, as a percentage.
+const VOLUME_STEP: u32 = 10;
+/// The range of playback speeds, as percentages.  Slowing down helps with complex rhythms.
+const MIN_SPEED: u32 = 50;
+const MAX_SPEED: u32 = 150;
+/// How much each press of , or . changes the speed, as a percentage.
+const SPEED_STEP: u32 = 5;
+
+type Samples = Box<dyn Source<Item = i16> + Send>;
+/// A song ready to play, and how far into it it starts.
+type Opened = (Option<Samples>, Duration);
+
+struct AudioStream {
+    handle: OutputStreamHandle,
+    /// Estimate the BPM of every song played within this range.
+    estimate: Option<BpmRange>,
+    /// The playback volume as a percentage.
+    volume: u32,
+    /// Silences playback without changing the volume, so taps still line up with the song.
+    muted: bool,
+    /// The playback speed as a percentage.
+    speed: u32,
+    start_at: Option<StartAt>,
+    /// How much of each song to loop over, instead of the whole thing.
+    preview: Option<Duration>,
+    /// The panels drawn from the whole of every song played, which have to decode it all.  Any
+    /// left out aren't decoded for.
+    decoded: Panels,
+    /// Apply each song's track gain.
+    replay_gain: bool,
+    /// How much to amplify the playing song by, from its track gain.
+    gain: f32,
+    /// Measured from every song as it plays.
+    levels: Arc<meter::Levels>,
+    /// The song being opened ahead of time, usually the next one.
+    preloaded: Option<(String, mpsc::Receiver<Result<Opened, anyhow::Error>>)>,
+    /// What to look every song played up with.
+    lookup: online::Lookup,
+}
+
+impl AudioStream {
+    fn new(
+        handle: OutputStreamHandle,
+        estimate: Option<BpmRange>,
+        volume: u32,
+        start_at: Option<StartAt>,
+        preview: Option<Duration>,
+        decoded: Panels,
+        replay_gain: bool,
+    ) -> AudioStream {
+        AudioStream {
+            handle,
+            estimate,
+            volume,
+            muted: false,
+            speed: 100,
+            start_at,
+            preview,
+            decoded,
+            replay_gain,
+            gain: 1.0,
+            levels: Arc::default(),
+            preloaded: None,
+            lookup: online::Lookup::default(),
+        }
+    }
+
+    /// Looks up every song played online as well.
+    fn with_lookup(self, lookup: online::Lookup) -> AudioStream {
+        AudioStream { lookup, ..self }
+    }
+
+    /// Changes the speed of the playing song, and every song after it.
+    fn set_speed(&mut self, song: &mut Song, speed: u32) {
+        self.speed = speed.clamp(MIN_SPEED, MAX_SPEED);
+        song.set_speed(self.speed as f32 / 100.0);
+    }
+
+    /// Changes the volume of the playing song, and every song after it.
+    fn set_volume(&mut self, song: &Song, volume: u32) {
+        self.volume = volume.min(MAX_VOLUME);
+        song.sink.set_volume(self.sink_volume());
+    }
+
+    fn toggle_mute(&mut self, song: &Song) {
+        self.muted = !self.muted;
+        song.sink.set_volume(self.sink_volume());
+    }
+
+    fn sink_volume(&self) -> f32 {
+        if self.muted {
+            0.0
+        } else {
+            self.volume as f32 / 100.0
+        }
+    }
+
+    /// Applies the track gain and measures the levels of a source before it's played.
+    fn output<S: Source<Item = i16>>(&self, source: S) -> meter::Meter<Amplify<S>> {
+        meter::Meter::new(source.amplify(self.gain), self.levels.clone())
+    }
+
+    /// Opens a song for playback, looping it if asked.  Formats neither rodio nor we can decode
+    /// can still be tagged, they just play silence, so there's no source for them.  Aac in mp4
+    /// is decoded, but not alac, wma, monkey's audio, musepack or dsd.
+    fn source(input: &str, looped: bool) -> Result<Option<Samples>, anyhow::Error> {
+        let file = || File::open(input).map(BufReader::new);
+        let decoded = if looped {
+            Decoder::new_looped(file()?).map(|source| Box::new(source) as Samples)
+        } else {
+            Decoder::new(file()?).map(|source| Box::new(source) as Samples)
+        };
+        match decoded {
+            Ok(source) => Ok(Some(source)),
+            Err(DecoderError::UnrecognizedFormat) => {
+                let decoded = if looped {
+                    aiff::AiffDecoder::new_looped(file()?).map(|source| Box::new(source) as Samples)
+                } else {
+                    aiff::AiffDecoder::new(file()?).map(|source| Box::new(source) as Samples)
+                };
+                Ok(decoded.ok())
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn new_sink(&self) -> Result<Sink, anyhow::Error> {
+        let sink = Sink::try_new(&self.handle)?;
+        sink.set_volume(self.sink_volume());
+        self.levels.reset();
+        sink.set_speed(self.speed as f32 / 100.0);
+        Ok(sink)
+    }
+
+    /// Starts playing a song from the given position.
+    fn sink(&self, input: &str, start: Duration) -> Result<Sink, anyhow::Error> {
+        let sink = self.new_sink()?;
+        if let Some(source) = AudioStream::source(input, true)? {
+            sink.append(self.output(source.skip_duration(start)));
+        }
+        sink.play();
+        Ok(sink)
+    }
+
+    /// Starts playing a stretch of a song over and over.
+    fn looped_sink(
+        &self,
+        input: &str,
+        start: Duration,
+        end: Duration,
+    ) -> Result<Sink, anyhow::Error> {
+        let sink = self.new_sink()?;
+        if let Some(source) = AudioStream::source(input, false)? {
+            let stretch = source.skip_duration(start).take_duration(end - start);
+            sink.append(self.output(stretch.buffered().repeat_infinite()));
+        }
+        sink.play();
+        Ok(sink)
+    }
+
+    /// Opens the output device again, or whatever the default one is now, for when the last one
+    /// has gone away, and picks the song up again where it stopped.  The returned stream has to
+    /// be kept alive for as long as anything plays on it.
+    fn reconnect(
+        &mut self,
+        device: Option<&str>,
+        input: &str,
+        song: &mut Song,
+    ) -> Result<OutputStream, anyhow::Error> {
+        let (stream, handle) = open_output(device)?;
+        self.handle = handle;
+        song.click = None;
+        match song.looping {
+            Some((start, end)) => song.start_loop(self.looped_sink(input, start, end)?, start, end),
+            None => {
+                let position = song.position();
+                song.jump(self.sink(input, position)?, position);
+            }
+        }
+        Ok(stream)
+    }
+
+    /// Opens a song ready to play from where it starts, looping over just the preview if there
+    /// is one.
+    fn open(
+        input: &str,
+        start_at: Option<StartAt>,
+        preview: Option<Duration>,
+    ) -> Result<Opened, anyhow::Error> {
+        let start = start_at
+            .map(|start_at| start_at.position(input))
+            .unwrap_or_default();
+        let source = match preview {
+            Some(preview) => AudioStream::source(input, false)?.map(|source| {
+                let stretch = source.skip_duration(start).take_duration(preview);
+                Box::new(stretch.buffered().repeat_infinite()) as Samples
+            }),
+            None => AudioStream::source(input, true)?
+                .map(|source| Box::new(source.skip_duration(start)) as Samples),
+        };
+        // Said when it's played, rather than leaving the user tapping along to silence.
+        if source.is_none() {
+            return Err(anyhow::anyhow!("format can't be decoded"));
+        }
+        Ok((source, start))
+    }
+
+    /// Starts opening a song on another thread, so it's ready to go when it's played.
+    fn preload(&mut self, input: &str) {
+        if self
+            .preloaded
+            .as_ref()
+            .is_some_and(|(preloaded, _)| preloaded == input)
+        {
+            return;
+        }
+        let (sender, receiver) = mpsc::channel();
+        let path = input.to_owned();
+        let (start_at, preview) = (self.start_at, self.preview);
+        thread::spawn(move || {
+            let _ = sender.send(AudioStream::open(&path, start_at, preview));
+        });
+        self.preloaded = Some((input.to_owned(), receiver));
+    }
+
+    /// Drops the preloaded song if it's about to be changed, since rewriting the file under its
+    /// decoder would have it play from the wrong place.
+    fn forget(&mut self, input: &str) {
+        if self
+            .preloaded
+            .as_ref()
+            .is_some_and(|(preloaded, _)| preloaded == input)
+        {
+            self.preloaded = None;
+        }
+    }
+
+    /// Plays the song at the given index, and preloads the one after it.
+    fn play(&mut self, inputs: &[Input], index: usize) -> Result<Song, anyhow::Error> {
+        let input = inputs[index].track.path();
+        let opened = match self.preloaded.take() {
+            Some((preloaded, receiver)) if preloaded == input => receiver
+                .recv()
+                .unwrap_or_else(|_| AudioStream::open(input, self.start_at, self.preview)),
+            preloaded => {
+                self.preloaded = preloaded;
+                AudioStream::open(input, self.start_at, self.preview)
+            }
+        };
+        // A song that can't be opened plays silence, so it can still be skipped past.
+        let mut error = None;
+        let (source, start) = opened.unwrap_or_else(|e| {
+            error = Some(e.to_string());
+            (None, Duration::ZERO)
+        });
+
+        let gain = inputs[index].track.gain().filter(|_| self.replay_gain);
+        // Gain is in dB of amplitude.
+        self.gain = gain.map_or(1.0, |gain| 10f32.powf(gain as f32 / 20.0));
+        let sink = self.new_sink()?;
+        if let Some(source) = source {
+            sink.append(self.output(source));
+        }
+        sink.play();
+        let mut song = Song::new(sink, self.levels.clone());
+        song.error = error;
+        song.gain = gain;
+        song.rebase(start);
+        song.looping = self.preview.map(|preview| (start, start + preview));
+        // The song list may have measured it already.
+        match inputs[index].duration {
+            Some(duration) => song.duration = Some(duration),
+            None => song.start_measure(input),
+        }
+        if self.decoded.contains(&Panel::Waveform) {
+            song.start_sketch(input);
+        }
+        song.start_cover(inputs[index].track.as_ref());
+        if self.decoded.contains(&Panel::Spectrogram) {
+            song.start_spectrogram(input);
+        }
+        if let Some(range) = self.estimate {
+            song.start_estimate(input, range);
+        }
+        if let Some(key) = &self.lookup.acoustid_key {
+            song.start_identify(input, key);
+        }
+        if let Some(api) = &self.lookup.bpm_api {
+            song.start_fetch(api, inputs[index].track.metadata());
+        }
+
+        // A lone song gets saved before it's played again, which would change the file under
+        // the preloaded decoder.
+        if inputs.len() > 1 {
+            self.preload(inputs[(index + 1) % inputs.len()].track.path());
+        }
+        Ok(song)
+    }
+
+    fn click(&self, bpm: f64, phase: f64) -> Result<Sink, anyhow::Error> {
+        let sink = Sink::try_new(&self.handle)?;
+        sink.append(click::Click::new(bpm, phase));
+        sink.play();
+        Ok(sink)
+    }
+}
+
+/// How far through the song playback is.
+fn progress(song: &Song) -> Gauge<'static> {
+    let position = song.position();
+    let (ratio, label) = match song.duration.filter(|duration| !duration.is_zero()) {
+        Some(duration) => {
+            // Songs loop, so start over each time round.
+            let position = Duration::from_secs_f64(position.as_secs_f64() % duration.as_secs_f64());
+            (
+                position.as_secs_f64() / duration.as_secs_f64(),
+                format!(
+                    "{} / {}",
+                    file::format_time(position),
+                    file::format_time(duration)
+                ),
+            )
+        }
+        None => (0.0, file::format_time(position)),
+    };
+
+    Gauge::default()
+        .block(Block::default().borders(Borders::ALL))
+        .ratio(ratio.clamp(0.0, 1.0))
+        .label(label)
+}
+
+/// How quiet a sound shows on the level meter, in decibels.
+const METER_FLOOR: f32 = -48.0;
+
+/// How loud each channel is right now, to show that audio is actually playing.
+fn level_meter(song: &Song, width: u16, theme: Theme) -> Paragraph<'static> {
+    let cell = (width.saturating_sub(2) as usize / 2).saturating_sub(2);
+    let (levels, title) = match song.levels.get() {
+        Some(levels) => (levels, "Level"),
+        None if song.paused() => ([0.0; 2], "Level"),
+        None => ([0.0; 2], "No audio"),
+    };
+    let spans = ["L ", "R "]
+        .into_iter()
+        .zip(levels)
+        .flat_map(|(name, level)| {
+            // Decibels match what's heard better than the raw level.
+            let lit = if level > 0.0 {
+                (1.0 - 20.0 * level.log10() / METER_FLOOR).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+            let lit = (lit * cell as f32).round() as usize;
+            [
+                Span::raw(name),
+                Span::styled("█".repeat(lit), theme.accent()),
+                Span::raw("·".repeat(cell - lit)),
+            ]
+        })
+        .collect::<Vec<_>>();
+
+    Paragraph::new(Line::from(spans)).block(Block::default().borders(Borders::ALL).title(title))
+}
+
+/// The number of rows the waveform is drawn over.
+const WAVEFORM_ROWS: u16 = 3;
+const WAVEFORM_LEVELS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+/// The song's waveform, with what's been played so far brighter than the rest.
+fn waveform(song: &Song, waveform: &[f32], width: u16, theme: Theme) -> Paragraph<'static> {
+    let columns = width.saturating_sub(2).max(1) as usize;
+    let peaks = (0..columns)
+        .map(|column| {
+            let start = column * waveform.len() / columns;
+            let end = ((column + 1) * waveform.len() / columns).max(start + 1);
+            waveform[start.min(waveform.len())..end.min(waveform.len())]
+                .iter()
+                .copied()
+                .fold(0.0, f32::max)
+        })
+        .collect::<Vec<_>>();
+
+    // Songs loop, so start over each time round, as on the progress bar.
+    let length = waveform.len() as f64 / analysis::WAVEFORM_RATE as f64;
+    let position = song.position().as_secs_f64() % length.max(f64::EPSILON);
+    let playhead = ((position / length * columns as f64) as usize).min(columns - 1);
+
+    let levels = WAVEFORM_LEVELS.len() - 1;
+    let lines = (0..WAVEFORM_ROWS as usize)
+        .rev()
+        .map(|row| {
+            let spans = peaks
+                .iter()
+                .enumerate()
+                .map(|(column, peak)| {
+                    let level = (peak * (levels * WAVEFORM_ROWS as usize) as f32).round() as usize;
+                    let cell = WAVEFORM_LEVELS[level.saturating_sub(row * levels).min(levels)];
+                    let style = match column.cmp(&playhead) {
+                        Ordering::Less => Style::default(),
+                        Ordering::Equal => theme.selection(),
+                        Ordering::Greater => Style::default().add_modifier(Modifier::DIM),
+                    };
+                    Span::styled(cell.to_string(), style)
+                })
+                .collect::<Vec<_>>();
+            Line::from(spans)
+        })
+        .collect::<Vec<_>>();
+
+    Paragraph::new(lines).block(Block::default().borders(Borders::ALL))
+}
+
+const HISTOGRAM_ROWS: u16 = 3;
+/// The narrowest the histogram goes either side of the mean, as a fraction of it, so steady
+/// taps don't get spread across the whole width.
+const HISTOGRAM_MIN_SPREAD: f64 = 0.05;
+
+/// How the time between taps is spread, which shows up taps alternating long and short, as
+/// happens with swing, where the average alone looks fine.  The last tap is lit.
+fn interval_histogram(bpms: &Bpms, width: u16, theme: Theme) -> Paragraph<'static> {
+    let columns = width.saturating_sub(2).max(1) as usize;
+    let mean = bpms.beat_period().unwrap_or_default();
+    let (min, max) = bpms.intervals().fold(
+        (
+            mean * (1.0 - HISTOGRAM_MIN_SPREAD),
+            mean * (1.0 + HISTOGRAM_MIN_SPREAD),
+        ),
+        |(min, max), interval| (min.min(interval), max.max(interval)),
+    );
+    let column = |interval: f64| {
+        (((interval - min) / (max - min).max(f64::EPSILON) * columns as f64) as usize)
+            .min(columns - 1)
+    };
+    let mut counts = vec![0; columns];
+    for interval in bpms.intervals() {
+        counts[column(interval)] += 1;
+    }
+    let highest = counts.iter().copied().max().unwrap_or_default().max(1);
+    let last = bpms.intervals().last().map(column);
+
+    let levels = WAVEFORM_LEVELS.len() - 1;
+    let lines = (0..HISTOGRAM_ROWS as usize)
+        .rev()
+        .map(|row| {
+            let spans = counts
+                .iter()
+                .enumerate()
+                .map(|(i, count)| {
+                    let level = count * levels * HISTOGRAM_ROWS as usize / highest;
+                    let cell = WAVEFORM_LEVELS[level.saturating_sub(row * levels).min(levels)];
+                    let style = if Some(i) == last {
+                        theme.accent()
+                    } else {
+                        Style::default()
+                    };
+                    Span::styled(cell.to_string(), style)
+                })
+                .collect::<Vec<_>>();
+            Line::from(spans)
+        })
+        .collect::<Vec<_>>();
+
+    Paragraph::new(lines).block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(format!("Intervals {:.0}-{:.0}ms", min, max)),
+    )
+}
+
+const SPECTROGRAM_LEVELS: [char; 5] = [' ', '░', '▒', '▓', '█'];
+/// Bands below this are where kick drums are, so they're drawn brighter.
+const KICK_FREQUENCY: f64 = 150.0;
+
+/// The song's frequencies around the playhead, low at the bottom, scrolling past as it plays.
+fn spectrogram(
+    song: &Song,
+    spectrogram: &[Spectrum],
+    width: u16,
+    theme: Theme,
+) -> Paragraph<'static> {
+    let columns = width.saturating_sub(2).max(1) as usize;
+    let length = spectrogram.len() as f64 / analysis::SPECTROGRAM_RATE as f64;
+    let position = song.position().as_secs_f64() % length.max(f64::EPSILON);
+    // Each column is measured over the frame either side of it.
+    let playhead = ((position * analysis::SPECTROGRAM_RATE as f64) as usize).saturating_sub(1);
+
+    let levels = SPECTROGRAM_LEVELS.len() - 1;
+    let lines = analysis::SPECTROGRAM_BANDS
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(band, frequency)| {
+            let spans = (0..columns)
+                .map(|column| {
+                    // The playhead stays in the middle, so what's coming up can be seen.
+                    let cell = (playhead + column)
+                        .checked_sub(columns / 2)
+                        .and_then(|i| spectrogram.get(i))
+                        .map_or(' ', |spectrum| {
+                            SPECTROGRAM_LEVELS
+                                [((spectrum[band] * levels as f32).round() as usize).min(levels)]
+                        });
+                    let style = if column == columns / 2 {
+                        theme.selection()
+                    } else if *frequency < KICK_FREQUENCY {
+                        theme.accent()
+                    } else {
+                        Style::default().add_modifier(Modifier::DIM)
+                    };
+                    Span::styled(cell.to_string(), style)
+                })
+                .collect::<Vec<_>>();
+            Line::from(spans)
+        })
+        .collect::<Vec<_>>();
+
+    Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("Spectrogram"))
+}
+
+/// The number of beats shown on the grid at once.
+const GRID_BEATS: u64 = 4;
+/// How much of each beat the metronome light stays on for.
+const FLASH_FRACTION: f64 = 0.2;
+
+const BIG_DIGIT_ROWS: u16 = 5;
+/// Digits three cells wide, for reading the BPM from across the room.
+const BIG_DIGITS: [[&str; BIG_DIGIT_ROWS as usize]; 10] = [
+    ["███", "█ █", "█ █", "█ █", "███"],
+    [" █ ", "██ ", " █ ", " █ ", "███"],
+    ["███", "  █", "███", "█  ", "███"],
+    ["███", "  █", " ██", "  █", "███"],
+    ["█ █", "█ █", "███", "  █", "  █"],
+    ["███", "█  ", "███", "  █", "███"],
+    ["███", "█  ", "███", "█ █", "███"],
+    ["███", "  █", "  █", "  █", "  █"],
+    ["███", "█ █", "███", "█ █", "███"],
+    ["███", "█ █", "███", "  █", "███"],
+];
+
+/// A number written out in BIG_DIGITS, a row at a time.
+fn big_number(number: &str) -> Vec<Line<'static>> {
+    (0..BIG_DIGIT_ROWS as usize)
+        .map(|row| {
+            let glyphs = number
+                .chars()
+                .map(|c| match c.to_digit(10) {
+                    Some(digit) => BIG_DIGITS[digit as usize][row],
+                    None if c == '.' && row + 1 == BIG_DIGIT_ROWS as usize => "█",
+                    None => " ",
+                })
+                .collect::<Vec<_>>();
+            Line::from(glyphs.join(" ")).alignment(Alignment::Center)
+        })
+        .collect()
+}
+
+/// A bar of beats with the current one lit, and a ticker showing how far into it playback is.
+fn beat_grid(beat: u64, phase: f64, width: u16, theme: Theme) -> Paragraph<'static> {
+    let cell = (width.saturating_sub(2) as usize / GRID_BEATS as usize).max(1);
+    let spans = (0..GRID_BEATS)
+        .map(|i| {
+            if i == beat % GRID_BEATS {
+                let lit = ((phase * cell as f64) as usize).min(cell);
+                Span::styled("█".repeat(cell - lit) + &"░".repeat(lit), theme.accent())
+            } else {
+                Span::raw("·".repeat(cell))
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // A light that flashes right on each beat, which is easier to check against the music out
+    // of the corner of an eye than the grid.
+    let flash = if phase < FLASH_FRACTION {
+        Span::styled(" ● ", theme.accent())
+    } else {
+        Span::raw(" ○ ")
+    };
+    Paragraph::new(Line::from(spans)).block(Block::default().borders(Borders::ALL).title(
+        Line::from(vec![Span::raw(format!("Beat {}", beat + 1)), flash]),
+    ))
+}
+
+/// What the tui is doing, for the status bar.
+#[derive(Clone, Copy)]
+enum Mode {
+    Playing,
+    Paused,
+    Notice,
+    Info,
+    Confirm,
+    Unsaved,
+    Manual,
+    Edit,
+    Jump,
+    Search,
+    History,
+    Browse,
+    Calibrating,
+    Help,
+    Disconnected,
+}
+
+impl Mode {
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Playing => "Playing",
+            Mode::Paused => "Paused",
+            Mode::Notice => "Notice",
+            Mode::Info => "Info",
+            Mode::Confirm => "Confirm",
+            Mode::Unsaved => "Unsaved",
+            Mode::Manual => "Manual",
+            Mode::Edit => "Edit tags",
+            Mode::Jump => "Jump",
+            Mode::Search => "Search",
+            Mode::History => "History",
+            Mode::Browse => "Add songs",
+            Mode::Calibrating => "Calibrating",
+            Mode::Help => "Help",
+            Mode::Disconnected => "Disconnected",
+        }
+    }
+
+    /// The keys most likely to be wanted next.
+    fn hints(self) -> &'static str {
+        match self {
+            Mode::Playing => "Space tap  Enter save  Up/Down change song  P pause  ? help  Q quit",
+            Mode::Paused => "P resume  G jump  Up/Down change song  ? help  Q quit",
+            Mode::Notice | Mode::Info | Mode::Help => "Any key to close",
+            Mode::Confirm => "Y save  N go back",
+            Mode::Unsaved => "Y save  N discard  Esc go back",
+            Mode::Manual => "Enter save  Backspace delete  Esc cancel",
+            Mode::Edit => "Up/Down pick  Enter save  Esc cancel",
+            Mode::Jump => "Enter jump  Backspace delete  Esc cancel",
+            Mode::Search => "Up/Down pick  Enter play  Esc cancel",
+            Mode::History => "Up/Down pick  Enter tap it again  Esc close",
+            Mode::Browse => "Enter open  Space add  Backspace up  Esc close",
+            Mode::Calibrating => "Space tap  Enter use  Esc cancel",
+            Mode::Disconnected => "R reconnect  Q quit",
+        }
+    }
+}
+
+/// Lists the session history down the right hand side, latest first.
+fn history_panel(
+    f: &mut Frame,
+    history: &[Entry],
+    inputs: &[Input],
+    selected: usize,
+    display: DisplayOptions,
+) {
+    let size = f.size();
+    // Leave the status bar showing.
+    let area = Rect {
+        height: size.height.saturating_sub(1),
+        ..Layout::horizontal([Constraint::Percentage(55), Constraint::Percentage(45)]).split(size)
+            [1]
+    };
+    let bpm = |bpm: Option<f64>| match bpm {
+        Some(bpm) => format!("{:.*}", display.precision, bpm),
+        None => "None".to_owned(),
+    };
+    let rows = history.iter().rev().map(|entry| {
+        let name = inputs
+            .iter()
+            .find(|input| input.order == entry.order)
+            .map(Input::name)
+            .unwrap_or_default();
+        Row::new(vec![
+            format!("{} ago", file::format_time(entry.at.elapsed())),
+            name,
+            format!("{} → {}", bpm(entry.old), bpm(entry.new)),
+        ])
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(10),
+            Constraint::Min(0),
+            Constraint::Length(15),
+        ],
+    )
+    .block(
+        display
+            .theme
+            .popup()
+            .borders(Borders::ALL)
+            .title(format!("History ({} saved)", history.len())),
+    )
+    .highlight_style(display.theme.selection());
+    let mut state = TableState::default();
+    state.select((!history.is_empty()).then_some(selected));
+    f.render_widget(Clear, area);
+    f.render_stateful_widget(table, area, &mut state);
+}
+
+/// A line along the bottom with what's going on and the keys for it.
+fn status_bar(f: &mut Frame, mode: Mode, input: &Input, theme: Theme) {
+    let size = f.size();
+    if size.height < 2 {
+        return;
+    }
+    let area = Rect::new(
+        size.x + 1,
+        size.bottom() - 1,
+        size.width.saturating_sub(2),
+        1,
+    );
+    let line = Line::from(vec![
+        Span::styled(mode.name(), theme.accent()),
+        Span::raw(" │ "),
+        Span::raw(input.name()),
+        Span::raw(" │ "),
+        Span::raw(mode.hints()),
+    ]);
+    f.render_widget(Paragraph::new(line), area);
+}
+
+/// Draws the song list and everything about the playing song, returning how many songs fit in
+/// the list at once.
+fn draw_ui(
+    f: &mut Frame,
+    inputs: &[Input],
+    table_state: &mut TableState,
+    bpm: Option<f64>,
+    bpms: &Bpms,
+    song: &Song,
+    display: DisplayOptions,
+) -> (usize, Option<Rect>) {
+    let DisplayOptions {
+        precision,
+        rounding,
+        range,
+        key_notation,
+        sort_by,
+        theme,
+        layout,
+        loading,
+        graphics,
+        dry_run,
+    } = display;
+
+    let beat = bpms
+        .avg()
+        .and_then(|bpm| song.beat(bpm, bpms.options.latency));
+    let mut panels = [
+        (Panel::Progress, true, 3),
+        (Panel::Waveform, song.waveform.is_some(), WAVEFORM_ROWS + 2),
+        (
+            Panel::Spectrogram,
+            song.spectrogram.is_some(),
+            analysis::SPECTROGRAM_BANDS.len() as u16 + 2,
+        ),
+        (Panel::BeatGrid, beat.is_some(), 3),
+        (Panel::Intervals, bpms.stats().is_some(), HISTOGRAM_ROWS + 2),
+    ]
+    .into_iter()
+    .filter(|(panel, ready, _)| *ready && !layout.hide.contains(panel))
+    .map(|(panel, _, height)| (panel, height))
+    .collect::<Vec<_>>();
+
+    // Leave the panels out one at a time on short terminals to make room for the song list, or
+    // the large readout in the compact layout, the beat grid last since it's the most use while
+    // tapping.
+    let height = f.size().height.saturating_sub(2);
+    let panels_height =
+        |panels: &[(Panel, u16)]| panels.iter().map(|(_, height)| height).sum::<u16>();
+    let table_height = |panels: &[(Panel, u16)]| match layout.table_height {
+        Some(percent) => height * percent / 100,
+        None => height.saturating_sub(READOUT_HEIGHT + panels_height(panels)),
+    };
+    let fits = |panels: &[(Panel, u16)]| {
+        if layout.compact {
+            height >= 1 + panels_height(panels) + BIG_READOUT_HEIGHT
+        } else {
+            table_height(panels) >= MIN_TABLE_HEIGHT
+        }
+    };
+    for dropped in [
+        Panel::Spectrogram,
+        Panel::Waveform,
+        Panel::Intervals,
+        Panel::Progress,
+    ] {
+        if fits(&panels) {
+            break;
+        }
+        panels.retain(|(panel, _)| *panel != dropped);
+    }
+    // If it still doesn't fit, only show the song that's playing.
+    let collapsed = layout.compact || table_height(&panels) < MIN_TABLE_HEIGHT;
+
+    let mut constraints = vec![match layout.table_height {
+        _ if collapsed => Constraint::Length(1),
+        Some(percent) => Constraint::Percentage(percent),
+        None => Constraint::Min(0),
+    }];
+    constraints.extend(panels.iter().map(|(_, height)| Constraint::Length(*height)));
+    // Whichever of the table and the readout doesn't have a set size takes up the slack.
+    constraints.push(match layout.table_height {
+        _ if layout.compact => Constraint::Min(READOUT_HEIGHT),
+        Some(_) if !collapsed => Constraint::Min(READOUT_HEIGHT),
+        _ => Constraint::Length(READOUT_HEIGHT),
+    });
+    let chunks = Layout::default()
+        .direction(Direction::Vertical)
+        .margin(1)
+        .constraints(constraints)
+        .split(f.size());
+
+    // Cover art goes to the right of the song list, as big as it can be without crowding it.
+    let cover = song
+        .cover
+        .as_ref()
+        .filter(|_| !collapsed && !layout.hide.contains(&Panel::Cover));
+    let cover_width = (chunks[0].height.saturating_sub(2) * 2 + 2).min(chunks[0].width / 3);
+    let (table_area, cover_area) = match cover {
+        Some(_) if cover_width >= MIN_COVER_WIDTH => {
+            let [table_area, cover_area] =
+                Layout::horizontal([Constraint::Min(0), Constraint::Length(cover_width)])
+                    .areas(chunks[0]);
+            (table_area, Some(cover_area))
+        }
+        _ => (chunks[0], None),
+    };
+
+    let columns = [
+        Constraint::Percentage(60),
+        Constraint::Percentage(22),
+        Constraint::Percentage(8),
+        Constraint::Percentage(10),
+    ];
+    // Laid out the same way the table does it, to know how much of each name will fit.
+    let name_width = Layout::horizontal(columns)
+        .flex(Flex::Start)
+        .spacing(1)
+        .split(table_area.inner(&Margin::new(1, 1)))[0]
+        .width as usize;
+    let input_table = inputs
+        .iter()
+        .map(|input| {
+            let bpm_str = match input.track.bpm() {
+                // Flag BPMs that should be checked by ear.
+                Some(bpm) if input.confidence.is_some_and(|c| c < LOW_CONFIDENCE) => {
+                    format!("{:.*} ?", display.precision, bpm)
+                }
+                Some(bpm) => format!("{:.*}", display.precision, bpm),
+                None => "None".to_owned(),
+            };
+
+            let mut marker = String::new();
+            if input.error.is_some() {
+                marker.push('✗');
+            }
+            if input.marked {
+                marker.push('+');
+            }
+            if input.flagged {
+                marker.push('⚑');
+            }
+            if !marker.is_empty() {
+                marker.push(' ');
+            }
+            let row = Row::new(vec![
+                format!(
+                    "{}{}",
+                    marker,
+                    truncate_middle(&input.name(), name_width.saturating_sub(marker.width()))
+                ),
+                input.track.metadata().album.clone().unwrap_or_default(),
+                input.duration.map(file::format_time).unwrap_or_default(),
+                bpm_str,
+            ]);
+            if input.marked {
+                row.style(theme.accent())
+            } else {
+                row
+            }
+        })
+        .collect::<Table>()
+        .widths(columns)
+        .block(Block::default().borders(Borders::ALL).title(match loading {
+            Some((read, total)) => {
+                format!("Sorted {} (reading {} of {})", sort_by.name(), read, total)
+            }
+            None => format!("Sorted {}", sort_by.name()),
+        }))
+        .highlight_style(theme.selection());
+
+    let page = if collapsed {
+        let selected = &inputs[table_state.selected().unwrap()];
+        f.render_widget(
+            Paragraph::new(Line::styled(
+                truncate_middle(&selected.name(), chunks[0].width.into()),
+                theme.selection(),
+            )),
+            chunks[0],
+        );
+        1
+    } else {
+        // The table scrolls itself to keep the selected song in view.
+        f.render_stateful_widget(input_table, table_area, table_state);
+        table_area.height.saturating_sub(2).max(1) as usize
+    };
+    if !collapsed && inputs.len() > page {
+        let mut scrollbar_state =
+            ScrollbarState::new(inputs.len().saturating_sub(page)).position(table_state.offset());
+        f.render_stateful_widget(
+            Scrollbar::new(ScrollbarOrientation::VerticalRight),
+            table_area.inner(&Margin::new(0, 1)),
+            &mut scrollbar_state,
+        );
+    }
+
+    // Show what's already tagged alongside, so overwriting it is a conscious decision.
+    let label = match inputs[table_state.selected().unwrap()].track.bpm() {
+        Some(current) => format!("BPM: {:.*} →", display.precision, current),
+        None => "BPM:".to_owned(),
+    };
+    let mut readout = match (bpm, song.estimate) {
+        (Some(bpm), _) => format!(
+            "{} {:.*} ({})",
+            label,
+            precision,
+            bpm,
+            rounding.to_possible_value().unwrap().get_name()
+        ),
+        (None, Some(estimate)) => format!(
+            "{} {:.*} (estimated, {:.0}% confidence)",
+            label,
+            precision,
+            estimate.bpm,
+            estimate.confidence * 100.0
+        ),
+        (None, None) if song.estimating.is_some() => "Estimating...".to_owned(),
+        (None, None) => String::new(),
+    };
+    if let Some(key) = song.key {
+        readout += &format!("  Key: {}", key.format(key_notation));
+    }
+    match &song.identified {
+        Some(Ok(Some(recording))) => readout += &format!("  Sounds like: {}", recording.name()),
+        Some(Ok(None)) => readout += "  Not on AcoustID",
+        Some(Err(e)) => readout += &format!("  Couldn't look up: {}", e),
+        None if song.identifying.is_some() => readout += "  Looking up...",
+        None => {}
+    }
+    match &song.fetched {
+        Some(Ok(Some(bpm))) => readout += &format!("  Online BPM: {:.*}", precision, bpm),
+        Some(Ok(None)) => readout += "  Not in the BPM database",
+        Some(Err(e)) => readout += &format!("  Couldn't fetch BPM: {}", e),
+        None if song.fetching.is_some() => readout += "  Fetching BPM...",
+        None => {}
+    }
+    if bpm.is_some_and(|bpm| !range.contains(bpm)) {
+        readout += &format!("  Outside {}!", range);
+    }
+    if let Some(stats) = bpms.stats() {
+        let taps = if bpms.ready() {
+            stats.taps.to_string()
+        } else {
+            format!("{}/{}", stats.taps, bpms.options.min_taps)
+        };
+        readout += &format!("  Taps: {}", taps);
+        // Each tap on its own, so a flubbed one stands out against the average.
+        if let Some(last) = bpms.last() {
+            readout += &format!("  Last: {:.*}", precision.max(1), last);
+        }
+        readout += &format!(
+            "  Std dev: {:.0}ms  Range: {:.*}-{:.*}  Confidence: {:?}",
+            stats.std_dev, precision, stats.min, precision, stats.max, stats.confidence
+        );
+        if let Some(drift) = stats.drift {
+            readout += &format!(
+                "  Tempo drifting {} ({:+.0}%)",
+                if drift > 0.0 { "faster" } else { "slower" },
+                drift * 100.0
+            );
+        }
+    }
+
+    if !song.sections.is_empty() {
+        readout += &format!(
+            "  Sections: {}",
+            file::format_sections(&song.sections, precision)
+        );
+    }
+    match (song.looping, song.loop_in) {
+        (Some((start, end)), _) => {
+            readout += &format!(
+                "  Loop: {}-{}",
+                file::format_time(start),
+                file::format_time(end)
+            )
+        }
+        (None, Some(start)) => readout += &format!("  Loop from: {}", file::format_time(start)),
+        (None, None) => {}
+    }
+    if song.sink.volume() == 0.0 {
+        readout += "  Muted";
+    } else {
+        readout += &format!("  Volume: {:.0}%", song.sink.volume() * 100.0);
+    }
+    if let Some(gain) = song.gain {
+        readout += &format!("  Gain: {:+.1} dB", gain);
+    }
+    if song.sink.speed() != 1.0 {
+        readout += &format!("  Speed: {:.0}%", song.sink.speed() * 100.0);
+    }
+
+    let readout_block = Block::default()
+        .borders(Borders::ALL)
+        .border_style(if song.flashing() {
+            theme.accent()
+        } else {
+            Style::default()
+        })
+        .title({
+            let title = match bpms.options.beats_per_tap {
+                _ if song.paused() => "Paused, press P to resume".to_owned(),
+                1 => "Tap Space for BPM! (? for help)".to_owned(),
+                beats => format!("Tap Space every {} beats for BPM! (? for help)", beats),
+            };
+            if dry_run {
+                format!("Dry run, nothing is saved │ {}", title)
+            } else {
+                title
+            }
+        })
+        .title_alignment(Alignment::Center);
+    let bpm_part = Paragraph::new(vec![Line::from(readout)]).wrap(Wrap { trim: true });
+
+    for ((panel, _), area) in panels.iter().zip(chunks[1..].iter().copied()) {
+        match panel {
+            Panel::Progress => {
+                let playback = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Percentage(75), Constraint::Percentage(25)])
+                    .split(area);
+                f.render_widget(progress(song), playback[0]);
+                f.render_widget(level_meter(song, playback[1].width, theme), playback[1]);
+            }
+            Panel::Waveform => {
+                if let Some(samples) = &song.waveform {
+                    f.render_widget(waveform(song, samples, area.width, theme), area);
+                }
+            }
+            Panel::Spectrogram => {
+                if let Some(spectra) = &song.spectrogram {
+                    f.render_widget(spectrogram(song, spectra, area.width, theme), area);
+                }
+            }
+            Panel::BeatGrid => {
+                if let Some((beat, phase)) = beat {
+                    f.render_widget(beat_grid(beat, phase, area.width, theme), area);
+                }
+            }
+            Panel::Intervals => {
+                f.render_widget(interval_histogram(bpms, area.width, theme), area);
+            }
+            Panel::Cover => {}
+        }
+    }
+    let readout_area = chunks[chunks.len() - 1];
+    let mut text_area = readout_block.inner(readout_area);
+    f.render_widget(readout_block, readout_area);
+    let big = match (bpm, song.estimate) {
+        (Some(bpm), _) => Some(bpm),
+        (None, Some(estimate)) => Some(estimate.bpm),
+        (None, None) => None,
+    };
+    if let Some(big) = big.filter(|_| layout.compact && text_area.height > BIG_DIGIT_ROWS + 1) {
+        let [digits_area, rest] =
+            Layout::vertical([Constraint::Length(BIG_DIGIT_ROWS + 1), Constraint::Min(0)])
+                .areas(text_area);
+        f.render_widget(
+            Paragraph::new(big_number(&format!("{:.*}", precision, big))).style(theme.accent()),
+            digits_area,
+        );
+        text_area = rest;
+    }
+    f.render_widget(bpm_part, text_area);
+
+    // Terminal graphics are drawn over the top afterwards, into the space left for them.
+    let mut overlay = None;
+    if let (Some(cover), Some(area)) = (cover, cover_area) {
+        let block = Block::default().borders(Borders::ALL).title("Cover");
+        let inner = cover.fit(block.inner(area));
+        f.render_widget(block, area);
+        if graphics.escaped() {
+            overlay = Some(inner);
+        } else {
+            f.render_widget(Paragraph::new(cover.blocks(inner)), inner);
+        }
+    }
+    (page, overlay)
+}
+
+/// Estimates the BPM and key of each input and saves them, without the tui.
+fn analyze(
+    inputs: &[String],
+    dry_run: bool,
+    display: DisplayOptions,
+    write_options: &file::WriteOptions,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let (mut saved, mut unclear, mut failed) = (0, 0, 0);
+    for input in inputs {
+        let result =
+            analysis::estimate(input, display.range.min, display.range.max).and_then(|estimate| {
+                let bpm = estimate
+                    .tempo
+                    .map(|tempo| (display.rounding.apply(tempo.bpm), tempo));
+                if !dry_run && (bpm.is_some() || estimate.key.is_some()) {
+                    let format = file::Format::detect(input)?
+                        .ok_or_else(|| anyhow::anyhow!("{}: Unsupported file type", input))?;
+                    let mut track = file::Track::new(input.clone(), format, write_options.clone())?;
+                    match (bpm, estimate.key) {
+                        (Some((bpm, tempo)), key) => {
+                            file::Music::set_beat_grid(&mut track, bpm, tempo.offset, key)?
+                        }
+                        (None, Some(key)) => file::Music::set_key(&mut track, key)?,
+                        (None, None) => {}
+                    }
+                }
+                Ok((bpm, estimate.key))
+            });
+
+        let key = |key: Option<file::Key>| match key {
+            Some(key) => format!(", key {}", key.format(display.key_notation)),
+            None => String::new(),
+        };
+        match result {
+            Ok((Some((bpm, tempo)), found_key)) => {
+                saved += 1;
+                println!(
+                    "{}: {:.*} ({:.0}% confidence, downbeat at {}ms){}{}",
+                    input,
+                    display.precision,
+                    bpm,
+                    tempo.confidence * 100.0,
+                    tempo.offset.as_millis(),
+                    key(found_key),
+                    if tempo.confidence < LOW_CONFIDENCE {
+                        "  Check this one by ear"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            Ok((None, found_key)) => {
+                unclear += 1;
+                println!("{}: No clear beat{}", input, key(found_key));
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    println!(
+        "{} {}, {} without a clear beat, {} failed",
+        saved,
+        if dry_run { "estimated" } else { "saved" },
+        unclear,
+        failed
+    );
+
+    Ok(exit_code(failed > 0, false))
+}
+
+/// What crabtap exits with, for scripts.
+const EXIT_CODES: &str = "Exit status:
+  0  Every song was done
+  1  Something went wrong that stopped crabtap, like a bad argument
+  2  Some songs couldn't be read or saved
+  3  Quit with songs still left without a BPM";
+
+/// Songs that couldn't be read or saved take precedence over quitting with some left to do.
+/// Errors that stop crabtap altogether are returned from main, which exits with 1.
+fn exit_code(failed: bool, unfinished: bool) -> ExitCode {
+    if failed {
+        ExitCode::from(2)
+    } else if unfinished {
+        ExitCode::from(3)
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Adds the lines of standard input to the end of the inputs with --stdin or a `-` among them, for
+/// piping in a list from crabtapfilter or find.  The tui reads keys from the terminal
+/// itself, so it still works with standard input used up.
+fn read_inputs(inputs: Vec<String>, stdin: bool) -> io::Result<Vec<String>> {
+    let stdin = stdin || inputs.iter().any(|input| input == "-");
+    let mut inputs = inputs
+        .into_iter()
+        .filter(|input| input != "-")
+        .collect::<Vec<_>>();
+    if stdin {
+        for line in io::stdin().lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                inputs.push(line);
+            }
+        }
+    }
+    Ok(inputs)
+}
+
+/// Puts the songs in a random order.  Std seeds its hash maps randomly, which is random enough
+/// for this.
+fn shuffle(inputs: &mut [String]) {
+    let state = RandomState::new();
+    for i in (1..inputs.len()).rev() {
+        let j = state.hash_one(i) as usize % (i + 1);
+        inputs.swap(i, j);
+    }
+}
+
+/// Prints each input's format, BPM, key and the rest of what's shown in the song list.
+fn show(
+    inputs: Vec<String>,
+    format: Listing,
+    display: DisplayOptions,
+    write_options: &file::WriteOptions,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    let total = inputs.len();
+    let tracks = inputs
+        .into_iter()
+        .filter_map(|input| match read_track(input, write_options) {
+            Ok(track) => Some(track),
+            Err(e) => {
+                eprintln!("{}", e);
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    if let Listing::Json = format {
+        for track in &tracks {
+            let track: &dyn file::Music = track;
+            let metadata = track.metadata();
+            println!(
+                "{{\"path\":{},\"format\":{},\"bpm\":{},\"key\":{},\"artist\":{},\"title\":{},\"album\":{},\"genre\":{}}}",
+                json::string(track.path()),
+                json::string(track.format().name()),
+                json::number(track.bpm()),
+                json::optional(track.key()),
+                json::optional(metadata.artist.as_deref()),
+                json::optional(metadata.title.as_deref()),
+                json::optional(metadata.album.as_deref()),
+                json::optional(metadata.genre.as_deref()),
+            );
+        }
+        return Ok(exit_code(tracks.len() < total, false));
+    }
+
+    let header = [
+        "Path", "Format", "BPM", "Key", "Artist", "Title", "Album", "Genre",
+    ]
+    .map(str::to_owned);
+    let rows = tracks
+        .iter()
+        .map(|track| {
+            let track: &dyn file::Music = track;
+            let metadata = track.metadata();
+            let text = |text: Option<&str>| text.unwrap_or("-").to_owned();
+            [
+                track.path().to_owned(),
+                track.format().name().to_owned(),
+                track.bpm().map_or_else(
+                    || "-".to_owned(),
+                    |bpm| format!("{:.*}", display.precision, bpm),
+                ),
+                text(track.key()),
+                text(metadata.artist.as_deref()),
+                text(metadata.title.as_deref()),
+                text(metadata.album.as_deref()),
+                text(metadata.genre.as_deref()),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let mut widths = [0; 8];
+    for row in std::iter::once(&header).chain(&rows) {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.width());
+        }
+    }
+    for row in std::iter::once(&header).chain(&rows) {
+        let line = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{}{}", cell, " ".repeat(width - cell.width())))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+
+    Ok(exit_code(tracks.len() < total, false))
+}
+
+/// Saves the same BPM to each input, without the tui.
+fn set(
+    bpm: f64,
+    inputs: Vec<String>,
+    display: DisplayOptions,
+    write_options: &file::WriteOptions,
+) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    if !bpm.is_finite() || bpm <= 0.0 {
+        return Err(anyhow::anyhow!("Expected a positive BPM").into());
+    }
+    let (mut saved, mut failed) = (0, 0);
+    for input in inputs {
+        match read_track(input, write_options)
+            .and_then(|mut track| file::Music::set_bpm(&mut track, bpm, None).map(|()| track))
+        {
+            Ok(track) => {
+                saved += 1;
+                println!(
+                    "{}: {:.*}",
+                    file::Music::path(&track),
+                    display.precision,
+                    bpm
+                );
+            }
+            Err(e) => {
+                failed += 1;
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    println!(
+        "{} {}, {} failed",
+        saved,
+        if write_options.dry_run {
+            "would be saved"
+        } else {
+            "saved"
+        },
+        failed
+    );
+
+    Ok(exit_code(failed > 0, false))
+}
+
+fn main() -> Result<ExitCode, Box<dyn std::error::Error>> {
+    // Clap exits with 2 for bad arguments, which means something else here.
+    let args = match Args::try_parse() {
+        Ok(args) => args,
+        Err(e) => {
+            e.print()?;
+            return Ok(if e.use_stderr() {
+                ExitCode::FAILURE
+            } else {
+                ExitCode::SUCCESS
+            });
+        }
+    };
+    let config = config::Config::load()?;
+    let mut display = DisplayOptions {
+        precision: args.precision.or(config.precision).unwrap_or(0),
+        rounding: args.rounding.or(config.rounding).unwrap_or_default(),
+        range: args.bpm_range.or(config.bpm_range).unwrap_or(BpmRange {
+            min: 40.0,
+            max: 250.0,
+        }),
+        key_notation: args
+            .key_notation
+            .or(config.key_notation)
+            .unwrap_or_default(),
+        sort_by: args.sort.or(config.sort).unwrap_or_default(),
+        theme: args.theme.or(config.theme).unwrap_or_default(),
+        layout: LayoutOptions {
+            table_height: args.table_height.or(config.table_height),
+            compact: args.compact || config.compact.unwrap_or(false),
+            hide: args
+                .hide
+                .or(config.hide)
+                .unwrap_or_default()
+                .into_iter()
+                .collect(),
+        },
+        loading: None,
+        dry_run: args.dry_run,
+        graphics: args
+            .graphics
+            .or(config.graphics)
+            .unwrap_or_default()
+            .detect(),
+    };
+    let tap_options = TapOptions {
+        window: args.num_avg.or(config.num_avg).unwrap_or(Window::Taps(10)),
+        estimator: args.estimator.or(config.estimator).unwrap_or_default(),
+        threshold: args
+            .outlier_threshold
+            .or(config.outlier_threshold)
+            .unwrap_or(10.0)
+            / 100.0,
+        beats_per_tap: args.beats_per_tap.or(config.beats_per_tap).unwrap_or(1),
+        min_taps: args.min_taps.or(config.min_taps).unwrap_or(2),
+        auto_confirm: args.auto_confirm.or(config.auto_confirm),
+        auto_tolerance: args.auto_tolerance.or(config.auto_tolerance).unwrap_or(2.0) / 100.0,
+        latency: args.latency.or(config.latency).unwrap_or(0.0),
+    };
+    let write_options = file::WriteOptions {
+        precision: display.precision,
+        id3_version: args.id3_version.or(config.id3_version).unwrap_or_default(),
+        mirror_ape: args.mirror_ape || config.mirror_ape.unwrap_or(false),
+        key_notation: display.key_notation,
+        dry_run: args.dry_run,
+        // Tag fields are named statically everywhere else, and this one lasts the whole session.
+        bpm_tag: args
+            .bpm_tag
+            .or(config.bpm_tag)
+            .map(|name| &*Box::leak(name.into_boxed_str())),
+        bpm_tag_only: args.bpm_tag_only || config.bpm_tag_only.unwrap_or(false),
+        write_comment: args.write_comment || config.write_comment.unwrap_or(false),
+        backup: match args.backup_dir.or(config.backup_dir) {
+            Some(dir) => Some(file::CopyTo::Dir(dir)),
+            None if args.backup || config.backup.unwrap_or(false) => Some(file::CopyTo::Sibling),
+            None => None,
+        },
+    };
+    match args.command {
+        Some(Command::Analyze { inputs, dry_run }) => {
+            let inputs = file::expand_inputs(read_inputs(inputs, false)?);
+            return analyze(&inputs, dry_run || args.dry_run, display, &write_options);
+        }
+        Some(Command::Show { inputs, format }) => {
+            let inputs = file::expand_inputs(read_inputs(inputs, false)?);
+            return show(inputs, format, display, &write_options);
+        }
+        Some(Command::Set { bpm, inputs }) => {
+            let inputs = file::expand_inputs(read_inputs(inputs, false)?);
+            return set(bpm, inputs, display, &write_options);
+        }
+        None => {}
+    }
+
+    let mut inputs = file::expand_inputs(read_inputs(args.inputs, args.stdin)?);
+    if args.shuffle || config.shuffle.unwrap_or(false) {
+        shuffle(&mut inputs);
+    }
+    let resumed = if args.resume {
+        session::Session::load()?.ok_or_else(|| anyhow::anyhow!("There's no session to resume"))?
+    } else {
+        session::Session::default()
+    };
+    if !resumed.songs.is_empty() {
+        let given = inputs;
+        inputs = resumed.songs.iter().map(|(_, path)| path.clone()).collect();
+        let known = inputs.iter().cloned().collect::<HashSet<_>>();
+        inputs.extend(given.into_iter().filter(|input| !known.contains(input)));
+    }
+    // Where to start playing from, which is the first song unless resuming.
+    let start = resumed
+        .playing
+        .and_then(|playing| inputs.iter().position(|input| *input == playing))
+        .unwrap_or(0);
+    let was_flagged = resumed
+        .songs
+        .into_iter()
+        .filter(|(status, _)| *status == session::Status::Flagged)
+        .map(|(_, path)| path)
+        .collect::<HashSet<_>>();
+    let total = inputs.len();
+    if total == 0 {
+        return Ok(ExitCode::SUCCESS);
+    }
+    let lookup = online::Lookup {
+        acoustid_key: args.acoustid_key.or(config.acoustid_key),
+        bpm_api: args.bpm_api.or(config.bpm_api),
+    };
+    if !lookup.is_empty() {
+        online::check_available()?;
+    }
+    // Every song, including ones that haven't been read by the time the session ends.
+    let queue = inputs.clone();
+    let loading = load_all(inputs, write_options.clone());
+    let (measure, paths) = mpsc::channel();
+    let measuring = measure_all(paths);
+    let mut read = 0;
+    // Songs added from the browser go after every song given on the command line.
+    let mut next_order = total;
+
+    let mut terminal = RAIITerminal::new()?;
+    // Only asked once in raw mode, and only when the theme needs it, since it means waiting on
+    // the terminal.
+    display.theme = display.theme.resolve(theme::light_background);
+    // Songs that couldn't be read, and how many of them have been told about.
+    let mut load_errors = Vec::new();
+    let mut reported = 0;
+    let skip_tagged = args.skip_tagged || config.skip_tagged.unwrap_or(false);
+    let tagged = |track: &file::Track| skip_tagged && file::Music::bpm(track).is_some();
+    let new_input = |track, order| {
+        let mut input = Input::new(track, order);
+        input.flagged = was_flagged.contains(input.track.path());
+        input
+    };
+    // Only the song to start from and the ones before it are needed to start playing.
+    let mut inputs = Vec::new();
+    loop {
+        terminal.draw(|f| {
+            let popup = Paragraph::new("Reading tags...")
+                .block(display.theme.popup().borders(Borders::ALL))
+                .alignment(Alignment::Center);
+            let area = centered_rect(30, 10, f.size());
+            f.render_widget(popup, area);
+        })?;
+        match loading.recv_timeout(TICK) {
+            Ok((_, Ok(track))) if tagged(&track) => read += 1,
+            Ok((order, Ok(track))) => {
+                read += 1;
+                let input = new_input(track, order);
+                let _ = measure.send((order, input.track.path().to_owned()));
+                inputs.push(input);
+                if order >= start {
+                    break;
+                }
+            }
+            Ok((_, Err(e))) => {
+                read += 1;
+                load_errors.push(e.to_string());
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            // The song to start from couldn't be read, so start from the one before.
+            Err(mpsc::RecvTimeoutError::Disconnected) if !inputs.is_empty() => break,
+            Err(mpsc::RecvTimeoutError::Disconnected) if skip_tagged && load_errors.is_empty() => {
+                drop(terminal);
+                println!("Every song already has a BPM");
+                return Ok(ExitCode::SUCCESS);
+            }
+            // None of them could be read.
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                return Err(anyhow::anyhow!("{}", load_errors.join("\n")).into());
+            }
+        }
+    }
+    let first = inputs.last().unwrap().order;
+    display.sort_by.sort(&mut inputs);
+    let first = inputs
+        .iter()
+        .position(|input| input.order == first)
+        .unwrap();
+    display.loading = (read < total).then_some((read, total));
+
+    let device = args.device.or(config.device);
+    let (mut _stream, stream_handle) = open_output(device.as_deref())?;
+    let estimate = args.estimate || config.estimate.unwrap_or(false);
+    let volume = args.volume.or(config.volume).unwrap_or(100);
+    let mut audio_stream = AudioStream::new(
+        stream_handle,
+        estimate.then_some(display.range),
+        volume,
+        args.start_at.or(config.start_at),
+        args.preview
+            .or(config.preview)
+            .map(|preview| Duration::from_secs(preview.into())),
+        [Panel::Waveform]
+            .into_iter()
+            .chain(
+                (args.spectrogram || config.spectrogram.unwrap_or(false))
+                    .then_some(Panel::Spectrogram),
+            )
+            .filter(|panel| !display.layout.hide.contains(panel))
+            .collect(),
+        args.replay_gain || config.replay_gain.unwrap_or(false),
+    )
+    .with_lookup(lookup);
+    let mut table_state = TableState::default();
+    table_state.select(Some(first));
+    let mut song = audio_stream.play(&inputs, first)?;
+    let mut _click = None;
+    let confirm = args.confirm || config.confirm.unwrap_or(false);
+    let max_time = args.max_time.or(config.max_time).unwrap_or(5);
+    let idle_reset = args.idle_reset.or(config.idle_reset).unwrap_or(4.0);
+    let mut bpms = Bpms::new(tap_options);
+
+    let mut state = State::Playing;
+    // Set by each tap, so a declined automatic save isn't offered again until the next one.
+    let mut tapped = false;
+    let mut click_track = false;
+    // The songs changed by the last save.
+    let mut undo = Vec::new();
+    let mut history = Vec::new();
+    // The count typed so far for the next command.
+    let mut count: Option<usize> = None;
+    // Whether G was just pressed, which waits to see what comes next.
+    let mut jumping = false;
+    // Where Shift+V marks songs from.
+    let mut marked_last = None;
+    let mut browse_dir = None;
+    // How many songs fit in the list, as of the last draw.
+    let mut page = 1;
+    // Where the cover art should be drawn over the tui, and the song and spot it was last drawn
+    // for.
+    let mut overlay = None;
+    let mut overlaid: Option<(usize, Rect)> = None;
+
+    loop {
+        // Pick up whatever's been worked out in the background, whatever's showing.
+        let before = inputs.len();
+        for (order, track) in loading.try_iter() {
+            read += 1;
+            match track {
+                Ok(track) if tagged(&track) => {}
+                Ok(track) => {
+                    let input = new_input(track, order);
+                    let _ = measure.send((order, input.track.path().to_owned()));
+                    inputs.push(input);
+                }
+                Err(e) => load_errors.push(e.to_string()),
+            }
+        }
+        if inputs.len() > before {
+            // Keep the playing song selected wherever the new songs sort to.
+            let playing = inputs[table_state.selected().unwrap()].order;
+            display.sort_by.sort(&mut inputs);
+            table_state.select(inputs.iter().position(|input| input.order == playing));
+        }
+        display.loading = (read < total).then_some((read, total));
+        for (order, duration) in measuring.try_iter() {
+            if let Some(input) = inputs.iter_mut().find(|input| input.order == order) {
+                input.duration = Some(duration);
+            }
+        }
+        song.poll_estimate();
+        song.poll_duration();
+        song.poll_waveform();
+        song.poll_spectrogram();
+        song.poll_cover();
+        song.poll_identified();
+        song.poll_fetched();
+        // Images drawn over the tui would end up on top of popups.
+        if overlaid.is_some() && !matches!(state, State::Playing) {
+            terminal.overlay(0, 0, display.graphics.clear())?;
+            terminal.clear()?;
+            overlaid = None;
+        }
+
+        match state {
+            State::Playing => {
+                if let Some(error) = song.error.take() {
+                    let input = &mut inputs[table_state.selected().unwrap()];
+                    state = State::Notice {
+                        message: format!("Couldn't play {}: {}", input.name(), error),
+                    };
+                    input.error = Some(error);
+                    continue;
+                }
+                if load_errors.len() > reported {
+                    let errors = load_errors[reported..].to_vec();
+                    reported = load_errors.len();
+                    state = State::Notice {
+                        message: format!("Couldn't read {}", summarize(errors).unwrap()),
+                    };
+                    continue;
+                }
+                // Playing carries on while the song is analyzed, and it's offered once it's done.
+                if song.analyze && song.estimating.is_none() {
+                    song.analyze = false;
+                    state = match (song.estimate, song.unestimated.take()) {
+                        (Some(tempo), _) => State::Finished {
+                            bpm: Some(display.rounding.apply(tempo.bpm)),
+                            estimate: Some(tempo),
+                        },
+                        (None, Some(message)) => State::Notice { message },
+                        (None, None) => State::Notice {
+                            message: "No clear beat found".to_owned(),
+                        },
+                    };
+                    continue;
+                }
+                terminal.draw(|f| {
+                    (page, overlay) = draw_ui(
+                        f,
+                        &inputs,
+                        &mut table_state,
+                        bpms.avg().map(|bpm| display.rounding.apply(bpm)),
+                        &bpms,
+                        &song,
+                        display,
+                    );
+                    status_bar(
+                        f,
+                        if song.paused() {
+                            Mode::Paused
+                        } else {
+                            Mode::Playing
+                        },
+                        &inputs[table_state.selected().unwrap()],
+                        display.theme,
+                    );
+                })?;
+
+                // The terminal keeps the cover art until it's told otherwise, so it's only drawn
+                // when it moves or the song changes.
+                let wanted =
+                    overlay.map(|area| (inputs[table_state.selected().unwrap()].order, area));
+                if wanted != overlaid {
+                    if overlaid.is_some() {
+                        // Take the old one down first, by redrawing whatever it covered.
+                        terminal.overlay(0, 0, display.graphics.clear())?;
+                        terminal.clear()?;
+                        overlaid = None;
+                        continue;
+                    }
+                    if let (Some((_, area)), Some(cover)) = (wanted, &song.cover) {
+                        terminal.overlay(area.x, area.y, &cover.escape(display.graphics, area))?;
+                    }
+                    overlaid = wanted;
+                }
+
+                song.sync_click(bpms.avg().filter(|_| click_track), &audio_stream)?;
+                if !song.paused() && song.levels.stalled() {
+                    state = State::Disconnected { error: None };
+                    continue;
+                }
+                let command = if std::mem::take(&mut tapped) && bpms.locked() {
+                    PlayCommands::Confirm
+                } else {
+                    // Redraw more often to keep the beat grid moving between taps.
+                    let tick = if song.anchor.is_some() && bpms.avg().is_some() || song.flashing() {
+                        FAST_TICK
+                    } else {
+                        TICK
+                    };
+                    match poll_keypress(play_keys, tick)? {
+                        Some(command) => command,
+                        None => continue,
+                    }
+                };
+
+                // A second G goes to the first song, like gg in vim, and a digit starts the time
+                // to jump to.  Anything else drops the G.
+                let command = match (std::mem::take(&mut jumping), command) {
+                    (false, command) => command,
+                    (true, PlayCommands::Jump) => PlayCommands::Top,
+                    (true, PlayCommands::Count(digit)) => {
+                        count = None;
+                        state = State::Jump {
+                            time: digit.to_string(),
+                        };
+                        continue;
+                    }
+                    (true, _) => {
+                        count = None;
+                        continue;
+                    }
+                };
+
+                // Counts typed before a command repeat it, like in vim.
+                let repeat = match command {
+                    PlayCommands::Count(digit) => {
+                        count = Some(count.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+                        continue;
+                    }
+                    _ => count.take(),
+                };
+                match command {
+                    PlayCommands::Count(_) => {}
+                    PlayCommands::Quit if bpms.ready() => {
+                        state = State::Unsaved {
+                            leaving: Leaving::Quit,
+                        };
+                    }
+                    PlayCommands::Quit => {
+                        break;
+                    }
+                    PlayCommands::Confirm => {
+                        let (bpm, estimate) = match (bpms.avg(), song.estimate) {
+                            (Some(_), _) => (display.tapped(&bpms), None),
+                            // Nothing was tapped, so go with the estimate.
+                            (None, Some(tempo)) => {
+                                (Some(display.rounding.apply(tempo.bpm)), Some(tempo))
+                            }
+                            (None, None) => (None, None),
+                        };
+                        if bpm.is_none() && song.sections.is_empty() {
+                            continue;
+                        }
+                        if confirm || bpm.is_some_and(|bpm| !display.range.contains(bpm)) {
+                            state = State::Finished { bpm, estimate };
+                        } else {
+                            let selected = table_state.selected().unwrap();
+                            let (saved, failed) =
+                                song.save(&mut audio_stream, &mut inputs, selected, bpm, estimate);
+                            record(&mut history, &inputs, &saved);
+                            undo = saved;
+                            if let Some(message) = failed {
+                                state = State::Notice { message };
+                            }
+                            // Stay on a song that couldn't be saved.
+                            if inputs[selected].error.is_some() {
+                                continue;
+                            }
+                            let input_idx = (selected + 1) % inputs.len();
+                            table_state.select(Some(input_idx));
+                            song = audio_stream.play(&inputs, input_idx)?;
+                            bpms.reset();
+                        }
+                    }
+                    PlayCommands::Restart => {
+                        song = audio_stream.play(&inputs, table_state.selected().unwrap())?;
+                        bpms.reset();
+                    }
+                    // Taps can't line up with the music while it's stopped.
+                    PlayCommands::Tap if song.paused() => {}
+                    PlayCommands::Tap => {
+                        // A constant offset doesn't change the intervals, but it keeps tap times in
+                        // line with when the beat actually came out of the speakers.
+                        let now = compensate(Instant::now(), bpms.options.latency);
+                        let mut pushed = false;
+                        if let Some(last_press_at) = song.last_press_at {
+                            // Slowing the song down stretches the taps out as well, so measure in
+                            // song time to get the song's own BPM.
+                            let diff = song.song_time(now.duration_since(last_press_at));
+                            let millis = diff.as_secs_f64() * 1000.0;
+                            if song.lap(last_press_at) != song.lap(now) {
+                                // Going back to the top of the loop skips part of a beat.
+                            } else if idle_reset > 0.0
+                                && bpms
+                                    .beat_period()
+                                    .is_some_and(|period| millis > period * idle_reset)
+                            {
+                                // The first interval after a pause is meaningless, so start over
+                                // from this tap.
+                                bpms.clear();
+                            } else if diff.as_secs() < max_time.into() {
+                                bpms.push(60000.0 / millis);
+                                pushed = true;
+                            }
+                        }
+                        if !pushed {
+                            song.anchor = Some(now);
+                        }
+                        song.last_press_at = Some(now);
+                        song.tapped_at = Some(Instant::now());
+                        tapped = true;
+                    }
+                    PlayCommands::Up | PlayCommands::Down => {
+                        let selected = table_state.selected().unwrap();
+                        let steps = repeat.unwrap_or(1) % inputs.len();
+                        let input_idx = if matches!(command, PlayCommands::Up) {
+                            (selected + inputs.len() - steps) % inputs.len()
+                        } else {
+                            (selected + steps) % inputs.len()
+                        };
+                        if input_idx == selected {
+                            continue;
+                        }
+                        if bpms.ready() {
+                            state = State::Unsaved {
+                                leaving: Leaving::Song(inputs[input_idx].order),
+                            };
+                            continue;
+                        }
+
+                        table_state.select(Some(input_idx));
+                        song = audio_stream.play(&inputs, input_idx)?;
+                        bpms.reset();
+                    }
+                    PlayCommands::PageUp
+                    | PlayCommands::PageDown
+                    | PlayCommands::HalfPageUp
+                    | PlayCommands::HalfPageDown
+                    | PlayCommands::Top
+                    | PlayCommands::Bottom => {
+                        let selected = table_state.selected().unwrap();
+                        let distance = repeat.unwrap_or(1).saturating_mul(page);
+                        let half = repeat.unwrap_or(1).saturating_mul((page / 2).max(1));
+                        let last = inputs.len() - 1;
+                        let input_idx = match command {
+                            PlayCommands::PageUp => selected.saturating_sub(distance),
+                            PlayCommands::PageDown => selected.saturating_add(distance).min(last),
+                            PlayCommands::HalfPageUp => selected.saturating_sub(half),
+                            PlayCommands::HalfPageDown => selected.saturating_add(half).min(last),
+                            PlayCommands::Top => 0,
+                            // A count picks the song by its position, counting from 1.
+                            _ => repeat.map_or(last, |n| n.saturating_sub(1).min(last)),
+                        };
+                        if input_idx == selected {
+                            continue;
+                        }
+                        if bpms.ready() {
+                            state = State::Unsaved {
+                                leaving: Leaving::Song(inputs[input_idx].order),
+                            };
+                            continue;
+                        }
+
+                        table_state.select(Some(input_idx));
+                        song = audio_stream.play(&inputs, input_idx)?;
+                        bpms.reset();
+                    }
+
+                    PlayCommands::NextUntagged => {
+                        let selected = table_state.selected().unwrap();
+                        let untagged = (1..inputs.len())
+                            .map(|offset| (selected + offset) % inputs.len())
+                            .find(|index| inputs[*index].track.bpm().is_none());
+                        let Some(input_idx) = untagged else {
+                            state = State::Notice {
+                                message: "Every other song has a BPM".to_owned(),
+                            };
+                            continue;
+                        };
+                        if bpms.ready() {
+                            state = State::Unsaved {
+                                leaving: Leaving::Song(inputs[input_idx].order),
+                            };
+                            continue;
+                        }
+
+                        table_state.select(Some(input_idx));
+                        song = audio_stream.play(&inputs, input_idx)?;
+                        bpms.reset();
+                    }
+                    PlayCommands::Half => bpms.halve(),
+                    PlayCommands::Double => bpms.double(),
+                    PlayCommands::BeatsPerTap => bpms.cycle_beats_per_tap(),
+                    PlayCommands::Manual => {
+                        state = State::Manual {
+                            manual_bpm: String::new(),
+                        };
+                    }
+                    PlayCommands::Section => {
+                        if let Some(bpm) = display.tapped(&bpms) {
+                            song.end_section(bpm);
+                            bpms.reset();
+                        }
+                    }
+                    PlayCommands::Analyze => {
+                        // An estimate that's already done, or still going with --estimate, is
+                        // used instead of decoding the song again.
+                        if song.estimate.is_none() && song.estimating.is_none() {
+                            let path = inputs[table_state.selected().unwrap()].track.path();
+                            song.start_estimate(path, display.range);
+                        }
+                        song.analyze = true;
+                    }
+                    PlayCommands::Jump => jumping = true,
+                    PlayCommands::VolumeUp => {
+                        audio_stream.set_volume(&song, audio_stream.volume + VOLUME_STEP);
+                    }
+                    PlayCommands::VolumeDown => {
+                        let volume = audio_stream.volume.saturating_sub(VOLUME_STEP);
+                        audio_stream.set_volume(&song, volume);
+                    }
+                    PlayCommands::Faster => {
+                        audio_stream.set_speed(&mut song, audio_stream.speed + SPEED_STEP);
+                    }
+                    PlayCommands::Slower => {
+                        let speed = audio_stream.speed.saturating_sub(SPEED_STEP);
+                        audio_stream.set_speed(&mut song, speed);
+                    }
+                    PlayCommands::LoopIn => song.loop_in = Some(song.position()),
+                    PlayCommands::LoopOut => {
+                        let path = inputs[table_state.selected().unwrap()].track.path();
+                        let position = song.position();
+                        if song.looping.is_some() {
+                            // Carry on through the rest of the song from here.
+                            song.jump(audio_stream.sink(path, position)?, position);
+                        } else if let Some(start) = song.loop_in.filter(|start| *start < position) {
+                            song.start_loop(
+                                audio_stream.looped_sink(path, start, position)?,
+                                start,
+                                position,
+                            );
+                        }
+                    }
+                    PlayCommands::Mute => audio_stream.toggle_mute(&song),
+                    PlayCommands::ClickTrack => click_track = !click_track,
+                    PlayCommands::Flag => {
+                        let input = &mut inputs[table_state.selected().unwrap()];
+                        input.flagged = !input.flagged;
+                    }
+                    PlayCommands::History => state = State::History { selected: 0 },
+                    PlayCommands::Browse => {
+                        // Start next to the playing song the first time.
+                        let dir = browse_dir.clone().unwrap_or_else(|| {
+                            Path::new(inputs[table_state.selected().unwrap()].track.path())
+                                .parent()
+                                .filter(|dir| !dir.as_os_str().is_empty())
+                                .unwrap_or(Path::new("."))
+                                .to_owned()
+                        });
+                        state = match file::list_dir(&dir) {
+                            Ok(entries) => State::Browse {
+                                dir,
+                                entries,
+                                selected: 0,
+                                added: 0,
+                            },
+                            Err(e) => State::Notice {
+                                message: format!("{}: {}", dir.display(), e),
+                            },
+                        };
+                    }
+                    PlayCommands::Undo => {
+                        let Some(first) = undo.first().map(|saved| saved.order) else {
+                            continue;
+                        };
+                        for saved in undo.drain(..) {
+                            let Some(input) =
+                                inputs.iter_mut().find(|input| input.order == saved.order)
+                            else {
+                                continue;
+                            };
+                            let old = input.track.bpm();
+                            audio_stream.forget(input.track.path());
+                            if let Err(e) = input.track.restore(saved.backup) {
+                                state = State::Notice {
+                                    message: format!("Couldn't undo {}: {}", input.name(), e),
+                                };
+                                input.error = Some(e.to_string());
+                                continue;
+                            }
+                            input.confidence = saved.confidence;
+                            history.push(Entry {
+                                order: saved.order,
+                                old,
+                                new: input.track.bpm(),
+                                at: Instant::now(),
+                            });
+                        }
+
+                        let input_idx = inputs.iter().position(|input| input.order == first);
+                        if let Some(input_idx) = input_idx {
+                            table_state.select(Some(input_idx));
+                            song = audio_stream.play(&inputs, input_idx)?;
+                            bpms.reset();
+                        }
+                    }
+                    PlayCommands::Mark => {
+                        let input = &mut inputs[table_state.selected().unwrap()];
+                        input.marked = !input.marked;
+                        marked_last = input.marked.then_some(input.order);
+                    }
+                    PlayCommands::MarkRange => {
+                        let selected = table_state.selected().unwrap();
+                        let from = marked_last
+                            .and_then(|order| inputs.iter().position(|input| input.order == order))
+                            .unwrap_or(selected);
+                        for input in &mut inputs[from.min(selected)..=from.max(selected)] {
+                            input.marked = true;
+                        }
+                        marked_last = Some(inputs[selected].order);
+                    }
+                    PlayCommands::Help => state = State::Help,
+                    PlayCommands::Edit => {
+                        let metadata = inputs[table_state.selected().unwrap()].track.metadata();
+                        // Blanks are filled in with what AcoustID recognized the song as, ready
+                        // to save.
+                        let recognized = match &song.identified {
+                            Some(Ok(Some(recording))) => Some(recording),
+                            _ => None,
+                        };
+                        state = State::Edit {
+                            values: file::MetadataField::ALL
+                                .iter()
+                                .map(|field| {
+                                    let looked_up = recognized.and_then(|recording| match field {
+                                        file::MetadataField::Artist => recording.artist.as_deref(),
+                                        file::MetadataField::Title => recording.title.as_deref(),
+                                        _ => None,
+                                    });
+                                    metadata
+                                        .get(*field)
+                                        .or(looked_up)
+                                        .unwrap_or_default()
+                                        .to_owned()
+                                })
+                                .collect(),
+                            selected: 0,
+                        };
+                    }
+                    PlayCommands::Info => {
+                        let input = &inputs[table_state.selected().unwrap()];
+                        state = match stream_info(input.track.as_ref()) {
+                            Ok(lines) => State::Info { lines },
+                            Err(e) => State::Notice {
+                                message: e.to_string(),
+                            },
+                        };
+                    }
+                    PlayCommands::Sort => {
+                        // Keep the playing song selected wherever it ends up.
+                        let playing = inputs[table_state.selected().unwrap()].order;
+                        display.sort_by = display.sort_by.next();
+                        display.sort_by.sort(&mut inputs);
+                        table_state.select(inputs.iter().position(|input| input.order == playing));
+                    }
+                    PlayCommands::Search => {
+                        state = State::Search {
+                            query: String::new(),
+                            selected: 0,
+                        };
+                    }
+                    PlayCommands::Pause if song.paused() => song.resume(),
+                    PlayCommands::Pause => song.pause(),
+                    PlayCommands::Calibrate => {
+                        song.pause();
+                        _click = Some(audio_stream.click(CLICK_BPM, 0.0)?);
+                        state = State::Calibrating {
+                            started: Instant::now(),
+                            offsets: Vec::new(),
+                        };
+                    }
+                }
+            }
+            State::Notice { message } => {
+                terminal.draw(|f| {
+                    draw_ui(f, &inputs, &mut table_state, None, &bpms, &song, display);
+                    status_bar(
+                        f,
+                        Mode::Notice,
+                        &inputs[table_state.selected().unwrap()],
+                        display.theme,
+                    );
+                    let popup = Paragraph::new(vec![
+                        Line::from(message.as_str()),
+                        Line::from("Press any key"),
+                    ])
+                    .block(display.theme.popup().borders(Borders::ALL))
+                    .alignment(Alignment::Center)
+                    // Errors can run long.
+                    .wrap(Wrap { trim: true });
+                    let area = centered_rect(40, 15, f.size());
+                    f.render_widget(Clear, area);
+                    f.render_widget(popup, area);
+                })?;
+
+                if poll_keypress(|_| Some(()), TICK)?.is_none() {
+                    state = State::Notice { message };
+                    continue;
+                }
+                state = State::Playing;
+            }
+            State::Unsaved { leaving } => {
+                let bpm = display.tapped(&bpms);
+                terminal.draw(|f| {
+                    draw_ui(f, &inputs, &mut table_state, bpm, &bpms, &song, display);
+                    status_bar(
+                        f,
+                        Mode::Unsaved,
+                        &inputs[table_state.selected().unwrap()],
+                        display.theme,
+                    );
+                    let question = format!(
+                        "Save BPM {:.*} before {}?",
+                        display.precision,
+                        bpm.unwrap_or_default(),
+                        match leaving {
+                            Leaving::Quit => "quitting",
+                            Leaving::Song(_) => "moving on",
+                        }
+                    );
+                    let mut lines = vec![
+                        Line::from(question),
+                        Line::from(vec![
+                            Span::styled("y", display.theme.accent()),
+                            Span::raw("es/"),
+                            Span::styled("n", display.theme.accent()),
+                            Span::raw("o/"),
+                            Span::styled("Esc", display.theme.accent()),
+                            Span::raw(" go back"),
+                        ]),
+                    ];
+                    let implausible = bpm.is_some_and(|bpm| !display.range.contains(bpm));
+                    if implausible {
+                        lines.insert(0, Line::from(format!("BPM is outside {}", display.range)));
+                    }
+                    let popup = Paragraph::new(lines)
+                        .block(display.theme.popup().borders(Borders::ALL))
+                        .alignment(Alignment::Center);
+                    let area = if implausible {
+                        centered_rect(30, 15, f.size())
+                    } else {
+                        centered_rect(30, 10, f.size())
+                    };
+                    f.render_widget(Clear, area);
+                    f.render_widget(popup, area);
+                })?;
+
+                let Some(command) = poll_keypress(unsaved_keys, TICK)? else {
+                    state = State::Unsaved { leaving };
+                    continue;
+                };
+
+                state = State::Playing;
+                match command {
+                    UnsavedCommands::Save => {
+                        let selected = table_state.selected().unwrap();
+                        let (saved, failed) =
+                            song.save(&mut audio_stream, &mut inputs, selected, bpm, None);
+                        record(&mut history, &inputs, &saved);
+                        undo = saved;
+                        // Stay put rather than lose the taps as well.
+                        if let Some(message) = failed {
+                            state = State::Notice { message };
+                            continue;
+                        }
+                    }
+                    UnsavedCommands::Discard => {}
+                    UnsavedCommands::Cancel => continue,
+                }
+                match leaving {
+                    Leaving::Quit => break,
+                    Leaving::Song(order) => {
+                        if let Some(input_idx) =
+                            inputs.iter().position(|input| input.order == order)
+                        {
+                            table_state.select(Some(input_idx));
+                            song = audio_stream.play(&inputs, input_idx)?;
+                            bpms.reset();
+                        }
+                    }
+                }
+            }
+            State::Info { lines } => {
+                terminal.draw(|f| {
+                    draw_ui(f, &inputs, &mut table_state, None, &bpms, &song, display);
+                    status_bar(
+                        f,
+                        Mode::Info,
+                        &inputs[table_state.selected().unwrap()],
+                        display.theme,
+                    );
+                    let popup = Paragraph::new(
+                        lines
+                            .iter()
+                            .map(|line| Line::from(line.as_str()))
+                            .collect::<Vec<_>>(),
+                    )
+                    .block(
+                        display
+                            .theme
+                            .popup()
+                            .borders(Borders::ALL)
+                            .title("Press any key to close")
+                            .title_alignment(Alignment::Center),
+                    );
+                    let area = centered_rect(40, 20, f.size());
+                    f.render_widget(Clear, area);
+                    f.render_widget(popup, area);
+                })?;
+
+                if poll_keypress(|_| Some(()), TICK)?.is_none() {
+                    state = State::Info { lines };
+                    continue;
+                }
+                state = State::Playing;
+            }
+            State::Finished { bpm, estimate } => {
+                terminal.draw(|f| {
+                    draw_ui(f, &inputs, &mut table_state, bpm, &bpms, &song, display);
+                    status_bar(
+                        f,
+                        Mode::Confirm,
+                        &inputs[table_state.selected().unwrap()],
+                        display.theme,
+                    );
+                    let question = match (inputs[table_state.selected().unwrap()].track.bpm(), bpm)
+                    {
+                        (Some(current), Some(bpm)) => {
+                            format!(
+                                "Replace BPM {:.*} with {:.*}?",
+                                display.precision, current, display.precision, bpm
+                            )
+                        }
+                        (None, Some(bpm)) => format!("Save BPM {:.*}?", display.precision, bpm),
+                        (_, None) => "Save BPM?".to_owned(),
+                    };
+                    let mut lines = vec![
+                        Line::from(question),
+                        Line::from(vec![
+                            Span::styled("y", display.theme.accent()),
+                            Span::raw("es/"),
+                            Span::styled("n", display.theme.accent()),
+                            Span::raw("o"),
+                        ]),
+                    ];
+                    let implausible = bpm.is_some_and(|bpm| !display.range.contains(bpm));
+                    if implausible {
+                        lines.insert(0, Line::from(format!("BPM is outside {}", display.range)));
+                    }
+                    let marked = inputs.iter().filter(|input| input.marked).count();
+                    if bpm.is_some() && marked > 0 {
+                        lines.insert(
+                            lines.len() - 1,
+                            Line::from(format!("and {} marked", marked)),
+                        );
+                    }
+                    if let Some(online) = song.online_bpm().filter(|online| bpm != Some(*online)) {
+                        lines.push(Line::from(vec![
+                            Span::styled("o", display.theme.accent()),
+                            Span::raw(format!(
+                                ": use the online BPM {:.*}",
+                                display.precision, online
+                            )),
+                        ]));
+                    }
+                    let tall = lines.len() > 2;
+                    let popup = Paragraph::new(lines)
+                        .block(display.theme.popup().borders(Borders::ALL))
+                        .alignment(Alignment::Center);
+                    let area = if tall {
+                        centered_rect(30, 15, f.size())
+                    } else {
+                        centered_rect(30, 10, f.size())
+                    };
                     f.render_widget(Clear, area);
                     f.render_widget(popup, area);
                 })?;
 
-                let command = on_keypress(confirm_keys)?;
+                let Some(command) = poll_keypress(confirm_keys, TICK)? else {
+                    state = State::Finished { bpm, estimate };
+                    continue;
+                };
 
                 match command {
                     ConfirmCommands::Yes => {
-                        inputs[table_state.selected().unwrap()].set_bpm(bpm)?;
-                        let input_idx = (table_state.selected().unwrap() + 1) % inputs.len();
-                        state = State::Playing;
+                        let selected = table_state.selected().unwrap();
+                        let (saved, failed) =
+                            song.save(&mut audio_stream, &mut inputs, selected, bpm, estimate);
+                        record(&mut history, &inputs, &saved);
+                        undo = saved;
+                        state = match failed {
+                            Some(message) => State::Notice { message },
+                            None => State::Playing,
+                        };
+                        if inputs[selected].error.is_some() {
+                            continue;
+                        }
+                        let input_idx = (selected + 1) % inputs.len();
                         table_state.select(Some(input_idx));
-                        _player = audio_stream.play(&inputs[input_idx].path())?;
-                        last_press_at = None;
-                        bpms = Bpms::new(args.num_avg);
+                        song = audio_stream.play(&inputs, input_idx)?;
+                        bpms.reset();
                     }
                     ConfirmCommands::No => {
                         state = State::Playing;
                     }
+                    ConfirmCommands::Online => {
+                        state = match song.online_bpm() {
+                            Some(online) => State::Finished {
+                                bpm: Some(online),
+                                estimate: None,
+                            },
+                            None => State::Finished { bpm, estimate },
+                        };
+                    }
                 }
             }
-            State::Manual { manual_bpm } => {
+            State::Manual { mut manual_bpm } => {
                 terminal.draw(|f| {
-                    draw_ui(f, &inputs, &mut table_state, bpms.avg());
-                    let manual_bpm_str = if manual_bpm > 0 {
-                        manual_bpm.to_string()
-                    } else {
-                        String::new()
-                    };
-                    let popup = Paragraph::new(manual_bpm_str).block(
-                        Block::default()
+                    draw_ui(
+                        f,
+                        &inputs,
+                        &mut table_state,
+                        bpms.avg().map(|bpm| display.rounding.apply(bpm)),
+                        &bpms,
+                        &song,
+                        display,
+                    );
+                    status_bar(
+                        f,
+                        Mode::Manual,
+                        &inputs[table_state.selected().unwrap()],
+                        display.theme,
+                    );
+                    let popup = Paragraph::new(manual_bpm.as_str()).block(
+                        display
+                            .theme
+                            .popup()
                             .title("Manually enter bpm")
                             .borders(Borders::ALL),
                     );
@@ -412,6 +4581,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 })?;
 
                 loop {
+                    if !crossterm::event::poll(TICK)? {
+                        state = State::Manual { manual_bpm };
+                        break;
+                    }
                     let key = crossterm::event::read()?;
 
                     let key_event = match key {
@@ -423,45 +4596,776 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                         continue;
                     }
 
-                    let new_bpm = match key_event.code {
+                    match key_event.code {
                         KeyCode::Esc | KeyCode::Char('m') => {
                             state = State::Playing;
                             break;
                         }
                         KeyCode::Enter => {
-                            inputs[table_state.selected().unwrap()].set_bpm(manual_bpm)?;
-                            let input_idx = (table_state.selected().unwrap() + 1) % inputs.len();
-                            state = State::Playing;
+                            let Ok(bpm) = manual_bpm.parse() else {
+                                state = State::Manual { manual_bpm };
+                                break;
+                            };
+                            if !display.range.contains(bpm) {
+                                state = State::Finished {
+                                    bpm: Some(bpm),
+                                    estimate: None,
+                                };
+                                break;
+                            }
+                            let selected = table_state.selected().unwrap();
+                            let (saved, failed) = song.save(
+                                &mut audio_stream,
+                                &mut inputs,
+                                selected,
+                                Some(bpm),
+                                None,
+                            );
+                            record(&mut history, &inputs, &saved);
+                            undo = saved;
+                            state = match failed {
+                                Some(message) => State::Notice { message },
+                                None => State::Playing,
+                            };
+                            if inputs[selected].error.is_some() {
+                                break;
+                            }
+                            let input_idx = (selected + 1) % inputs.len();
                             table_state.select(Some(input_idx));
-                            _player = audio_stream.play(&inputs[input_idx].path())?;
-                            last_press_at = None;
-                            bpms = Bpms::new(args.num_avg);
+                            song = audio_stream.play(&inputs, input_idx)?;
+                            bpms.reset();
                             break;
                         }
-                        KeyCode::Backspace => manual_bpm / 10,
-                        KeyCode::Char(c) => {
-                            if let Some(digit) = c.to_digit(10) {
-                                if manual_bpm > u32::MAX / 10 {
-                                    manual_bpm
-                                } else {
-                                    manual_bpm * 10 + digit
-                                }
+                        KeyCode::Backspace => {
+                            manual_bpm.pop();
+                        }
+                        KeyCode::Char(c)
+                            if c.is_ascii_digit() || (c == '.' && !manual_bpm.contains('.')) =>
+                        {
+                            manual_bpm.push(c);
+                        }
+                        _ => {}
+                    }
+
+                    state = State::Manual { manual_bpm };
+
+                    break;
+                }
+            }
+            State::Edit {
+                mut values,
+                mut selected,
+            } => {
+                terminal.draw(|f| {
+                    draw_ui(f, &inputs, &mut table_state, None, &bpms, &song, display);
+                    status_bar(
+                        f,
+                        Mode::Edit,
+                        &inputs[table_state.selected().unwrap()],
+                        display.theme,
+                    );
+                    let lines = file::MetadataField::ALL
+                        .iter()
+                        .zip(&values)
+                        .enumerate()
+                        .map(|(i, (field, value))| {
+                            let line = Line::from(vec![
+                                Span::styled(
+                                    format!("{:>6}: ", field.name()),
+                                    display.theme.accent(),
+                                ),
+                                Span::raw(value.as_str()),
+                            ]);
+                            if i == selected {
+                                line.style(display.theme.selection())
+                            } else {
+                                line
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    let popup = Paragraph::new(lines).block(
+                        display
+                            .theme
+                            .popup()
+                            .title("Edit tags")
+                            .borders(Borders::ALL),
+                    );
+                    let area = centered_rect(50, 30, f.size());
+                    f.render_widget(Clear, area);
+                    f.render_widget(popup, area);
+                })?;
+
+                if !crossterm::event::poll(TICK)? {
+                    state = State::Edit { values, selected };
+                    continue;
+                }
+                let Event::Key(key_event) = crossterm::event::read()? else {
+                    state = State::Edit { values, selected };
+                    continue;
+                };
+                // Shift is needed to type capitals.
+                if !matches!(
+                    key_event.modifiers,
+                    KeyModifiers::NONE | KeyModifiers::SHIFT
+                ) {
+                    state = State::Edit { values, selected };
+                    continue;
+                }
+
+                let fields = file::MetadataField::ALL.len();
+                match key_event.code {
+                    KeyCode::Esc => {
+                        state = State::Playing;
+                        continue;
+                    }
+                    KeyCode::Enter => {
+                        state = State::Playing;
+                        let input = &mut inputs[table_state.selected().unwrap()];
+                        let metadata = input.track.metadata();
+                        // Only write the fields that changed, so untouched ones keep their exact
+                        // spacing.
+                        let edits = file::MetadataField::ALL
+                            .into_iter()
+                            .zip(values)
+                            .filter(|(field, value)| {
+                                metadata.get(*field).unwrap_or_default().trim() != value.trim()
+                            })
+                            .collect::<Vec<_>>();
+                        if edits.is_empty() {
+                            continue;
+                        }
+                        audio_stream.forget(input.track.path());
+                        if let Err(e) = input.track.set_metadata(&edits) {
+                            state = State::Notice {
+                                message: format!("Couldn't save {}: {}", input.name(), e),
+                            };
+                            input.error = Some(e.to_string());
+                        }
+                        continue;
+                    }
+                    KeyCode::Up | KeyCode::BackTab => selected = (selected + fields - 1) % fields,
+                    KeyCode::Down | KeyCode::Tab => selected = (selected + 1) % fields,
+                    KeyCode::Backspace => {
+                        values[selected].pop();
+                    }
+                    KeyCode::Char(c) => values[selected].push(c),
+                    _ => {}
+                }
+                state = State::Edit { values, selected };
+            }
+            State::Jump { mut time } => {
+                terminal.draw(|f| {
+                    draw_ui(
+                        f,
+                        &inputs,
+                        &mut table_state,
+                        bpms.avg().map(|bpm| display.rounding.apply(bpm)),
+                        &bpms,
+                        &song,
+                        display,
+                    );
+                    status_bar(
+                        f,
+                        Mode::Jump,
+                        &inputs[table_state.selected().unwrap()],
+                        display.theme,
+                    );
+                    let popup = Paragraph::new(time.as_str()).block(
+                        display
+                            .theme
+                            .popup()
+                            .title("Jump to time (like 1:30)")
+                            .borders(Borders::ALL),
+                    );
+                    let area = centered_rect(30, 5, f.size());
+                    f.render_widget(Clear, area);
+                    f.render_widget(popup, area);
+                })?;
+
+                if !crossterm::event::poll(TICK)? {
+                    state = State::Jump { time };
+                    continue;
+                }
+                let Event::Key(key_event) = crossterm::event::read()? else {
+                    state = State::Jump { time };
+                    continue;
+                };
+                if key_event.modifiers != KeyModifiers::empty() {
+                    state = State::Jump { time };
+                    continue;
+                }
+
+                match key_event.code {
+                    KeyCode::Esc => state = State::Playing,
+                    KeyCode::Enter => match parse_time(&time) {
+                        Some(position) => {
+                            let path = inputs[table_state.selected().unwrap()].track.path();
+                            song.jump(audio_stream.sink(path, position)?, position);
+                            state = State::Playing;
+                        }
+                        None => state = State::Jump { time },
+                    },
+                    KeyCode::Backspace => {
+                        time.pop();
+                        state = State::Jump { time };
+                    }
+                    KeyCode::Char(c) if c.is_ascii_digit() || c == ':' => {
+                        time.push(c);
+                        state = State::Jump { time };
+                    }
+                    _ => state = State::Jump { time },
+                }
+            }
+            State::Search {
+                mut query,
+                mut selected,
+            } => {
+                let matches = search(&inputs, &query);
+                selected = selected.min(matches.len().saturating_sub(1));
+                terminal.draw(|f| {
+                    draw_ui(
+                        f,
+                        &inputs,
+                        &mut table_state,
+                        bpms.avg().map(|bpm| display.rounding.apply(bpm)),
+                        &bpms,
+                        &song,
+                        display,
+                    );
+                    status_bar(
+                        f,
+                        Mode::Search,
+                        &inputs[table_state.selected().unwrap()],
+                        display.theme,
+                    );
+                    let area = centered_rect(60, 60, f.size());
+                    let rows = area.height.saturating_sub(3) as usize;
+                    // Scroll the matches to keep the highlighted one in view.
+                    let first = selected.saturating_sub(rows.saturating_sub(1));
+                    let mut lines = vec![Line::from(format!("/{}", query))];
+                    lines.extend(matches.iter().enumerate().skip(first).take(rows).map(
+                        |(i, index)| {
+                            let name = inputs[*index].name();
+                            if i == selected {
+                                Line::styled(name, display.theme.selection())
                             } else {
-                                manual_bpm
+                                Line::from(name)
                             }
+                        },
+                    ));
+                    let popup = Paragraph::new(lines).block(
+                        display
+                            .theme
+                            .popup()
+                            .title(format!(
+                                "Search ({} of {} songs)",
+                                matches.len(),
+                                inputs.len()
+                            ))
+                            .borders(Borders::ALL),
+                    );
+                    f.render_widget(Clear, area);
+                    f.render_widget(popup, area);
+                })?;
+
+                if !crossterm::event::poll(TICK)? {
+                    state = State::Search { query, selected };
+                    continue;
+                }
+                let Event::Key(key_event) = crossterm::event::read()? else {
+                    state = State::Search { query, selected };
+                    continue;
+                };
+                // Shift is needed to type capitals.
+                if !matches!(
+                    key_event.modifiers,
+                    KeyModifiers::NONE | KeyModifiers::SHIFT
+                ) {
+                    state = State::Search { query, selected };
+                    continue;
+                }
+
+                match key_event.code {
+                    KeyCode::Esc => state = State::Playing,
+                    KeyCode::Enter => {
+                        state = State::Playing;
+                        let Some(&input_idx) = matches.get(selected) else {
+                            continue;
+                        };
+                        if input_idx == table_state.selected().unwrap() {
+                            continue;
+                        }
+                        if bpms.ready() {
+                            state = State::Unsaved {
+                                leaving: Leaving::Song(inputs[input_idx].order),
+                            };
+                        } else {
+                            table_state.select(Some(input_idx));
+                            song = audio_stream.play(&inputs, input_idx)?;
+                            bpms.reset();
+                        }
+                    }
+                    KeyCode::Up => {
+                        state = State::Search {
+                            query,
+                            selected: selected.saturating_sub(1),
+                        };
+                    }
+                    KeyCode::Down => {
+                        state = State::Search {
+                            query,
+                            selected: selected + 1,
+                        };
+                    }
+                    KeyCode::Backspace => {
+                        query.pop();
+                        state = State::Search { query, selected: 0 };
+                    }
+                    KeyCode::Char(c) => {
+                        query.push(c);
+                        state = State::Search { query, selected: 0 };
+                    }
+                    _ => state = State::Search { query, selected },
+                }
+            }
+            State::History { selected } => {
+                terminal.draw(|f| {
+                    draw_ui(f, &inputs, &mut table_state, None, &bpms, &song, display);
+                    status_bar(
+                        f,
+                        Mode::History,
+                        &inputs[table_state.selected().unwrap()],
+                        display.theme,
+                    );
+                    history_panel(f, &history, &inputs, selected, display);
+                })?;
+
+                let Some(command) = poll_keypress(history_keys, TICK)? else {
+                    state = State::History { selected };
+                    continue;
+                };
+                match command {
+                    HistoryCommands::Up => {
+                        state = State::History {
+                            selected: selected.saturating_sub(1),
+                        };
+                    }
+                    HistoryCommands::Down => {
+                        state = State::History {
+                            selected: (selected + 1).min(history.len().saturating_sub(1)),
+                        };
+                    }
+                    HistoryCommands::Open => {
+                        state = State::Playing;
+                        let Some(entry) = history.iter().rev().nth(selected) else {
+                            continue;
+                        };
+                        let Some(input_idx) =
+                            inputs.iter().position(|input| input.order == entry.order)
+                        else {
+                            continue;
+                        };
+                        if input_idx == table_state.selected().unwrap() {
+                            continue;
+                        }
+                        if bpms.ready() {
+                            state = State::Unsaved {
+                                leaving: Leaving::Song(entry.order),
+                            };
+                            continue;
                         }
-                        _ => manual_bpm,
+                        table_state.select(Some(input_idx));
+                        song = audio_stream.play(&inputs, input_idx)?;
+                        bpms.reset();
+                    }
+                    HistoryCommands::Close => state = State::Playing,
+                }
+            }
+            State::Browse {
+                dir,
+                entries,
+                mut selected,
+                mut added,
+            } => {
+                selected = selected.min(entries.len().saturating_sub(1));
+                terminal.draw(|f| {
+                    draw_ui(f, &inputs, &mut table_state, None, &bpms, &song, display);
+                    status_bar(
+                        f,
+                        Mode::Browse,
+                        &inputs[table_state.selected().unwrap()],
+                        display.theme,
+                    );
+                    let area = centered_rect(60, 60, f.size());
+                    let rows = area.height.saturating_sub(2) as usize;
+                    // Scroll the entries to keep the highlighted one in view.
+                    let first = selected.saturating_sub(rows.saturating_sub(1));
+                    let lines = entries
+                        .iter()
+                        .enumerate()
+                        .skip(first)
+                        .take(rows)
+                        .map(|(i, path)| {
+                            let mut name = path
+                                .file_name()
+                                .map(|name| name.to_string_lossy().into_owned())
+                                .unwrap_or_default();
+                            if path.is_dir() {
+                                name.push('/');
+                            }
+                            if i == selected {
+                                Line::styled(name, display.theme.selection())
+                            } else {
+                                Line::from(name)
+                            }
+                        })
+                        .collect::<Vec<_>>();
+                    let mut title = dir.display().to_string();
+                    if added > 0 {
+                        title.push_str(&format!(" (added {})", added));
+                    }
+                    let popup = Paragraph::new(lines)
+                        .block(display.theme.popup().title(title).borders(Borders::ALL));
+                    f.render_widget(Clear, area);
+                    f.render_widget(popup, area);
+                })?;
+
+                let Some(command) = poll_keypress(browse_keys, TICK)? else {
+                    state = State::Browse {
+                        dir,
+                        entries,
+                        selected,
+                        added,
                     };
+                    continue;
+                };
+                let highlighted = entries.get(selected);
+                // The folder to go to, if any.
+                let next = match command {
+                    BrowseCommands::Up => {
+                        selected = selected.saturating_sub(1);
+                        None
+                    }
+                    BrowseCommands::Down => {
+                        selected += 1;
+                        None
+                    }
+                    BrowseCommands::Close => {
+                        browse_dir = Some(dir);
+                        state = State::Playing;
+                        continue;
+                    }
+                    BrowseCommands::Parent => match dir.parent() {
+                        Some(parent) if !parent.as_os_str().is_empty() => Some(parent.to_owned()),
+                        // Relative paths run out of parents before reaching the root.
+                        _ => dir
+                            .canonicalize()
+                            .ok()
+                            .and_then(|dir| dir.parent().map(Path::to_owned)),
+                    },
+                    BrowseCommands::Open if highlighted.is_some_and(|path| path.is_dir()) => {
+                        highlighted.cloned()
+                    }
+                    BrowseCommands::Open | BrowseCommands::Add => {
+                        if let Some(path) = highlighted {
+                            let before = inputs.len();
+                            added += add_songs(
+                                &mut inputs,
+                                file::find_songs(path),
+                                &mut next_order,
+                                &write_options,
+                            );
+                            for input in &inputs[before..] {
+                                let _ = measure.send((input.order, input.track.path().to_owned()));
+                            }
+                            // Keep the playing song selected wherever the new songs sort to.
+                            let playing = inputs[table_state.selected().unwrap()].order;
+                            display.sort_by.sort(&mut inputs);
+                            table_state
+                                .select(inputs.iter().position(|input| input.order == playing));
+                            selected += 1;
+                        }
+                        None
+                    }
+                };
 
-                    state = State::Manual {
-                        manual_bpm: new_bpm,
+                state = match next.map(|next| (file::list_dir(&next), next)) {
+                    Some((Ok(next_entries), next)) => State::Browse {
+                        // Coming back up, keep the folder just left highlighted.
+                        selected: next_entries
+                            .iter()
+                            .position(|path| *path == dir)
+                            .unwrap_or(0),
+                        dir: next,
+                        entries: next_entries,
+                        added,
+                    },
+                    // Folders that can't be read are stayed out of.
+                    _ => State::Browse {
+                        dir,
+                        entries,
+                        selected,
+                        added,
+                    },
+                };
+            }
+            State::Calibrating {
+                started,
+                mut offsets,
+            } => {
+                terminal.draw(|f| {
+                    draw_ui(
+                        f,
+                        &inputs,
+                        &mut table_state,
+                        bpms.avg().map(|bpm| display.rounding.apply(bpm)),
+                        &bpms,
+                        &song,
+                        display,
+                    );
+                    status_bar(
+                        f,
+                        Mode::Calibrating,
+                        &inputs[table_state.selected().unwrap()],
+                        display.theme,
+                    );
+                    let measured = match measured_latency(&offsets) {
+                        Some(measured) => format!("Latency: {:.0}ms", measured),
+                        None => format!("Taps: {}/{}", offsets.len(), CALIBRATION_TAPS),
                     };
+                    let popup = Paragraph::new(vec![
+                        Line::from("Tap Space along with the clicks"),
+                        Line::from(measured),
+                        Line::from(format!("Current latency: {:.0}ms", bpms.options.latency)),
+                        Line::from("Enter to use, Esc to cancel"),
+                    ])
+                    .block(
+                        display
+                            .theme
+                            .popup()
+                            .title("Calibrate latency")
+                            .borders(Borders::ALL),
+                    )
+                    .alignment(Alignment::Center);
+                    let area = centered_rect(30, 20, f.size());
+                    f.render_widget(Clear, area);
+                    f.render_widget(popup, area);
+                })?;
 
-                    break;
+                let Some(command) = poll_keypress(calibrate_keys, TICK)? else {
+                    state = State::Calibrating { started, offsets };
+                    continue;
+                };
+
+                match command {
+                    CalibrateCommands::Tap => {
+                        let period = 60000.0 / CLICK_BPM;
+                        let elapsed = started.elapsed().as_secs_f64() * 1000.0;
+                        // Match each tap to the nearest click, early or late.
+                        let offset = elapsed % period;
+                        offsets.push(if offset > period / 2.0 {
+                            offset - period
+                        } else {
+                            offset
+                        });
+                        state = State::Calibrating { started, offsets };
+                    }
+                    CalibrateCommands::Accept => match measured_latency(&offsets) {
+                        Some(measured) => {
+                            bpms.options.latency = measured;
+                            _click = None;
+                            song.resume();
+                            state = State::Playing;
+                        }
+                        None => state = State::Calibrating { started, offsets },
+                    },
+                    CalibrateCommands::Cancel => {
+                        _click = None;
+                        song.resume();
+                        state = State::Playing;
+                    }
+                }
+            }
+            State::Help => {
+                terminal.draw(|f| {
+                    draw_ui(f, &inputs, &mut table_state, None, &bpms, &song, display);
+                    status_bar(
+                        f,
+                        Mode::Help,
+                        &inputs[table_state.selected().unwrap()],
+                        display.theme,
+                    );
+                    let width = HELP
+                        .iter()
+                        .flat_map(|(_, keys)| keys.iter())
+                        .map(|(key, _)| key.len())
+                        .max()
+                        .unwrap_or_default();
+                    let mut lines = Vec::new();
+                    for (i, (heading, keys)) in HELP.iter().enumerate() {
+                        if i > 0 {
+                            lines.push(Line::from(""));
+                        }
+                        lines.push(Line::styled(*heading, display.theme.accent()));
+                        lines.extend(keys.iter().map(|(key, action)| {
+                            Line::from(vec![
+                                Span::styled(
+                                    format!("{:>1$}  ", key, width),
+                                    display.theme.accent(),
+                                ),
+                                Span::raw(*action),
+                            ])
+                        }));
+                    }
+                    let popup = Paragraph::new(lines).block(
+                        display
+                            .theme
+                            .popup()
+                            .borders(Borders::ALL)
+                            .title("Press any key to close")
+                            .title_alignment(Alignment::Center),
+                    );
+                    let area = centered_rect(60, 90, f.size());
+                    f.render_widget(Clear, area);
+                    f.render_widget(popup, area);
+                })?;
+
+                if poll_keypress(|_| Some(()), TICK)?.is_some() {
+                    state = State::Playing;
+                }
+            }
+            State::Disconnected { error } => {
+                terminal.draw(|f| {
+                    draw_ui(f, &inputs, &mut table_state, None, &bpms, &song, display);
+                    status_bar(
+                        f,
+                        Mode::Disconnected,
+                        &inputs[table_state.selected().unwrap()],
+                        display.theme,
+                    );
+                    let mut lines = vec![
+                        Line::from("Audio output lost"),
+                        Line::from(vec![
+                            Span::styled("r", display.theme.accent()),
+                            Span::raw("econnect/"),
+                            Span::styled("q", display.theme.accent()),
+                            Span::raw("uit"),
+                        ]),
+                    ];
+                    if let Some(error) = &error {
+                        lines.insert(1, Line::from(error.as_str()));
+                    }
+                    let popup = Paragraph::new(lines)
+                        .block(display.theme.popup().borders(Borders::ALL))
+                        .alignment(Alignment::Center);
+                    let area = centered_rect(40, 15, f.size());
+                    f.render_widget(Clear, area);
+                    f.render_widget(popup, area);
+                })?;
+
+                let Some(command) = poll_keypress(reconnect_keys, TICK)? else {
+                    state = State::Disconnected { error };
+                    continue;
+                };
+                match command {
+                    ReconnectCommands::Reconnect => {
+                        let path = inputs[table_state.selected().unwrap()].track.path();
+                        match audio_stream.reconnect(device.as_deref(), path, &mut song) {
+                            Ok(stream) => {
+                                _stream = stream;
+                                state = State::Playing;
+                            }
+                            Err(e) => {
+                                state = State::Disconnected {
+                                    error: Some(e.to_string()),
+                                }
+                            }
+                        }
+                    }
+                    ReconnectCommands::Quit => break,
                 }
             }
         }
     }
 
-    Ok(())
+    // Put the terminal back before printing anything.
+    drop(terminal);
+    for error in &load_errors {
+        eprintln!("{}", error);
+    }
+    if display.dry_run {
+        eprintln!("Dry run, so nothing was saved.  Would have saved:");
+        let bpm = |bpm: Option<f64>| match bpm {
+            Some(bpm) => format!("{:.*}", display.precision, bpm),
+            None => "None".to_owned(),
+        };
+        for entry in &history {
+            if let Some(input) = inputs.iter().find(|input| input.order == entry.order) {
+                eprintln!(
+                    "{}: {} → {}",
+                    input.track.path(),
+                    bpm(entry.old),
+                    bpm(entry.new)
+                );
+            }
+        }
+    }
+    let playing = inputs[table_state.selected().unwrap()]
+        .track
+        .path()
+        .to_owned();
+    inputs.sort_by_key(|input| input.order);
+    // Tapping along in a dry run doesn't get anything done, so there's nothing to resume.
+    if !display.dry_run {
+        let session = session::Session {
+            playing: Some(playing),
+            songs: queue
+                .into_iter()
+                .enumerate()
+                .map(|(order, path)| (order, Some(path)))
+                // Songs added from the browser come after the rest.
+                .chain(
+                    inputs
+                        .iter()
+                        .filter(|input| input.order >= total)
+                        .map(|input| (input.order, None)),
+                )
+                .map(|(order, path)| {
+                    let input = inputs
+                        .binary_search_by_key(&order, |input| input.order)
+                        .ok()
+                        .map(|i| &inputs[i]);
+                    let path = path.unwrap_or_else(|| input.unwrap().track.path().to_owned());
+                    // A song that never got read, say because it was skipped or quitting came
+                    // first, stays flagged if it was.
+                    let flagged = match input {
+                        Some(input) => input.flagged,
+                        None => was_flagged.contains(&path),
+                    };
+                    let status = if flagged {
+                        session::Status::Flagged
+                    } else {
+                        session::Status::Queued
+                    };
+                    (status, path)
+                })
+                .collect(),
+        };
+        if let Err(e) = session.save() {
+            eprintln!("Couldn't save the session to resume: {}", e);
+        }
+    }
+    let flagged = inputs
+        .iter()
+        .filter(|input| input.flagged)
+        .map(|input| format!("{}\n", input.track.path()))
+        .collect::<String>();
+    match args.flagged {
+        Some(path) => std::fs::write(path, flagged)?,
+        None => print!("{}", flagged),
+    }
+
+    let failed = !load_errors.is_empty() || inputs.iter().any(|input| input.error.is_some());
+    let unfinished = read < total || inputs.iter().any(|input| input.track.bpm().is_none());
+    Ok(exit_code(failed, unfinished))
 }
@@ -0,0 +1,120 @@
+use std::{
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use rodio::Source;
+
+/// The number of times a second the levels are updated.
+const UPDATE_RATE: u32 = 20;
+/// Playback is taken to have stopped once the levels haven't been updated for this long.
+const STALE: Duration = Duration::from_millis(500);
+/// The output is taken to be gone once playback has stopped for this long.  Seeking a long way
+/// into some formats takes a while, so it's well over STALE.
+const LOST: Duration = Duration::from_secs(2);
+
+/// The loudest recent sample on the left and right channels, shared between the playback thread
+/// and the UI.
+#[derive(Default)]
+pub struct Levels {
+    peaks: [AtomicU16; 2],
+    updated: Mutex<Option<Instant>>,
+}
+
+impl Levels {
+    /// Forgets the last update, for when playback starts again from scratch.
+    pub fn reset(&self) {
+        *self.updated.lock().unwrap() = None;
+    }
+
+    /// Whether audio was playing since the last reset but has since stopped coming out, which
+    /// means the output device has gone away.
+    pub fn stalled(&self) -> bool {
+        self.updated
+            .lock()
+            .unwrap()
+            .is_some_and(|updated| updated.elapsed() > LOST)
+    }
+
+    /// The left and right levels from 0 to 1, or None if nothing has been played lately.
+    pub fn get(&self) -> Option<[f32; 2]> {
+        let updated = (*self.updated.lock().unwrap())?;
+        if updated.elapsed() > STALE {
+            return None;
+        }
+        Some(
+            self.peaks
+                .each_ref()
+                .map(|peak| f32::from(peak.load(Ordering::Relaxed)) / f32::from(i16::MAX)),
+        )
+    }
+}
+
+/// Passes a song through unchanged, measuring its levels on the way.  Mono songs show on both
+/// channels, and any past the second are folded onto the first two.
+pub struct Meter<S> {
+    source: S,
+    levels: Arc<Levels>,
+    peaks: [u16; 2],
+    sample: usize,
+}
+
+impl<S: Source<Item = i16>> Meter<S> {
+    pub fn new(source: S, levels: Arc<Levels>) -> Meter<S> {
+        Meter {
+            source,
+            levels,
+            peaks: [0; 2],
+            sample: 0,
+        }
+    }
+}
+
+impl<S: Source<Item = i16>> Iterator for Meter<S> {
+    type Item = i16;
+
+    fn next(&mut self) -> Option<i16> {
+        let sample = self.source.next()?;
+        let channels = self.source.channels().max(1) as usize;
+        let level = sample.unsigned_abs();
+        if channels == 1 {
+            self.peaks = self.peaks.map(|peak| peak.max(level));
+        } else {
+            let peak = &mut self.peaks[self.sample % channels % 2];
+            *peak = (*peak).max(level);
+        }
+
+        self.sample += 1;
+        let window = (self.source.sample_rate() / UPDATE_RATE).max(1) as usize * channels;
+        if self.sample >= window {
+            for (shared, peak) in self.levels.peaks.iter().zip(&mut self.peaks) {
+                shared.store(*peak, Ordering::Relaxed);
+                *peak = 0;
+            }
+            *self.levels.updated.lock().unwrap() = Some(Instant::now());
+            self.sample = 0;
+        }
+        Some(sample)
+    }
+}
+
+impl<S: Source<Item = i16>> Source for Meter<S> {
+    fn current_frame_len(&self) -> Option<usize> {
+        self.source.current_frame_len()
+    }
+
+    fn channels(&self) -> u16 {
+        self.source.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        self.source.total_duration()
+    }
+}
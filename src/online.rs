@@ -0,0 +1,209 @@
+/// What to look songs up with while they play.  Nothing is looked up unless asked for.
+#[derive(Clone, Default)]
+pub struct Lookup {
+    /// An AcoustID application key, to recognize songs by their audio.
+    pub acoustid_key: Option<String>,
+    /// A url to fetch BPMs from, with `{artist}` and `{title}` where the song's tags go.
+    pub bpm_api: Option<String>,
+}
+
+impl Lookup {
+    pub fn is_empty(&self) -> bool {
+        self.acoustid_key.is_none() && self.bpm_api.is_none()
+    }
+}
+
+/// What a song's audio was recognized as, from the MusicBrainz recording AcoustID matched it
+/// with.
+pub struct Recording {
+    pub artist: Option<String>,
+    pub title: Option<String>,
+}
+
+impl Recording {
+    /// Like `Artist - Title`, or whichever of them is known.
+    pub fn name(&self) -> String {
+        match (&self.artist, &self.title) {
+            (Some(artist), Some(title)) => format!("{} - {}", artist, title),
+            (Some(name), None) | (None, Some(name)) => name.clone(),
+            (None, None) => "an untitled recording".to_owned(),
+        }
+    }
+}
+
+/// Fails unless crabtap was built with the `online` feature, so asking for lookups without it
+/// is caught before anything starts.
+pub fn check_available() -> Result<(), anyhow::Error> {
+    if cfg!(feature = "online") {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Looking songs up online needs crabtap built with `--features online`"
+        ))
+    }
+}
+
+#[cfg(feature = "online")]
+mod lookup {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        time::Duration,
+    };
+
+    use base64::prelude::*;
+    use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+    use serde_json::Value;
+
+    use super::Recording;
+    use crate::analysis;
+
+    const ACOUSTID_URL: &str = "https://api.acoustid.org/v2/lookup";
+    /// How much of each song is fingerprinted, the same as AcoustID's own fpcalc.
+    const FINGERPRINT_SECONDS: usize = 120;
+    /// How long to wait on a server before giving up.
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    fn agent() -> ureq::Agent {
+        ureq::AgentBuilder::new()
+            .timeout(TIMEOUT)
+            .user_agent(concat!("crabtap/", env!("CARGO_PKG_VERSION")))
+            .build()
+    }
+
+    /// Reads a json response, using the message in the body when the server says there's an
+    /// error.
+    fn json(response: Result<ureq::Response, ureq::Error>) -> Result<Value, anyhow::Error> {
+        match response {
+            Ok(response) => Ok(response.into_json()?),
+            Err(ureq::Error::Status(code, response)) => {
+                let body = response.into_json::<Value>().unwrap_or_default();
+                match body["error"]["message"].as_str() {
+                    Some(message) => Err(anyhow::anyhow!("{}", message)),
+                    None => Err(anyhow::anyhow!("The server said {}", code)),
+                }
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// The AcoustID fingerprint of the start of a song, compressed and base64 encoded the way
+    /// the lookup api takes it.
+    fn fingerprint(path: &str) -> Result<String, anyhow::Error> {
+        let audio = analysis::decode(path, Some(FINGERPRINT_SECONDS))?;
+        let config = rusty_chromaprint::Configuration::preset_test2();
+        let mut printer = rusty_chromaprint::Fingerprinter::new(&config);
+        printer
+            .start(audio.rate as u32, audio.channels as u32)
+            .map_err(|e| anyhow::anyhow!("{:?}", e))?;
+        printer.consume(&audio.samples);
+        printer.finish();
+        let compressed =
+            rusty_chromaprint::FingerprintCompressor::from(&config).compress(printer.fingerprint());
+        Ok(BASE64_URL_SAFE_NO_PAD.encode(compressed))
+    }
+
+    /// Recognizes a song by its audio on AcoustID, returning None if it doesn't know it.  Gives
+    /// up without asking if it's cancelled while the song is being fingerprinted.
+    pub fn identify(
+        path: &str,
+        key: &str,
+        cancelled: &AtomicBool,
+    ) -> Result<Option<Recording>, anyhow::Error> {
+        let fingerprint = fingerprint(path)?;
+        let duration = analysis::duration(path)?.as_secs().to_string();
+        if cancelled.load(Ordering::Relaxed) {
+            return Err(anyhow::anyhow!("{}: Cancelled", path));
+        }
+
+        // The fingerprint is too long to be sure of fitting in a url.
+        let body = json(agent().post(ACOUSTID_URL).send_form(&[
+            ("client", key),
+            ("meta", "recordings"),
+            ("duration", &duration),
+            ("fingerprint", &fingerprint),
+        ]))?;
+
+        // Results come best match first, but not all of them are linked to a recording.
+        let recording = body["results"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .find_map(|result| result["recordings"].as_array()?.first());
+        let Some(recording) = recording else {
+            return Ok(None);
+        };
+
+        let artists = recording["artists"].as_array().cloned().unwrap_or_default();
+        let mut artist = String::new();
+        for (i, credit) in artists.iter().enumerate() {
+            artist += credit["name"].as_str().unwrap_or_default();
+            if i + 1 < artists.len() {
+                artist += credit["joinphrase"].as_str().unwrap_or(", ");
+            }
+        }
+        Ok(Some(Recording {
+            artist: Some(artist).filter(|artist| !artist.is_empty()),
+            title: recording["title"].as_str().map(str::to_owned),
+        }))
+    }
+
+    /// The first `tempo` or `bpm` anywhere in a response, as a number or a string of one.
+    fn find_bpm(value: &Value) -> Option<f64> {
+        match value {
+            Value::Object(object) => {
+                for (key, value) in object {
+                    if key.eq_ignore_ascii_case("tempo") || key.eq_ignore_ascii_case("bpm") {
+                        let bpm = match value {
+                            Value::Number(number) => number.as_f64(),
+                            Value::String(string) => string.trim().parse().ok(),
+                            _ => None,
+                        };
+                        // Unknown tempos are sometimes given as 0 or blank.
+                        if let Some(bpm) = bpm.filter(|bpm: &f64| bpm.is_finite() && *bpm > 0.0) {
+                            return Some(bpm);
+                        }
+                    }
+                }
+                object.values().find_map(find_bpm)
+            }
+            Value::Array(array) => array.iter().find_map(find_bpm),
+            _ => None,
+        }
+    }
+
+    /// Asks a BPM database for a song by its artist and title, returning None if it doesn't
+    /// know it.  The api is a url like GetSongBPM's search, with `{artist}` and `{title}` in
+    /// it, and whichever result comes first is taken.
+    pub fn fetch_bpm(api: &str, artist: &str, title: &str) -> Result<Option<f64>, anyhow::Error> {
+        let url = api
+            .replace(
+                "{artist}",
+                &utf8_percent_encode(artist, NON_ALPHANUMERIC).to_string(),
+            )
+            .replace(
+                "{title}",
+                &utf8_percent_encode(title, NON_ALPHANUMERIC).to_string(),
+            );
+        let body = json(agent().get(&url).call())?;
+        Ok(find_bpm(&body))
+    }
+}
+
+#[cfg(feature = "online")]
+pub use lookup::{fetch_bpm, identify};
+
+#[cfg(not(feature = "online"))]
+pub fn identify(
+    _path: &str,
+    _key: &str,
+    _cancelled: &std::sync::atomic::AtomicBool,
+) -> Result<Option<Recording>, anyhow::Error> {
+    check_available()?;
+    Ok(None)
+}
+
+#[cfg(not(feature = "online"))]
+pub fn fetch_bpm(_api: &str, _artist: &str, _title: &str) -> Result<Option<f64>, anyhow::Error> {
+    check_available()?;
+    Ok(None)
+}
@@ -0,0 +1,98 @@
+use std::{env, fs, io, path::PathBuf};
+
+/// Where a song had got to when the last session ended.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Status {
+    /// Whether it has a BPM is read from its tags again, so it isn't kept here.
+    Queued,
+    /// It was flagged with F to come back to.
+    Flagged,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Queued => "queued",
+            Status::Flagged => "flagged",
+        }
+    }
+}
+
+/// The song list as it was on quitting, saved to `~/.local/state/crabtap/session` for --resume.
+/// It's a line for whichever song was playing, then a line for every song in the order they were
+/// given, each a status or `playing` and the path, separated by a tab.
+#[derive(Default)]
+pub struct Session {
+    pub playing: Option<String>,
+    pub songs: Vec<(Status, String)>,
+}
+
+fn session_path() -> Option<PathBuf> {
+    let dir = match env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?)
+            .join(".local")
+            .join("state"),
+    };
+    Some(dir.join("crabtap").join("session"))
+}
+
+impl Session {
+    /// Reads the last session, or None if there isn't one.
+    pub fn load() -> Result<Option<Session>, anyhow::Error> {
+        let Some(path) = session_path() else {
+            return Ok(None);
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut session = Session::default();
+        for (i, line) in contents.lines().enumerate() {
+            let (status, song) = line.split_once('\t').ok_or_else(|| {
+                anyhow::anyhow!(
+                    "{}: line {}: Expected a status and a path",
+                    path.display(),
+                    i + 1
+                )
+            })?;
+            let status = match status {
+                "playing" => {
+                    session.playing = Some(song.to_owned());
+                    continue;
+                }
+                // Older sessions said whether each song was done, which wasn't always known.
+                "queued" | "done" | "todo" => Status::Queued,
+                "flagged" => Status::Flagged,
+                status => {
+                    return Err(anyhow::anyhow!(
+                        "{}: line {}: Unknown status {}",
+                        path.display(),
+                        i + 1,
+                        status
+                    ))
+                }
+            };
+            session.songs.push((status, song.to_owned()));
+        }
+        Ok(Some(session))
+    }
+
+    pub fn save(&self) -> Result<(), anyhow::Error> {
+        let path = session_path().ok_or_else(|| anyhow::anyhow!("No home directory"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut contents = String::new();
+        if let Some(playing) = &self.playing {
+            contents += &format!("playing\t{}\n", playing);
+        }
+        for (status, song) in &self.songs {
+            contents += &format!("{}\t{}\n", status.name(), song);
+        }
+        fs::write(path, contents)?;
+        Ok(())
+    }
+}
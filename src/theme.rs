@@ -0,0 +1,145 @@
+use std::time::Duration;
+
+use ratatui::{
+    style::{Color, Modifier, Style},
+    widgets::Block,
+};
+
+/// Colours for the tui.
+#[derive(Clone, Copy, Default, Debug, clap::ValueEnum)]
+pub enum Theme {
+    /// The terminal's own colours, with the selection reversed
+    #[default]
+    Default,
+    /// Solarized, light or dark to match the terminal
+    Solarized,
+    SolarizedDark,
+    SolarizedLight,
+    /// No colours or reversed text at all, for terminals where those are hard to read
+    Monochrome,
+}
+
+const SOLARIZED_BASE02: Color = Color::Rgb(0x07, 0x36, 0x42);
+const SOLARIZED_BASE00: Color = Color::Rgb(0x65, 0x7b, 0x83);
+const SOLARIZED_BASE1: Color = Color::Rgb(0x93, 0xa1, 0xa1);
+const SOLARIZED_BASE2: Color = Color::Rgb(0xee, 0xe8, 0xd5);
+const SOLARIZED_BASE3: Color = Color::Rgb(0xfd, 0xf6, 0xe3);
+const SOLARIZED_YELLOW: Color = Color::Rgb(0xb5, 0x89, 0x00);
+const SOLARIZED_ORANGE: Color = Color::Rgb(0xcb, 0x4b, 0x16);
+const SOLARIZED_BLUE: Color = Color::Rgb(0x26, 0x8b, 0xd2);
+
+/// How long to wait for the terminal to say what its background colour is.
+const REPLY_TIMEOUT: Duration = Duration::from_millis(200);
+
+impl Theme {
+    /// Picks the light or dark version of a theme that comes in both, from whether the terminal
+    /// has a light background.  Dark is assumed when that's not known.
+    pub fn resolve(self, light: impl FnOnce() -> Option<bool>) -> Theme {
+        match self {
+            Theme::Solarized if light().unwrap_or(false) => Theme::SolarizedLight,
+            Theme::Solarized => Theme::SolarizedDark,
+            theme => theme,
+        }
+    }
+
+    /// The selected song, and the playhead.
+    pub fn selection(self) -> Style {
+        match self {
+            Theme::Default => Style::default().add_modifier(Modifier::REVERSED),
+            Theme::Solarized | Theme::SolarizedDark | Theme::SolarizedLight => {
+                Style::default().fg(SOLARIZED_BASE3).bg(SOLARIZED_BLUE)
+            }
+            Theme::Monochrome => {
+                Style::default().add_modifier(Modifier::BOLD | Modifier::UNDERLINED)
+            }
+        }
+    }
+
+    /// Keys in prompts, and whatever's lit up on the meters.
+    pub fn accent(self) -> Style {
+        match self {
+            Theme::Default | Theme::Monochrome => Style::default().add_modifier(Modifier::BOLD),
+            Theme::Solarized | Theme::SolarizedDark => Style::default()
+                .fg(SOLARIZED_YELLOW)
+                .add_modifier(Modifier::BOLD),
+            // Yellow is too faint against a light background.
+            Theme::SolarizedLight => Style::default()
+                .fg(SOLARIZED_ORANGE)
+                .add_modifier(Modifier::BOLD),
+        }
+    }
+
+    /// The background of popups.
+    pub fn popup(self) -> Block<'static> {
+        match self {
+            Theme::Default | Theme::Monochrome => Block::default(),
+            Theme::Solarized | Theme::SolarizedDark => {
+                Block::default().style(Style::default().fg(SOLARIZED_BASE1).bg(SOLARIZED_BASE02))
+            }
+            Theme::SolarizedLight => {
+                Block::default().style(Style::default().fg(SOLARIZED_BASE00).bg(SOLARIZED_BASE2))
+            }
+        }
+    }
+}
+
+/// Asks the terminal for its background colour with OSC 11, and says whether it's light.  Needs
+/// raw mode, so the reply isn't echoed.  A device attributes query goes out straight after,
+/// which every terminal answers, so there's always a reply to read up to even from terminals
+/// that ignore the colour query.
+#[cfg(unix)]
+pub fn light_background() -> Option<bool> {
+    use std::{
+        io::{Read, Write},
+        sync::mpsc,
+        thread,
+    };
+
+    let mut tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .ok()?;
+    tty.write_all(b"\x1b]11;?\x07\x1b[c").ok()?;
+    tty.flush().ok()?;
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut reply = Vec::new();
+        let mut byte = [0];
+        while tty.read(&mut byte).is_ok_and(|read| read == 1) {
+            reply.push(byte[0]);
+            // The device attributes reply, ESC [ ? ... c, comes last.
+            if byte[0] == b'c' && reply.windows(3).any(|w| w == b"\x1b[?") {
+                break;
+            }
+        }
+        let _ = sender.send(reply);
+    });
+    let reply = receiver.recv_timeout(REPLY_TIMEOUT).ok()?;
+    parse_background(&String::from_utf8_lossy(&reply))
+}
+
+#[cfg(not(unix))]
+pub fn light_background() -> Option<bool> {
+    None
+}
+
+/// Reads a reply like `ESC ] 11 ; rgb:ffff/ffff/dddd BEL`, where each channel has one to four hex
+/// digits.
+fn parse_background(reply: &str) -> Option<bool> {
+    let rgb = &reply[reply.find("rgb:")? + 4..];
+    let end = rgb.find(['\x07', '\x1b']).unwrap_or(rgb.len());
+    let channels = rgb[..end]
+        .split('/')
+        .map(|channel| {
+            let max = 16u32.checked_pow(channel.len() as u32)? - 1;
+            let value = u32::from_str_radix(channel, 16).ok()?;
+            Some(value as f64 / max as f64)
+        })
+        .collect::<Option<Vec<_>>>()?;
+    let [r, g, b] = channels[..] else {
+        return None;
+    };
+    Some(0.2126 * r + 0.7152 * g + 0.0722 * b > 0.5)
+}